@@ -0,0 +1,348 @@
+//! Client library for [textsurf](https://github.com/knaw-huc/textsurf), a webservice for efficiently serving multiple plain text documents or excerpts thereof (by unicode character offset).
+//!
+//! ```no_run
+//! use textsurf_client::{Client, Range};
+//!
+//! let client = Client::new("http://localhost:8080").with_token("secret");
+//! client.overwrite("corpus/hello", "Hello world")?;
+//! assert_eq!(client.get("corpus/hello", Range::Char(0, 5))?, "Hello");
+//! println!("{}", client.stat("corpus/hello")?.checksum);
+//! # Ok::<(), textsurf_client::Error>(())
+//! ```
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Read;
+use ureq::http::Response;
+use ureq::typestate::{WithBody, WithoutBody};
+use ureq::{Agent, Body, RequestBuilder};
+
+/// Characters that are percent-encoded in a text identifier (everything but unreserved characters and the slash)
+const PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+/// Characters that are percent-encoded in a text identifier for API 2, where slashes have to be encoded as well
+const PATH_SEGMENT: &AsciiSet = &PATH.add(b'/');
+
+/// Maximum size of an error response that is read
+const MAX_ERROR_SIZE: u64 = 64 * 1024;
+
+/// Which of the two (functionally equivalent) Text Referencing APIs of textsurf to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Api {
+    /// Text Referencing API 1: `/{text_id}?char={begin},{end}`
+    #[default]
+    V1,
+    /// Text Referencing API 2: `/api2/{text_id}/char:{begin},{end}`
+    V2,
+}
+
+/// A range in a text. Begin and end may be negative to count from the end of the text, and an end of 0 denotes the end of the text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Range {
+    /// The whole text
+    Full,
+    /// Offsets in unicode points (0-indexed, end is non-inclusive)
+    Char(isize, isize),
+    /// Lines (0-indexed, end is non-inclusive)
+    Line(isize, isize),
+}
+
+/// Metadata about a text, as returned by `/stat/{text_id}` or `/api2/{text_id}/info.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stat {
+    /// File size in bytes
+    pub bytes: u64,
+    /// Length of the text in unicode points
+    pub chars: u64,
+    /// SHA-256 checksum of the text
+    pub checksum: String,
+    /// Modification time in seconds since the unix epoch
+    pub mtime: u64,
+    /// Current version number of the text (numbered from 1)
+    #[serde(default)]
+    pub version: usize,
+    /// All prior versions of the text
+    #[serde(default)]
+    pub revisions: Vec<Revision>,
+}
+
+/// A prior version of a text
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revision {
+    pub version: usize,
+    pub checksum: String,
+    pub mtime: u64,
+}
+
+/// Errors returned by the client. Errors reported by the server (as a JSON `ApiError`) map onto the variants of the same name.
+#[derive(Debug)]
+pub enum Error {
+    InternalError(String),
+    NotFound(String),
+    NotAcceptable(String),
+    PermissionDenied(String),
+    ParameterError(String),
+    TextError(String),
+    /// The server responded with an unsuccessful status but not with an `ApiError`
+    UnexpectedResponse {
+        status: u16,
+        body: String,
+    },
+    /// The request could not be made or the response could not be read
+    HttpError(ureq::Error),
+    /// The response could not be parsed
+    JsonError(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InternalError(s) => write!(f, "internal server error: {}", s),
+            Self::NotFound(s) => write!(f, "not found: {}", s),
+            Self::NotAcceptable(s) => write!(f, "not acceptable: {}", s),
+            Self::PermissionDenied(s) => write!(f, "permission denied: {}", s),
+            Self::ParameterError(s) => write!(f, "parameter error: {}", s),
+            Self::TextError(s) => write!(f, "text error: {}", s),
+            Self::UnexpectedResponse { status, body } => {
+                write!(f, "unexpected response (status {}): {}", status, body)
+            }
+            Self::HttpError(e) => write!(f, "http error: {}", e),
+            Self::JsonError(e) => write!(f, "invalid json: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::HttpError(e) => Some(e),
+            Self::JsonError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ureq::Error> for Error {
+    fn from(value: ureq::Error) -> Self {
+        Self::HttpError(value)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Self::JsonError(value)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::HttpError(value.into())
+    }
+}
+
+/// The body of an error response
+#[derive(Deserialize)]
+struct ApiError {
+    name: String,
+    message: String,
+}
+
+/// A client for a textsurf server
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// URL of the server, without trailing slash
+    baseurl: String,
+    token: Option<String>,
+    api: Api,
+    agent: Agent,
+}
+
+impl Client {
+    /// Creates a client for the server at the given URL (e.g. `http://localhost:8080`)
+    pub fn new(baseurl: impl Into<String>) -> Self {
+        Self {
+            baseurl: baseurl.into().trim_end_matches('/').to_string(),
+            token: None,
+            api: Api::default(),
+            agent: Agent::config_builder()
+                .http_status_as_error(false)
+                .build()
+                .into(),
+        }
+    }
+
+    /// Sets the API key to authenticate with (sent as bearer token), needed for writing if the server was started with `--apikey`
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Sets which Text Referencing API to use (API 1 by default). Listing and flushing are only available in API 1 and always use it.
+    pub fn with_api(mut self, api: Api) -> Self {
+        self.api = api;
+        self
+    }
+
+    /// Lists the identifiers of all texts (recursively)
+    pub fn list(&self) -> Result<Vec<String>, Error> {
+        self.list_dir("")
+    }
+
+    /// Lists the identifiers of all texts under a directory (recursively), relative to that directory
+    pub fn list_dir(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let dir = dir.trim_matches('/');
+        let url = if dir.is_empty() {
+            format!("{}/", self.baseurl)
+        } else {
+            format!("{}/{}/", self.baseurl, encode(dir, PATH))
+        };
+        let response = self.send(self.agent.get(url).header("Accept", "application/json"))?;
+        Ok(serde_json::from_reader(response.into_body().into_reader())?)
+    }
+
+    /// Retrieves a text or a range therein
+    pub fn get(&self, id: &str, range: Range) -> Result<String, Error> {
+        let mut text = String::new();
+        self.get_reader(id, range)?.read_to_string(&mut text)?;
+        Ok(text)
+    }
+
+    /// Retrieves a text or a range therein as a stream, so large excerpts need not be held in memory as a whole
+    pub fn get_reader(&self, id: &str, range: Range) -> Result<impl Read + 'static, Error> {
+        let url = match self.api {
+            Api::V1 => {
+                let url = self.url(id);
+                match range {
+                    Range::Full => url,
+                    Range::Char(begin, end) => format!("{}?char={},{}", url, begin, end),
+                    Range::Line(begin, end) => format!("{}?line={},{}", url, begin, end),
+                }
+            }
+            Api::V2 => {
+                let url = self.url(id);
+                match range {
+                    Range::Full => format!("{}/full", url),
+                    Range::Char(begin, end) => format!("{}/char:{},{}", url, begin, end),
+                    Range::Line(begin, end) => format!("{}/line:{},{}", url, begin, end),
+                }
+            }
+        };
+        let response = self.send(self.agent.get(url).header("Accept", "text/plain"))?;
+        Ok(response.into_body().into_reader())
+    }
+
+    /// Returns metadata about a text (via `info.json` in API 2)
+    pub fn stat(&self, id: &str) -> Result<Stat, Error> {
+        let url = match self.api {
+            Api::V1 => format!("{}/stat/{}", self.baseurl, encode(id, PATH)),
+            Api::V2 => format!("{}/info.json", self.url(id)),
+        };
+        let response = self.send(self.agent.get(url))?;
+        Ok(serde_json::from_reader(response.into_body().into_reader())?)
+    }
+
+    /// Uploads a new text, fails with [`Error::PermissionDenied`] if it already exists
+    pub fn create(&self, id: &str, text: &str) -> Result<(), Error> {
+        self.send_body(
+            self.agent
+                .post(self.url(id))
+                .header("Content-Type", "text/plain"),
+            text,
+        )?;
+        Ok(())
+    }
+
+    /// Uploads a text, overwriting it if it already exists. Returns whether the text was newly created.
+    pub fn overwrite(&self, id: &str, text: &str) -> Result<bool, Error> {
+        let response = self.send_body(
+            self.agent
+                .put(self.url(id))
+                .header("Content-Type", "text/plain"),
+            text,
+        )?;
+        Ok(response.status() == 201)
+    }
+
+    /// Deletes a text
+    pub fn delete(&self, id: &str) -> Result<(), Error> {
+        self.send(self.agent.delete(self.url(id)))?;
+        Ok(())
+    }
+
+    /// Unloads all texts from the memory of the server
+    pub fn flush(&self) -> Result<(), Error> {
+        self.send_body(self.agent.post(format!("{}/flush", self.baseurl)), "")?;
+        Ok(())
+    }
+
+    /// Returns the URL of a text
+    fn url(&self, id: &str) -> String {
+        match self.api {
+            Api::V1 => format!("{}/{}", self.baseurl, encode(id, PATH)),
+            Api::V2 => format!("{}/api2/{}", self.baseurl, encode(id, PATH_SEGMENT)),
+        }
+    }
+
+    /// Sends a request without body
+    fn send(&self, request: RequestBuilder<WithoutBody>) -> Result<Response<Body>, Error> {
+        check_status(self.authorize(request).call()?)
+    }
+
+    /// Sends a request with a body
+    fn send_body(
+        &self,
+        request: RequestBuilder<WithBody>,
+        body: &str,
+    ) -> Result<Response<Body>, Error> {
+        check_status(self.authorize(request).send(body)?)
+    }
+
+    fn authorize<B>(&self, request: RequestBuilder<B>) -> RequestBuilder<B> {
+        match self.token.as_deref() {
+            Some(token) => request.header("Authorization", format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+}
+
+/// Turns responses with an unsuccessful status into errors
+fn check_status(mut response: Response<Body>) -> Result<Response<Body>, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response
+        .body_mut()
+        .with_config()
+        .limit(MAX_ERROR_SIZE)
+        .read_to_string()
+        .unwrap_or_default();
+    Err(match serde_json::from_str::<ApiError>(&body) {
+        Ok(e) => match e.name.as_str() {
+            "InternalError" => Error::InternalError(e.message),
+            "NotFound" => Error::NotFound(e.message),
+            "NotAcceptable" => Error::NotAcceptable(e.message),
+            "PermissionDenied" => Error::PermissionDenied(e.message),
+            "ParameterError" => Error::ParameterError(e.message),
+            "TextError" => Error::TextError(e.message),
+            _ => Error::UnexpectedResponse {
+                status: status.as_u16(),
+                body,
+            },
+        },
+        Err(_) => Error::UnexpectedResponse {
+            status: status.as_u16(),
+            body,
+        },
+    })
+}
+
+fn encode(id: &str, set: &'static AsciiSet) -> String {
+    utf8_percent_encode(id, set).to_string()
+}
@@ -3,6 +3,14 @@ cd /usr/src || exit 1
 set --
 [ "$WRITABLE" = "1" ] && set -- "$@" --writable
 [ -n "$UNLOADTIME" ] && set -- "$@" --unload-time "$UNLOADTIME"
+[ -n "$SEARCHTIMELIMIT" ] && set -- "$@" --search-time-limit "$SEARCHTIMELIMIT"
+[ -n "$SEARCHMAXHITS" ] && set -- "$@" --search-max-hits "$SEARCHMAXHITS"
+[ "$INVERTEDINDEX" = "1" ] && set -- "$@" --inverted-index
+[ "$GIT" = "1" ] && set -- "$@" --git
+[ -n "$GITAUTHOR" ] && set -- "$@" --git-author "$GITAUTHOR"
+[ -n "$BAREREPOSITORY" ] && set -- "$@" --bare-repository "$BAREREPOSITORY" --ref "${REF:-HEAD}" --refresh-interval "${REFRESHINTERVAL:-60}"
+[ -n "$S3ENDPOINT" ] && set -- "$@" --s3-endpoint "$S3ENDPOINT" --s3-bucket "$S3BUCKET" --s3-region "${S3REGION:-us-east-1}" --s3-prefix "$S3PREFIX"
+[ "$ARCHIVES" = "1" ] && set -- "$@" --archives
 [ -n "$APIKEY" ] && set -- "$@" --apikey "$APIKEY"
 [ "$DEBUG" = "1" ] && set -- "$@" --debug
 sudo -u user /usr/bin/textsurf --bind 0.0.0.0:8080 --basedir=/data "$@" || sleep 5 #sleep is a safeguard against continuous restarts in case of failure
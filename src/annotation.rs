@@ -0,0 +1,371 @@
+use crate::common::ApiError;
+use crate::search::{edit_distance, Pattern};
+use crate::textpool::{ContextUnit, TextPool};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Number of characters used for the prefix and suffix of the quote selectors we generate
+const QUOTE_CONTEXT: usize = 32;
+
+/// Maximum proportion of characters in a quote that may have been edited for fuzzy anchoring to still find it
+const MAX_ERROR_RATE: f64 = 0.25;
+
+/// Maximum number of fuzzy anchoring candidates for which the context is compared
+const MAX_CANDIDATES: usize = 100;
+
+/// Weights of the quote, prefix, suffix and position when scoring fuzzy anchoring candidates
+const QUOTE_WEIGHT: f64 = 50.0;
+const PREFIX_WEIGHT: f64 = 20.0;
+const SUFFIX_WEIGHT: f64 = 20.0;
+const POSITION_WEIGHT: f64 = 2.0;
+
+/// A selector as defined by the W3C Web Annotation Data Model (<https://www.w3.org/TR/annotation-model/#selectors>).
+/// Only the text selectors are supported, others are ignored.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum Selector {
+    #[serde(rename = "TextPositionSelector")]
+    TextPosition { start: usize, end: usize },
+    #[serde(rename = "TextQuoteSelector")]
+    TextQuote {
+        exact: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        suffix: Option<String>,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+/// JSON-LD allows either a single value or a list of values in many places
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    pub fn into_vec(self) -> Vec<T> {
+        match self {
+            Self::One(value) => vec![value],
+            Self::Many(values) => values,
+        }
+    }
+}
+
+/// An annotation target, consisting of a source (the URI of a text) and one or more selectors
+#[derive(Deserialize, Debug)]
+pub struct Target {
+    pub source: Option<String>,
+    pub selector: OneOrMany<Selector>,
+}
+
+/// What can be submitted for selector resolution: a whole annotation, an annotation target, or just one or more selectors
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum SelectorRequest {
+    Annotation { target: Target },
+    Target(Target),
+    Selectors(OneOrMany<Selector>),
+}
+
+impl SelectorRequest {
+    /// Returns the source (if any) and the selectors
+    pub fn into_parts(self) -> (Option<String>, Vec<Selector>) {
+        match self {
+            Self::Annotation { target } | Self::Target(target) => {
+                (target.source, target.selector.into_vec())
+            }
+            Self::Selectors(selectors) => (None, selectors.into_vec()),
+        }
+    }
+}
+
+/// Resolves text selectors against a text. Returns the selected text along with both a TextPositionSelector and a TextQuoteSelector for it.
+/// If both a position and a quote are given, the position is leading and the result reports whether the quote still matches there.
+pub fn resolve(
+    textpool: &TextPool,
+    id: &str,
+    selectors: Vec<Selector>,
+) -> Result<serde_json::Value, ApiError> {
+    let mut position = None;
+    let mut quote = None;
+    for selector in selectors {
+        match selector {
+            Selector::TextPosition { start, end } if position.is_none() => {
+                position = Some((start, end))
+            }
+            Selector::TextQuote {
+                exact,
+                prefix,
+                suffix,
+            } if quote.is_none() => quote = Some((exact, prefix, suffix)),
+            _ => {}
+        }
+    }
+    let (start, end, quote_matches) = match (position, quote) {
+        (Some((start, end)), None) => (start, end, None),
+        (Some((start, end)), Some((exact, prefix, suffix))) => {
+            let matches = quote_matches_at(
+                textpool,
+                id,
+                start,
+                end,
+                &exact,
+                prefix.as_deref(),
+                suffix.as_deref(),
+            )?;
+            (start, end, Some(matches))
+        }
+        (None, Some((exact, prefix, suffix))) => {
+            let (start, end) = find_quote(
+                textpool,
+                id,
+                &exact,
+                prefix.as_deref(),
+                suffix.as_deref(),
+                None,
+            )?
+            .ok_or(ApiError::NotFound("The quote was not found in the text"))?;
+            (start, end, None)
+        }
+        (None, None) => {
+            return Err(ApiError::ParameterError(
+                "expected a TextPositionSelector or TextQuoteSelector",
+            ))
+        }
+    };
+    if end < start {
+        return Err(ApiError::ParameterError(
+            "The selector has a negative length",
+        ));
+    }
+    if start != end {
+        //validates the offsets
+        textpool.absolute_pos(id, start as isize, end as isize)?;
+    }
+
+    let text = textpool.excerpt(id, start, end)?;
+    let (before, after) = textpool.context(id, start, end, QUOTE_CONTEXT, ContextUnit::Chars)?;
+    let selectors = [
+        Selector::TextPosition { start, end },
+        Selector::TextQuote {
+            exact: text.clone(),
+            prefix: Some(textpool.excerpt(id, before, start)?),
+            suffix: Some(textpool.excerpt(id, end, after)?),
+        },
+    ];
+    let mut result = serde_json::json!({
+        "id": id,
+        "text": text,
+        "selector": selectors,
+    });
+    if let Some(quote_matches) = quote_matches {
+        result["quote_matches"] = quote_matches.into();
+    }
+    Ok(result)
+}
+
+/// Checks whether a quote (with optional prefix and suffix) occurs at the given position
+pub fn quote_matches_at(
+    textpool: &TextPool,
+    id: &str,
+    start: usize,
+    end: usize,
+    exact: &str,
+    prefix: Option<&str>,
+    suffix: Option<&str>,
+) -> Result<bool, ApiError> {
+    if end < start || textpool.excerpt(id, start, end).ok().as_deref() != Some(exact) {
+        return Ok(false);
+    }
+    context_matches(textpool, id, start, end, prefix, suffix)
+}
+
+/// Finds a quote (with optional prefix and suffix) in a text. If there are multiple candidates, the one closest to the position hint is returned, or the first one if there is no hint.
+/// With a hint, only the `MAX_CANDIDATES` occurrences of the quote closest to it are considered.
+/// Returns the character offsets of the exact quote, or `None` if it could not be found.
+pub fn find_quote(
+    textpool: &TextPool,
+    id: &str,
+    exact: &str,
+    prefix: Option<&str>,
+    suffix: Option<&str>,
+    hint: Option<usize>,
+) -> Result<Option<(usize, usize)>, ApiError> {
+    let pattern = Pattern::new(exact, false)?;
+    let deadline = std::time::Instant::now() + textpool.search_limits().time;
+    let Some(hint) = hint else {
+        //the first occurrence with a matching context wins, so we can stop there
+        let mut found = None;
+        let mut result = Ok(());
+        textpool.search_with(id, &pattern, deadline, false, |m| {
+            match context_matches(textpool, id, m.begin, m.end, prefix, suffix) {
+                Ok(true) => found = Some((m.begin, m.end)),
+                Ok(false) => return true,
+                Err(e) => result = Err(e),
+            }
+            false
+        })?;
+        return result.map(|_| found);
+    };
+
+    //occurrences come in order, so the ones closest to the hint form a window that slides along until occurrences get farther away again
+    let mut candidates: VecDeque<(usize, usize)> = VecDeque::with_capacity(MAX_CANDIDATES);
+    textpool.search_with(id, &pattern, deadline, false, |m| {
+        if candidates.len() == MAX_CANDIDATES {
+            if hint.abs_diff(m.begin) >= hint.abs_diff(candidates[0].0) {
+                return false;
+            }
+            candidates.pop_front();
+        }
+        candidates.push_back((m.begin, m.end));
+        true
+    })?;
+    let mut candidates = Vec::from(candidates);
+    candidates.sort_by_key(|(begin, _)| hint.abs_diff(*begin));
+    for (begin, end) in candidates {
+        if context_matches(textpool, id, begin, end, prefix, suffix)? {
+            return Ok(Some((begin, end)));
+        }
+    }
+    Ok(None)
+}
+
+/// Checks whether the prefix and suffix (if any) match the text around a selection. Near the boundaries of the text, the prefix and suffix may be longer than the available text.
+fn context_matches(
+    textpool: &TextPool,
+    id: &str,
+    start: usize,
+    end: usize,
+    prefix: Option<&str>,
+    suffix: Option<&str>,
+) -> Result<bool, ApiError> {
+    textpool.with_text(id, |text| {
+        if let Some(prefix) = prefix.filter(|prefix| !prefix.is_empty()) {
+            let before = start.saturating_sub(prefix.chars().count());
+            if before < start
+                && !prefix.ends_with(text.get_or_load(before as isize, start as isize)?)
+            {
+                return Ok(false);
+            }
+        }
+        if let Some(suffix) = suffix.filter(|suffix| !suffix.is_empty()) {
+            let after = (end + suffix.chars().count()).min(text.textfile().len());
+            if end < after && !suffix.starts_with(text.get_or_load(end as isize, after as isize)?) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    })
+}
+
+/// Anchors a TextQuoteSelector in the current text, tolerating edits made since the selector was created.
+/// An accompanying TextPositionSelector serves as a hint for the expected position.
+/// Candidates are found by approximate search for the quote, then scored on the similarity of quote, prefix and suffix and on the distance to the hint.
+/// The result is as for [`resolve()`], with an additional `confidence` between 0 and 1 (1 being an exact match of quote, prefix and suffix at the expected position).
+pub fn anchor(
+    textpool: &TextPool,
+    id: &str,
+    selectors: Vec<Selector>,
+) -> Result<serde_json::Value, ApiError> {
+    let mut hint = None;
+    let mut quote = None;
+    for selector in selectors {
+        match selector {
+            Selector::TextPosition { start, end } if hint.is_none() => hint = Some((start, end)),
+            Selector::TextQuote {
+                exact,
+                prefix,
+                suffix,
+            } if quote.is_none() => quote = Some((exact, prefix, suffix)),
+            _ => {}
+        }
+    }
+    let (exact, prefix, suffix) =
+        quote.ok_or(ApiError::ParameterError("expected a TextQuoteSelector"))?;
+    let prefix = prefix.unwrap_or_default();
+    let suffix = suffix.unwrap_or_default();
+
+    let (start, end, confidence) = if let Some((start, end)) = hint.filter(|(start, end)| {
+        //fast path: nothing changed
+        quote_matches_at(
+            textpool,
+            id,
+            *start,
+            *end,
+            &exact,
+            Some(&prefix),
+            Some(&suffix),
+        )
+        .unwrap_or(false)
+    }) {
+        (start, end, 1.0)
+    } else {
+        let length = exact.chars().count();
+        let max_errors = (length as f64 * MAX_ERROR_RATE) as usize;
+        let deadline = std::time::Instant::now() + textpool.search_limits().time;
+        let (mut candidates, _) = textpool.search_approximate(id, &exact, max_errors, deadline)?;
+        let hint = hint.map(|(start, _)| start);
+        candidates.sort_by_key(|candidate| {
+            (
+                candidate.errors,
+                hint.map(|hint| hint.abs_diff(candidate.begin)).unwrap_or(0),
+            )
+        });
+        candidates.truncate(MAX_CANDIDATES);
+        let textlength = textpool.with_text(id, |text| Ok(text.textfile().len()))?;
+        let mut best: Option<(usize, usize, f64)> = None;
+        for candidate in candidates {
+            let mut score = QUOTE_WEIGHT * similarity(candidate.errors, length);
+            let mut max_score = QUOTE_WEIGHT;
+            if !prefix.is_empty() {
+                let before = textpool.excerpt(
+                    id,
+                    candidate.begin.saturating_sub(prefix.chars().count()),
+                    candidate.begin,
+                )?;
+                score += PREFIX_WEIGHT
+                    * similarity(edit_distance(&prefix, &before), prefix.chars().count());
+                max_score += PREFIX_WEIGHT;
+            }
+            if !suffix.is_empty() {
+                let after = textpool.excerpt(
+                    id,
+                    candidate.end,
+                    (candidate.end + suffix.chars().count()).min(textlength),
+                )?;
+                score += SUFFIX_WEIGHT
+                    * similarity(edit_distance(&suffix, &after), suffix.chars().count());
+                max_score += SUFFIX_WEIGHT;
+            }
+            if let Some(hint) = hint {
+                score += POSITION_WEIGHT
+                    * (1.0 - hint.abs_diff(candidate.begin) as f64 / textlength.max(1) as f64);
+                max_score += POSITION_WEIGHT;
+            }
+            let confidence = score / max_score;
+            if best.is_none_or(|(_, _, best)| confidence > best) {
+                best = Some((candidate.begin, candidate.end, confidence));
+            }
+        }
+        best.ok_or(ApiError::NotFound(
+            "The quote could not be anchored in the text",
+        ))?
+    };
+
+    let mut result = resolve(textpool, id, vec![Selector::TextPosition { start, end }])?;
+    result["confidence"] = confidence.into();
+    Ok(result)
+}
+
+/// Similarity based on the number of edits relative to the length, between 0 and 1
+fn similarity(errors: usize, length: usize) -> f64 {
+    if length == 0 {
+        1.0
+    } else {
+        1.0 - (errors as f64 / length as f64).min(1.0)
+    }
+}
@@ -14,3 +14,261 @@ pub struct ApiError {
     /// The error message
     message: String,
 }
+
+#[derive(ToSchema)]
+/// A single range in a batch request
+#[allow(dead_code)]
+pub struct BatchItem {
+    /// The identifier of the text
+    id: String,
+
+    /// Character range specification conforming to RFC5147, begin and end values are separated by a comma, 0-indexed, end is non-inclusive
+    char: Option<String>,
+
+    /// Line range specification conforming to RFC5147, begin and end values are separated by a comma, 0-indexed (first line is 0!), end is non-inclusive. Ignored if `char` is set.
+    line: Option<String>,
+
+    /// Range specification in UTF-16 code units, with the same syntax as `char`. Ignored if `char` or `line` is set.
+    utf16: Option<String>,
+
+    /// Range specification in grapheme clusters, with the same syntax as `char`. Ignored if `char`, `line` or `utf16` is set.
+    grapheme: Option<String>,
+
+    /// Range specification in tokens, with the same syntax as `char`. Ignored if any of the above is set.
+    token: Option<String>,
+
+    /// Range specification in paragraphs, with the same syntax as `char`. Ignored if any of the above is set.
+    para: Option<String>,
+
+    /// Range specification in pages, with the same syntax as `char`. Ignored if any of the above is set.
+    page: Option<String>,
+}
+
+#[derive(ToSchema)]
+/// A single result in a batch response
+#[allow(dead_code)]
+pub struct BatchResult {
+    /// The identifier of the text
+    id: String,
+
+    /// The absolute begin offset in unicode points (0-indexed)
+    begin: Option<usize>,
+
+    /// The absolute end offset in unicode points (0-indexed, non-inclusive)
+    end: Option<usize>,
+
+    /// The text excerpt
+    text: Option<String>,
+
+    /// Only set if this range could not be resolved
+    error: Option<ApiError>,
+}
+
+#[derive(ToSchema)]
+/// A position in a text, expressed in all supported coordinate systems
+#[allow(dead_code)]
+pub struct Coordinates {
+    /// Offset in unicode points (0-indexed)
+    char: usize,
+
+    /// Offset in UTF-8 bytes (0-indexed)
+    byte: usize,
+
+    /// Offset in UTF-16 code units (0-indexed)
+    utf16: Option<usize>,
+
+    /// Offset in grapheme clusters (0-indexed), only available if the position is at the boundary of a grapheme cluster
+    grapheme: Option<usize>,
+
+    /// Index of the token that holds this position (0-indexed), if any
+    token: Option<usize>,
+
+    /// Index of the paragraph that holds this position (0-indexed), if any
+    para: Option<usize>,
+
+    /// Index of the page that holds this position (0-indexed), if any
+    page: Option<usize>,
+
+    /// Line number (0-indexed), only available if the service has a line index
+    line: Option<usize>,
+
+    /// Offset in unicode points relative to the start of the line (0-indexed), only available if the service has a line index
+    column: Option<usize>,
+}
+
+#[derive(ToSchema)]
+/// A single match of a search query in a text
+#[allow(dead_code)]
+pub struct SearchMatch {
+    /// The begin offset in unicode points (0-indexed)
+    begin: usize,
+
+    /// The end offset in unicode points (0-indexed, non-inclusive)
+    end: usize,
+
+    /// The line the match begins on (0-indexed)
+    line: usize,
+
+    /// The matched text (this may differ from the query in case-insensitive matching)
+    text: String,
+}
+
+#[derive(ToSchema)]
+/// A single line in a keyword-in-context concordance
+#[allow(dead_code)]
+pub struct ConcordanceLine {
+    /// The identifier of the text
+    id: String,
+
+    /// The begin offset of the match in unicode points (0-indexed)
+    begin: usize,
+
+    /// The end offset of the match in unicode points (0-indexed, non-inclusive)
+    end: usize,
+
+    /// The context to the left of the match
+    left: String,
+
+    /// The matched text
+    #[schema(rename = "match")]
+    r#match: String,
+
+    /// The context to the right of the match
+    right: String,
+}
+
+#[derive(ToSchema)]
+/// A part of a text along with its absolute offsets
+#[allow(dead_code)]
+pub struct TextPart {
+    /// The begin offset in unicode points (0-indexed)
+    begin: usize,
+
+    /// The end offset in unicode points (0-indexed, non-inclusive)
+    end: usize,
+
+    /// The text
+    text: String,
+}
+
+#[derive(ToSchema)]
+/// A text selection along with the context before and after it
+#[allow(dead_code)]
+pub struct ContextEnvelope {
+    /// The identifier of the text
+    id: String,
+
+    /// The version number of the text, if a specific version was requested
+    version: Option<usize>,
+
+    /// The git ref the text was retrieved at, if one was requested
+    #[schema(rename = "ref")]
+    r#ref: Option<String>,
+
+    /// The context before the selection
+    before: TextPart,
+
+    /// The selection itself
+    selection: TextPart,
+
+    /// The context after the selection
+    after: TextPart,
+}
+
+#[derive(ToSchema)]
+/// A self-describing text selection
+#[allow(dead_code)]
+pub struct TextEnvelope {
+    /// The identifier of the text
+    id: String,
+
+    /// The version number of the text, if a specific version was requested
+    version: Option<usize>,
+
+    /// The git ref the text was retrieved at, if one was requested
+    #[schema(rename = "ref")]
+    r#ref: Option<String>,
+
+    /// The absolute begin offset in unicode points (0-indexed)
+    begin: usize,
+
+    /// The absolute end offset in unicode points (0-indexed, non-inclusive)
+    end: usize,
+
+    /// The number of lines in the selection
+    lines: usize,
+
+    /// The length of the selection in unicode points
+    chars: usize,
+
+    /// The MD5 checksum of the selection (as used in RFC5147 integrity checks)
+    md5: String,
+
+    /// The text of the selection
+    text: String,
+}
+
+#[derive(ToSchema)]
+/// A W3C Web Annotation text selector, either a TextPositionSelector (`start` and `end`) or a TextQuoteSelector (`exact` with optional `prefix` and `suffix`)
+#[allow(dead_code)]
+pub struct Selector {
+    /// The selector type: `TextPositionSelector` or `TextQuoteSelector`
+    #[schema(rename = "type")]
+    r#type: String,
+
+    /// The begin offset in unicode points (0-indexed), for a TextPositionSelector
+    start: Option<usize>,
+
+    /// The end offset in unicode points (0-indexed, non-inclusive), for a TextPositionSelector
+    end: Option<usize>,
+
+    /// The exact text that is selected, for a TextQuoteSelector
+    exact: Option<String>,
+
+    /// The text immediately preceding the selection, for a TextQuoteSelector
+    prefix: Option<String>,
+
+    /// The text immediately following the selection, for a TextQuoteSelector
+    suffix: Option<String>,
+}
+
+#[derive(ToSchema)]
+/// An annotation target, may also be submitted as the `target` of a full annotation, or be replaced by one or more bare selectors
+#[allow(dead_code)]
+pub struct SelectorRequest {
+    /// The URI of the text on this server
+    source: Option<String>,
+
+    /// One or more selectors (selectors of other types are ignored)
+    selector: Vec<Selector>,
+}
+
+#[derive(ToSchema)]
+/// The result of resolving annotation selectors
+#[allow(dead_code)]
+pub struct SelectorResult {
+    /// The identifier of the text
+    id: String,
+
+    /// The version number of the text, if a specific version was requested
+    version: Option<usize>,
+
+    /// The git ref the text was retrieved at, if one was requested
+    #[schema(rename = "ref")]
+    r#ref: Option<String>,
+
+    /// The source as submitted (if any)
+    source: Option<String>,
+
+    /// The selected text
+    text: String,
+
+    /// A TextPositionSelector and a TextQuoteSelector (with prefix and suffix) for the selection
+    selector: Vec<Selector>,
+
+    /// Whether the submitted quote matches at the submitted position, only set if both were submitted
+    quote_matches: Option<bool>,
+
+    /// How well the anchored text matches the submitted quote selector, between 0 and 1 (only set when anchoring)
+    confidence: Option<f64>,
+}
@@ -0,0 +1,661 @@
+use crate::common::ApiError;
+use crate::storage::{is_cached_copy, read_file_range, FileCache, FileStat, Storage};
+use flate2::read::DeflateDecoder;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::SystemTime;
+use tracing::{error, info, warn};
+use zip::{CompressionMethod, ZipArchive};
+
+/// Name of the (hidden) directory under the base directory where archive members are extracted to, for operations that need to scan a whole text (like search) and for reading ranges of compressed members
+pub const ARCHIVE_CACHE_DIR: &str = ".archivecache";
+
+/// Default maximum total size (in bytes) of the extracted archive members that are kept in the cache
+pub const ARCHIVE_CACHE_LIMIT: u64 = 1 << 30;
+
+/// Size of the buffer used when reading archives through the storage backend
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Size of the fixed part of the local file header that precedes every member in a zip file
+const ZIP_LOCAL_HEADER_SIZE: u64 = 30;
+
+/// Storage backend that exposes the members of tar and zip archives (files ending in `.tar` or `.zip`) as read-only files, without extracting them.
+/// A member is addressed by the path of the archive followed by the path of the member, e.g. `archive.zip/path/file.txt`, and listing a directory lists the members of all archives in it rather than the archives themselves.
+/// The archives themselves are held by another storage backend, all other files are passed through to it.
+/// Members must be stored uncompressed or be compressed with deflate (zip only); ranges of stored members are read directly from the archive, compressed members are extracted to the cache when a range is first read from them.
+pub struct ArchiveStorage {
+    inner: Arc<dyn Storage>,
+    cache: FileCache,
+    /// Indices of the archives that were opened so far, by path
+    indices: RwLock<HashMap<String, Arc<ArchiveIndex>>>,
+}
+
+/// The table of contents of an archive
+struct ArchiveIndex {
+    /// Modification time of the archive this index was built for
+    mtime: SystemTime,
+    members: BTreeMap<String, Member>,
+}
+
+/// A file in an archive
+struct Member {
+    /// Offset of the (local) header of the member in the archive
+    header_start: u64,
+    /// Offset of the data of the member in the archive, only determined when first needed (for zip files this requires reading the local header)
+    data_start: OnceLock<u64>,
+    /// Uncompressed size
+    size: u64,
+    /// Size of the data in the archive
+    compressed_size: u64,
+    deflated: bool,
+}
+
+impl ArchiveStorage {
+    /// Wraps a storage backend. Members that need to be available as a whole on local disk are extracted into the cache directory, which holds at most `cache_limit` bytes of them.
+    pub fn new(
+        inner: Arc<dyn Storage>,
+        cachedir: &Path,
+        cache_limit: u64,
+    ) -> Result<Self, ApiError> {
+        Ok(Self {
+            inner,
+            cache: FileCache::open(cachedir, cache_limit, is_cached_copy)?,
+            indices: HashMap::new().into(),
+        })
+    }
+
+    /// Splits a path into the path of an archive and the path of a member inside it, or returns `None` if the path does not point into an archive
+    fn split<'a>(&self, path: &'a str) -> Result<Option<(&'a str, &'a str)>, ApiError> {
+        for (i, _) in path.match_indices('/') {
+            let archive = &path[..i];
+            if is_archive(archive) && self.inner.stat(archive)?.is_some() {
+                return Ok(Some((archive, &path[i + 1..])));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the index of an archive, (re)building it if the archive changed since it was last opened
+    fn index(&self, archive: &str) -> Result<Arc<ArchiveIndex>, ApiError> {
+        let stat = self
+            .inner
+            .stat(archive)?
+            .ok_or(ApiError::NotFound("No such archive"))?;
+        if let Some(index) = self
+            .indices
+            .read()
+            .map_err(|_| ApiError::InternalError("Lock poisoned"))?
+            .get(archive)
+        {
+            if index.mtime == stat.mtime {
+                return Ok(index.clone());
+            }
+        }
+        info!("Reading archive {}", archive);
+        let reader = BufReader::with_capacity(
+            BUFFER_SIZE,
+            StorageReader::new(self.inner.clone(), archive, stat.size),
+        );
+        let members = if archive.ends_with(".zip") {
+            zip_members(reader)
+        } else {
+            tar_members(reader)
+        }
+        .map_err(|e| {
+            error!("Unable to read archive {}: {}", archive, e);
+            ApiError::InternalError("Unable to read archive")
+        })?;
+        let index = Arc::new(ArchiveIndex {
+            mtime: stat.mtime,
+            members,
+        });
+        self.indices
+            .write()
+            .map_err(|_| ApiError::InternalError("Lock poisoned"))?
+            .insert(archive.to_string(), index.clone());
+        Ok(index)
+    }
+
+    /// Returns the offset of the data of an archive member in the archive, determining it first if needed
+    fn data_start(&self, archive: &str, member: &Member) -> Result<u64, ApiError> {
+        if let Some(data_start) = member.data_start.get() {
+            return Ok(*data_start);
+        }
+        //the local header of a zip member has a variable size: the lengths of the filename and extra field are stored at the end of its fixed part
+        let header = self.inner.read_range(
+            archive,
+            member.header_start,
+            member.header_start + ZIP_LOCAL_HEADER_SIZE,
+        )?;
+        let name_length = u16::from_le_bytes([header[26], header[27]]) as u64;
+        let extra_length = u16::from_le_bytes([header[28], header[29]]) as u64;
+        Ok(*member.data_start.get_or_init(|| {
+            member.header_start + ZIP_LOCAL_HEADER_SIZE + name_length + extra_length
+        }))
+    }
+
+    /// Returns a reader over the (uncompressed) content of an archive member
+    fn open(&self, archive: &str, member: &Member) -> Result<Box<dyn Read>, ApiError> {
+        let data_start = self.data_start(archive, member)?;
+        let mut reader = StorageReader::new(
+            self.inner.clone(),
+            archive,
+            data_start + member.compressed_size,
+        );
+        reader.seek(SeekFrom::Start(data_start))?;
+        if member.deflated {
+            Ok(Box::new(DeflateDecoder::new(BufReader::with_capacity(
+                BUFFER_SIZE,
+                reader,
+            ))))
+        } else {
+            Ok(Box::new(reader))
+        }
+    }
+}
+
+impl Storage for ArchiveStorage {
+    fn list(&self, dir: &str) -> Result<Vec<String>, ApiError> {
+        if let Some((archive, subdir)) = self.split(dir)? {
+            let index = self.index(archive)?;
+            return Ok(index
+                .members
+                .keys()
+                .filter(|member| member.starts_with(subdir) && !is_hidden(&member[subdir.len()..]))
+                .map(|member| format!("{}/{}", archive, member))
+                .collect());
+        }
+        let mut paths = Vec::new();
+        for path in self.inner.list(dir)? {
+            if is_archive(&path) {
+                match self.index(&path) {
+                    Ok(index) => paths.extend(
+                        index
+                            .members
+                            .keys()
+                            .filter(|member| !is_hidden(member))
+                            .map(|member| format!("{}/{}", path, member)),
+                    ),
+                    //an archive that can't be read is skipped rather than failing the entire listing
+                    Err(_) => warn!("Skipping unreadable archive {}", path),
+                }
+            } else {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    /// An archive counts as a directory, so it is not listed itself (its members are in a subdirectory)
+    fn list_dir(&self, dir: &str) -> Result<Vec<String>, ApiError> {
+        Ok(match self.split(dir)? {
+            Some(_) => self
+                .list(dir)?
+                .into_iter()
+                .filter(|path| !path[dir.len()..].contains('/'))
+                .collect(),
+            None => self
+                .inner
+                .list_dir(dir)?
+                .into_iter()
+                .filter(|path| !is_archive(path))
+                .collect(),
+        })
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<FileStat>, ApiError> {
+        match self.split(path)? {
+            Some((archive, member)) => {
+                //members have the modification time of the archive, so their indices are rebuilt whenever the archive changes
+                let index = self.index(archive)?;
+                Ok(index.members.get(member).map(|member| FileStat {
+                    size: member.size,
+                    mtime: index.mtime,
+                }))
+            }
+            None => self.inner.stat(path),
+        }
+    }
+
+    fn read_range(&self, path: &str, begin: u64, end: u64) -> Result<Vec<u8>, ApiError> {
+        let Some((archive, member)) = self.split(path)? else {
+            return self.inner.read_range(path, begin, end);
+        };
+        let index = self.index(archive)?;
+        let member = index
+            .members
+            .get(member)
+            .ok_or(ApiError::NotFound("No such file in archive"))?;
+        if end <= begin {
+            return Ok(Vec::new());
+        }
+        if end > member.size {
+            return Err(ApiError::ParameterError("Range exceeds file size"));
+        }
+        if !member.deflated {
+            //stored data is read directly
+            let data_start = self.data_start(archive, member)?;
+            return self
+                .inner
+                .read_range(archive, data_start + begin, data_start + end);
+        }
+        //compressed data can only be read sequentially, so rather than decompressing everything before the range on every read, the member is extracted once and read from the cache
+        let filename = self
+            .local_path(path)?
+            .ok_or(ApiError::NotFound("No such file in archive"))?;
+        read_file_range(&filename, begin, end)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> Result<(), ApiError> {
+        match self.split(path)? {
+            Some(_) => Err(ApiError::PermissionDenied("Archives are read-only")),
+            None => self.inner.write(path, data),
+        }
+    }
+
+    fn delete(&self, path: &str) -> Result<(), ApiError> {
+        match self.split(path)? {
+            Some(_) => Err(ApiError::PermissionDenied("Archives are read-only")),
+            None => self.inner.delete(path),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        if self.split(from)?.is_some() || self.split(to)?.is_some() {
+            Err(ApiError::PermissionDenied("Archives are read-only"))
+        } else {
+            self.inner.rename(from, to)
+        }
+    }
+
+    /// Returns the filename of an extracted copy of an archive member, extracting it if there is no up-to-date copy yet
+    fn local_path(&self, path: &str) -> Result<Option<PathBuf>, ApiError> {
+        let Some((archive, member)) = self.split(path)? else {
+            return self.inner.local_path(path);
+        };
+        let index = self.index(archive)?;
+        let Some(member) = index.members.get(member) else {
+            return Ok(None);
+        };
+        let cachefile = self.cache.dir().join(path);
+        if let Ok(metadata) = std::fs::metadata(&cachefile) {
+            if metadata.len() == member.size
+                && metadata.modified().is_ok_and(|mtime| mtime >= index.mtime)
+            {
+                self.cache.touch(&cachefile)?;
+                return Ok(Some(cachefile));
+            }
+        }
+        info!("Extracting {}", path);
+        self.cache
+            .write(&cachefile, &mut self.open(archive, member)?)?;
+        Ok(Some(cachefile))
+    }
+
+    fn is_remote(&self, path: &str) -> bool {
+        match self.split(path) {
+            Ok(Some(_)) => true,
+            _ => self.inner.is_remote(path),
+        }
+    }
+
+    /// Picks up changes from the underlying storage, an archive that changed implies that all its members (before and after the change) changed
+    fn refresh(&self) -> Result<Vec<String>, ApiError> {
+        let mut paths = Vec::new();
+        for path in self.inner.refresh()? {
+            if is_archive(&path) {
+                let previous = self
+                    .indices
+                    .write()
+                    .map_err(|_| ApiError::InternalError("Lock poisoned"))?
+                    .remove(&path);
+                if let Some(previous) = previous {
+                    paths.extend(
+                        previous
+                            .members
+                            .keys()
+                            .map(|member| format!("{}/{}", path, member)),
+                    );
+                }
+                if let Ok(index) = self.index(&path) {
+                    paths.extend(
+                        index
+                            .members
+                            .keys()
+                            .map(|member| format!("{}/{}", path, member)),
+                    );
+                }
+            } else {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Removes the least recently used extracted members from the cache until it is within its size limit again, then prunes the underlying storage
+    fn prune(&self, in_use: &dyn Fn(&Path) -> bool) -> Result<(), ApiError> {
+        self.cache.prune(in_use)?;
+        self.inner.prune(in_use)
+    }
+}
+
+/// Reads the members of a zip file from its central directory
+fn zip_members(
+    reader: impl Read + Seek,
+) -> Result<BTreeMap<String, Member>, Box<dyn std::error::Error>> {
+    let archive = ZipArchive::new(reader)?;
+    let mut members = BTreeMap::new();
+    for i in 0..archive.len() {
+        let file = archive.by_index_data(i)?;
+        if !file.is_file() {
+            continue;
+        }
+        let name = file.name()?;
+        let deflated = match file.compression() {
+            CompressionMethod::Stored => false,
+            CompressionMethod::DEFLATE => true,
+            method => {
+                warn!(
+                    "Skipping {}, unsupported compression method {}",
+                    name, method
+                );
+                continue;
+            }
+        };
+        if file.encrypted() {
+            warn!("Skipping {}, encrypted", name);
+            continue;
+        }
+        if let Some(name) = member_name(&name) {
+            members.insert(
+                name,
+                Member {
+                    header_start: file.header_start(),
+                    data_start: OnceLock::new(),
+                    size: file.size(),
+                    compressed_size: file.compressed_size(),
+                    deflated,
+                },
+            );
+        }
+    }
+    Ok(members)
+}
+
+/// Reads the members of a tar file, this needs to visit the header of each member
+fn tar_members(
+    reader: impl Read + Seek,
+) -> Result<BTreeMap<String, Member>, Box<dyn std::error::Error>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut members = BTreeMap::new();
+    for entry in archive.entries_with_seek()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?;
+        if let Some(name) = path.to_str().and_then(member_name) {
+            let data_start = entry.raw_file_position();
+            members.insert(
+                name,
+                Member {
+                    header_start: data_start,
+                    data_start: data_start.into(),
+                    size: entry.size(),
+                    compressed_size: entry.size(),
+                    deflated: false,
+                },
+            );
+        }
+    }
+    Ok(members)
+}
+
+/// Normalises the path of a member in an archive. Returns `None` for paths that would break out of the archive (absolute paths or paths with parent directories).
+fn member_name(name: &str) -> Option<String> {
+    let mut components = Vec::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(component) => components.push(component.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!components.is_empty()).then(|| components.join("/"))
+}
+
+/// Does the path have the extension of a supported archive format?
+fn is_archive(path: &str) -> bool {
+    path.ends_with(".zip") || path.ends_with(".tar")
+}
+
+/// Does the path have a hidden component?
+fn is_hidden(path: &str) -> bool {
+    path.split('/').any(|component| component.starts_with('.'))
+}
+
+/// Reads a file from a storage backend as a stream
+struct StorageReader {
+    storage: Arc<dyn Storage>,
+    path: String,
+    pos: u64,
+    size: u64,
+}
+
+impl StorageReader {
+    /// Opens a file of the given size (reading stops there, so it can also be used to read only a part of a file)
+    fn new(storage: Arc<dyn Storage>, path: &str, size: u64) -> Self {
+        Self {
+            storage,
+            path: path.to_string(),
+            pos: 0,
+            size,
+        }
+    }
+}
+
+impl Read for StorageReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let end = self.size.min(self.pos + buf.len() as u64);
+        if end <= self.pos {
+            return Ok(0);
+        }
+        let data = self
+            .storage
+            .read_range(&self.path, self.pos, end)
+            .map_err(|e| std::io::Error::other(format!("{:?}", e)))?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.pos = end;
+        Ok(data.len())
+    }
+}
+
+impl Seek for StorageReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "invalid seek",
+        ))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FilesystemStorage;
+    use std::fs::File;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    /// A text that is long enough for deflate to make a difference, with multi-byte characters
+    fn content() -> String {
+        (0..500)
+            .map(|i| format!("Regel {} met ümlauts en één €\n", i))
+            .collect()
+    }
+
+    /// Creates an empty directory for a test
+    fn testdir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("textsurf-archive-{}-{}", std::process::id(), name));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).expect("creating test directory");
+        dir
+    }
+
+    /// Creates a storage holding a zip file (with a stored and a deflated member) and a tar file, besides a plain file
+    fn storage(name: &str) -> (ArchiveStorage, PathBuf) {
+        let dir = testdir(name);
+        let mut zip = ZipWriter::new(File::create(dir.join("test.zip")).expect("creating zip"));
+        for (member, method) in [
+            ("stored.txt", CompressionMethod::Stored),
+            ("sub/deflated.txt", CompressionMethod::Deflated),
+        ] {
+            zip.start_file(
+                member,
+                SimpleFileOptions::default().compression_method(method),
+            )
+            .expect("adding zip member");
+            zip.write_all(content().as_bytes())
+                .expect("writing zip member");
+        }
+        zip.finish().expect("finishing zip");
+        let mut tar = tar::Builder::new(File::create(dir.join("test.tar")).expect("creating tar"));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content().len() as u64);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, "member.txt", content().as_bytes())
+            .expect("adding tar member");
+        tar.finish().expect("finishing tar");
+        std::fs::write(dir.join("plain.txt"), "plain").expect("writing plain file");
+        let storage = ArchiveStorage::new(
+            Arc::new(FilesystemStorage::new(&dir)),
+            &dir.join(ARCHIVE_CACHE_DIR),
+            1 << 20,
+        )
+        .expect("opening archive storage");
+        (storage, dir)
+    }
+
+    #[test]
+    fn lists_members() {
+        let (storage, dir) = storage("list");
+        let mut paths = storage.list("").expect("listing");
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "plain.txt",
+                "test.tar/member.txt",
+                "test.zip/stored.txt",
+                "test.zip/sub/deflated.txt"
+            ]
+        );
+        let mut paths = storage.list_dir("").expect("listing");
+        paths.sort();
+        assert_eq!(paths, ["plain.txt"]);
+        assert_eq!(
+            storage.list_dir("test.zip/").expect("listing"),
+            ["test.zip/stored.txt"]
+        );
+        assert_eq!(
+            storage.list("test.zip/sub/").expect("listing"),
+            ["test.zip/sub/deflated.txt"]
+        );
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn stat_member() {
+        let (storage, dir) = storage("stat");
+        let stat = storage
+            .stat("test.zip/sub/deflated.txt")
+            .expect("stat")
+            .expect("member must exist");
+        assert_eq!(stat.size, content().len() as u64);
+        assert!(storage
+            .stat("test.zip/missing.txt")
+            .expect("stat")
+            .is_none());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn read_range() {
+        let (storage, dir) = storage("range");
+        let content = content();
+        for path in [
+            "test.zip/stored.txt",
+            "test.zip/sub/deflated.txt",
+            "test.tar/member.txt",
+        ] {
+            for (begin, end) in [(0, 10), (1000, 1100), (content.len() - 5, content.len())] {
+                assert_eq!(
+                    storage
+                        .read_range(path, begin as u64, end as u64)
+                        .expect("reading range"),
+                    content.as_bytes()[begin..end],
+                    "{} {}-{}",
+                    path,
+                    begin,
+                    end
+                );
+            }
+            assert!(storage
+                .read_range(path, 0, content.len() as u64 + 1)
+                .is_err());
+        }
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn members_are_read_only() {
+        let (storage, dir) = storage("readonly");
+        assert!(matches!(
+            storage.write("test.zip/stored.txt", b"changed"),
+            Err(ApiError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            storage.delete("test.tar/member.txt"),
+            Err(ApiError::PermissionDenied(_))
+        ));
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn prune_extracted_members() {
+        let (storage, dir) = storage("prune");
+        let cachefile = storage
+            .local_path("test.zip/sub/deflated.txt")
+            .expect("extracting")
+            .expect("member must exist");
+        assert_eq!(
+            std::fs::read_to_string(&cachefile).expect("reading extracted member"),
+            content()
+        );
+        //within the limit, so it is kept
+        storage.prune(&|_| false).expect("pruning");
+        assert!(cachefile.exists());
+        let storage = ArchiveStorage::new(
+            Arc::new(FilesystemStorage::new(&dir)),
+            &dir.join(ARCHIVE_CACHE_DIR),
+            0,
+        )
+        .expect("opening archive storage");
+        //extracted members that are in use are kept
+        storage.prune(&|path| path == cachefile).expect("pruning");
+        assert!(cachefile.exists());
+        storage.prune(&|_| false).expect("pruning");
+        assert!(!cachefile.exists());
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,649 @@
+use crate::common::ApiError;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::info;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Identifies our auxiliary index files
+const MAGIC: &[u8; 4] = b"TSAX";
+
+/// Auxiliary indices complement the textframe position index and allow addressing texts in other units than unicode points.
+/// They are cached on disk next to the textframe index, this lists the kinds that may exist.
+pub const AUX_INDEX_KINDS: &[&str] = &["utf16", "grapheme", "token", "para", "page"];
+
+/// Kind of the cached index that holds the checksum of a text (see [`Checksum`]), it is written whenever a text is loaded rather than built on demand
+pub const CHECKSUM_KIND: &str = "checksum";
+
+/// Returns the filename of an auxiliary index for a text file
+pub fn aux_index_path(textpath: &Path, kind: &str) -> PathBuf {
+    textpath.with_extension(format!("{}.index", kind))
+}
+
+/// An auxiliary index that can be cached on disk
+pub trait AuxIndex: Sized {
+    /// Loads the index from a cached index file, returns `None` if there is no (valid or up-to-date) cache
+    fn from_file(indexpath: &Path, textpath: &Path) -> Option<Self>;
+
+    /// Saves the index to a cached index file
+    fn to_file(&self, indexpath: &Path) -> Result<(), ApiError>;
+}
+
+/// Loads an auxiliary index of the given kind from its cache file, or builds (and caches) it if there is no up-to-date cache
+pub fn load_or_build<T, F>(textpath: &Path, kind: &str, build: F) -> Result<T, ApiError>
+where
+    T: AuxIndex,
+    F: FnOnce(&Path) -> Result<T, ApiError>,
+{
+    let indexpath = aux_index_path(textpath, kind);
+    if let Some(index) = T::from_file(&indexpath, textpath) {
+        Ok(index)
+    } else {
+        info!("Building {} index for {}", kind, textpath.display());
+        let index = build(textpath)?;
+        index.to_file(&indexpath)?;
+        //load it again from the cache, so indices that are kept on disk don't stay in memory
+        Ok(T::from_file(&indexpath, textpath).unwrap_or(index))
+    }
+}
+
+/// The SHA-256 checksum of a text, cached on disk so it can be reported without loading the text (as for the revisions of a text)
+pub struct Checksum(pub [u8; 32]);
+
+impl Checksum {
+    /// Returns the checksum as a hexadecimal digest string
+    pub fn digest(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl AuxIndex for Checksum {
+    fn from_file(indexpath: &Path, textpath: &Path) -> Option<Self> {
+        let values = read_index(indexpath, textpath)?;
+        if values.len() != 4 {
+            return None;
+        }
+        let mut checksum = [0; 32];
+        for (bytes, value) in checksum.chunks_exact_mut(8).zip(values) {
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+        Some(Self(checksum))
+    }
+
+    fn to_file(&self, indexpath: &Path) -> Result<(), ApiError> {
+        let values: Vec<u64> = self
+            .0
+            .chunks_exact(8)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().expect("chunk must be 8 bytes")))
+            .collect();
+        write_index(indexpath, &values)
+    }
+}
+
+/// A segment in which characters and units do not correspond one-to-one
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    /// Begin offset in unicode points
+    charbegin: usize,
+    /// Length in unicode points
+    charlen: usize,
+    /// Begin offset in units
+    unitbegin: usize,
+    /// Length in units
+    unitlen: usize,
+}
+
+/// Maps between unicode points and an alternative unit (such as UTF-16 code units).
+/// Characters and units are assumed to correspond one-to-one, except in irregular segments, which are the only ones stored.
+/// This keeps the index small for most texts.
+#[derive(Debug, Default)]
+pub struct Alignment {
+    segments: Vec<Segment>,
+    /// Length of the text in unicode points
+    chars: usize,
+}
+
+impl Alignment {
+    fn push(&mut self, charbegin: usize, charlen: usize, unitlen: usize) {
+        let unitbegin = self.to_unit_unchecked(charbegin);
+        self.segments.push(Segment {
+            charbegin,
+            charlen,
+            unitbegin,
+            unitlen,
+        });
+    }
+
+    /// Computes the unit offset for a character offset, assuming all segments lie before it
+    fn to_unit_unchecked(&self, charpos: usize) -> usize {
+        if let Some(last) = self.segments.last() {
+            last.unitbegin + last.unitlen + (charpos - last.charbegin - last.charlen)
+        } else {
+            charpos
+        }
+    }
+
+    /// Builds an alignment between unicode points and UTF-16 code units
+    pub fn new_utf16(textpath: &Path) -> Result<Self, ApiError> {
+        let mut alignment = Self::default();
+        let chars = scan_lines(textpath, |line, charpos| {
+            for (i, c) in line.chars().enumerate() {
+                if c.len_utf16() > 1 {
+                    alignment.push(charpos + i, 1, c.len_utf16());
+                }
+            }
+        })?;
+        alignment.chars = chars;
+        Ok(alignment)
+    }
+
+    /// Builds an alignment between unicode points and extended grapheme clusters (user-perceived characters)
+    pub fn new_graphemes(textpath: &Path) -> Result<Self, ApiError> {
+        let mut alignment = Self::default();
+        let chars = scan_lines(textpath, |line, charpos| {
+            let mut offset = charpos;
+            for grapheme in line.graphemes(true) {
+                let len = grapheme.chars().count();
+                if len > 1 {
+                    alignment.push(offset, len, 1);
+                }
+                offset += len;
+            }
+        })?;
+        alignment.chars = chars;
+        Ok(alignment)
+    }
+
+    /// Returns the length of the text in units
+    pub fn len(&self) -> usize {
+        self.to_unit_unchecked(self.chars)
+    }
+
+    /// Converts an absolute character offset to an absolute unit offset
+    pub fn to_unit(&self, charpos: usize) -> Result<usize, ApiError> {
+        if charpos > self.chars {
+            return Err(ApiError::TextError(textframe::Error::OutOfBoundsError {
+                begin: charpos as isize,
+                end: 0,
+            }));
+        }
+        let i = self
+            .segments
+            .partition_point(|s| s.charbegin + s.charlen <= charpos);
+        if let Some(segment) = self.segments.get(i) {
+            if segment.charbegin < charpos {
+                return Err(ApiError::ParameterError(
+                    "Offset does not correspond to a unit boundary",
+                ));
+            }
+            Ok(segment.unitbegin - (segment.charbegin - charpos))
+        } else {
+            Ok(self.to_unit_unchecked(charpos))
+        }
+    }
+
+    /// Converts an absolute unit offset to an absolute character offset
+    pub fn to_char(&self, unitpos: usize) -> Result<usize, ApiError> {
+        if unitpos > self.len() {
+            return Err(ApiError::TextError(textframe::Error::OutOfBoundsError {
+                begin: unitpos as isize,
+                end: 0,
+            }));
+        }
+        let i = self
+            .segments
+            .partition_point(|s| s.unitbegin + s.unitlen <= unitpos);
+        if let Some(segment) = self.segments.get(i) {
+            if segment.unitbegin < unitpos {
+                return Err(ApiError::ParameterError(
+                    "Offset does not correspond to a character boundary",
+                ));
+            }
+            Ok(segment.charbegin - (segment.unitbegin - unitpos))
+        } else if let Some(last) = self.segments.last() {
+            Ok(last.charbegin + last.charlen + (unitpos - last.unitbegin - last.unitlen))
+        } else {
+            Ok(unitpos)
+        }
+    }
+
+    /// Converts a relative unit range to an absolute character range.
+    /// Negative offsets are interpreted relative to the end, an end of 0 denotes the end of the text (as in `TextFile::absolute_pos()`).
+    pub fn absolute_pos(&self, begin: isize, end: isize) -> Result<(usize, usize), ApiError> {
+        let (begin, end) = absolute_offsets(begin, end, self.len())?;
+        Ok((self.to_char(begin)?, self.to_char(end)?))
+    }
+}
+
+impl AuxIndex for Alignment {
+    fn from_file(indexpath: &Path, textpath: &Path) -> Option<Self> {
+        let values = read_index(indexpath, textpath)?;
+        let (chars, values) = values.split_first()?;
+        if values.len() % 3 != 0 {
+            return None;
+        }
+        let mut alignment = Self {
+            segments: Vec::with_capacity(values.len() / 3),
+            chars: *chars as usize,
+        };
+        for triple in values.chunks_exact(3) {
+            //the same computation as push(), but checked: a corrupt cache must not panic, it is rebuilt instead
+            let (charbegin, charlen, unitlen) =
+                (triple[0] as usize, triple[1] as usize, triple[2] as usize);
+            let (previous_charend, previous_unitend) = match alignment.segments.last() {
+                Some(last) => (
+                    last.charbegin + last.charlen,
+                    last.unitbegin.checked_add(last.unitlen)?,
+                ),
+                None => (0, 0),
+            };
+            if charbegin.checked_add(charlen)? > alignment.chars {
+                return None;
+            }
+            let unitbegin =
+                previous_unitend.checked_add(charbegin.checked_sub(previous_charend)?)?;
+            alignment.segments.push(Segment {
+                charbegin,
+                charlen,
+                unitbegin,
+                unitlen,
+            });
+        }
+        //the length in units is derived from the last segment as well
+        alignment.segments.last().map_or(Some(0), |last| {
+            last.unitbegin
+                .checked_add(last.unitlen)?
+                .checked_add(alignment.chars - last.charbegin - last.charlen)
+        })?;
+        Some(alignment)
+    }
+
+    fn to_file(&self, indexpath: &Path) -> Result<(), ApiError> {
+        let mut values: Vec<u64> = Vec::with_capacity(self.segments.len() * 3 + 1);
+        values.push(self.chars as u64);
+        for segment in self.segments.iter() {
+            values.push(segment.charbegin as u64);
+            values.push(segment.charlen as u64);
+            values.push(segment.unitlen as u64);
+        }
+        write_index(indexpath, &values)
+    }
+}
+
+/// Holds the character spans of a sequence of non-overlapping segments (such as tokens) in a text, in textual order.
+/// Unlike an `Alignment`, segments need not cover the entire text.
+/// When loaded from a cache file, the spans are not read into memory but the file is memory-mapped, as there is one for every token in the text.
+#[derive(Debug, Default)]
+pub struct Spans {
+    /// Begin and end offsets in unicode points (end is non-inclusive)
+    spans: SpanData,
+    /// Length of the text in unicode points
+    chars: usize,
+}
+
+#[derive(Debug)]
+enum SpanData {
+    Memory(Vec<(usize, usize)>),
+    /// An index file (see `write_index()`), holding the length of the text followed by the begin and end offset of each span
+    Mapped(Mmap),
+}
+
+impl Default for SpanData {
+    fn default() -> Self {
+        Self::Memory(Vec::new())
+    }
+}
+
+impl SpanData {
+    /// Offset of the first span in a mapped index file, after the magic and the length of the text
+    const MAPPED_OFFSET: usize = MAGIC.len() + 8;
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Memory(spans) => spans.len(),
+            Self::Mapped(map) => (map.len() - Self::MAPPED_OFFSET) / 16,
+        }
+    }
+
+    fn get(&self, i: usize) -> Option<(usize, usize)> {
+        match self {
+            Self::Memory(spans) => spans.get(i).copied(),
+            Self::Mapped(map) => {
+                let offset = Self::MAPPED_OFFSET + i * 16;
+                let bytes = map.get(offset..offset + 16)?;
+                Some((
+                    u64::from_le_bytes(bytes[..8].try_into().expect("must be 8 bytes")) as usize,
+                    u64::from_le_bytes(bytes[8..].try_into().expect("must be 8 bytes")) as usize,
+                ))
+            }
+        }
+    }
+
+    /// Returns the index of the first span for which the predicate is false (the spans must be partitioned by it, as in `slice::partition_point()`)
+    fn partition_point(&self, pred: impl Fn((usize, usize)) -> bool) -> usize {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if pred(self.get(mid).expect("span must exist")) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+}
+
+impl Spans {
+    /// Builds an index of tokens. Tokens are determined by unicode word segmentation (as defined in [Unicode Standard Annex #29](https://unicode.org/reports/tr29/)),
+    /// all segments except those consisting solely of whitespace count as tokens (so punctuation is tokenised as well).
+    pub fn new_tokens(textpath: &Path) -> Result<Self, ApiError> {
+        let mut spans = Vec::new();
+        let chars = scan_lines(textpath, |line, charpos| {
+            for (offset, token) in split_tokens(line) {
+                let begin = charpos + offset;
+                spans.push((begin, begin + token.chars().count()));
+            }
+        })?;
+        Ok(Self {
+            spans: SpanData::Memory(spans),
+            chars,
+        })
+    }
+
+    /// Builds an index of paragraphs and an index of pages, in a single pass.
+    /// Paragraphs are separated by blank lines (or lines consisting only of whitespace) and by form feeds, a paragraph includes the trailing newline of its last line.
+    /// Pages are separated by form feeds (which are not part of the page), a final page consisting solely of whitespace is ignored.
+    pub fn new_paragraphs_and_pages(textpath: &Path) -> Result<(Self, Self), ApiError> {
+        let mut paragraphs = Vec::new();
+        let mut pages = Vec::new();
+        let mut paragraph_begin: Option<usize> = None;
+        let mut paragraph_end = 0;
+        let mut page_begin = 0;
+        let mut page_blank = true;
+        let chars = scan_lines(textpath, |line, charpos| {
+            let mut offset = charpos;
+            for (i, piece) in line.split('\u{000C}').enumerate() {
+                if i > 0 {
+                    //we passed a form feed
+                    if let Some(begin) = paragraph_begin.take() {
+                        paragraphs.push((begin, paragraph_end));
+                    }
+                    pages.push((page_begin, offset));
+                    offset += 1;
+                    page_begin = offset;
+                    page_blank = true;
+                }
+                let len = piece.chars().count();
+                if piece.chars().all(char::is_whitespace) {
+                    if piece.ends_with('\n') {
+                        //blank line
+                        if let Some(begin) = paragraph_begin.take() {
+                            paragraphs.push((begin, paragraph_end));
+                        }
+                    }
+                } else {
+                    if paragraph_begin.is_none() {
+                        paragraph_begin = Some(offset);
+                    }
+                    paragraph_end = offset + len;
+                    page_blank = false;
+                }
+                offset += len;
+            }
+        })?;
+        if let Some(begin) = paragraph_begin {
+            paragraphs.push((begin, paragraph_end));
+        }
+        if !page_blank || pages.is_empty() {
+            pages.push((page_begin, chars));
+        }
+        Ok((
+            Self {
+                spans: SpanData::Memory(paragraphs),
+                chars,
+            },
+            Self {
+                spans: SpanData::Memory(pages),
+                chars,
+            },
+        ))
+    }
+
+    /// Returns the number of segments
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    /// Returns the begin offset of the n-th segment that ends at or before the given absolute character offset (or 0 if there are fewer segments)
+    pub fn preceding(&self, charpos: usize, n: usize) -> usize {
+        if n == 0 {
+            return charpos;
+        }
+        let i = self.spans.partition_point(|span| span.1 <= charpos);
+        if i >= n {
+            self.spans.get(i - n).expect("span must exist").0
+        } else {
+            0
+        }
+    }
+
+    /// Returns the end offset of the n-th segment that begins at or after the given absolute character offset (or the end of the text if there are fewer segments)
+    pub fn following(&self, charpos: usize, n: usize) -> usize {
+        if n == 0 {
+            return charpos;
+        }
+        let i = self.spans.partition_point(|span| span.0 < charpos);
+        if let Some(span) = self.spans.get(i + n - 1) {
+            span.1
+        } else {
+            self.chars
+        }
+    }
+
+    /// Returns the index of the segment that holds the given absolute character offset, if any
+    pub fn find(&self, charpos: usize) -> Option<usize> {
+        let i = self.spans.partition_point(|span| span.1 <= charpos);
+        match self.spans.get(i) {
+            Some(span) if span.0 <= charpos => Some(i),
+            _ => None,
+        }
+    }
+
+    /// Does the character range begin at the beginning of a segment and end at the end of a (possibly other) segment?
+    pub fn aligned(&self, begin: usize, end: usize) -> bool {
+        let first = self.find(begin).and_then(|i| self.spans.get(i));
+        let last = self
+            .find(end.saturating_sub(1))
+            .and_then(|i| self.spans.get(i));
+        begin < end
+            && first.is_some_and(|span| span.0 == begin)
+            && last.is_some_and(|span| span.1 == end)
+    }
+
+    /// Converts a relative segment range to an absolute character range, spanning from the beginning of the first segment to the end of the last one.
+    /// Negative offsets are interpreted relative to the end, an end of 0 denotes the end of the text (as in `TextFile::absolute_pos()`).
+    pub fn absolute_pos(&self, begin: isize, end: isize) -> Result<(usize, usize), ApiError> {
+        let (begin, end) = absolute_offsets(begin, end, self.len())?;
+        let charbegin = self
+            .spans
+            .get(begin)
+            .map(|span| span.0)
+            .unwrap_or(self.chars);
+        if begin == end {
+            Ok((charbegin, charbegin))
+        } else {
+            Ok((
+                charbegin,
+                self.spans.get(end - 1).expect("span must exist").1,
+            ))
+        }
+    }
+}
+
+impl AuxIndex for Spans {
+    fn from_file(indexpath: &Path, textpath: &Path) -> Option<Self> {
+        let map = map_index(indexpath, textpath)?;
+        if map.len() < SpanData::MAPPED_OFFSET
+            || !(map.len() - SpanData::MAPPED_OFFSET).is_multiple_of(16)
+        {
+            return None;
+        }
+        let chars = u64::from_le_bytes(map[MAGIC.len()..SpanData::MAPPED_OFFSET].try_into().ok()?);
+        Some(Self {
+            spans: SpanData::Mapped(map),
+            chars: chars as usize,
+        })
+    }
+
+    fn to_file(&self, indexpath: &Path) -> Result<(), ApiError> {
+        let mut values: Vec<u64> = Vec::with_capacity(self.spans.len() * 2 + 1);
+        values.push(self.chars as u64);
+        for i in 0..self.spans.len() {
+            let (begin, end) = self.spans.get(i).expect("span must exist");
+            values.push(begin as u64);
+            values.push(end as u64);
+        }
+        write_index(indexpath, &values)
+    }
+}
+
+/// Loads the paragraph and page indices from their cache files, or builds (and caches) them if there is no up-to-date cache
+pub fn load_or_build_paragraphs_and_pages<F>(
+    textpath: &Path,
+    build: F,
+) -> Result<(Spans, Spans), ApiError>
+where
+    F: FnOnce(&Path) -> Result<(Spans, Spans), ApiError>,
+{
+    let paragraphs_path = aux_index_path(textpath, "para");
+    let pages_path = aux_index_path(textpath, "page");
+    if let (Some(paragraphs), Some(pages)) = (
+        Spans::from_file(&paragraphs_path, textpath),
+        Spans::from_file(&pages_path, textpath),
+    ) {
+        Ok((paragraphs, pages))
+    } else {
+        info!(
+            "Building paragraph and page index for {}",
+            textpath.display()
+        );
+        let (paragraphs, pages) = build(textpath)?;
+        paragraphs.to_file(&paragraphs_path)?;
+        pages.to_file(&pages_path)?;
+        Ok((paragraphs, pages))
+    }
+}
+
+/// Converts relative offsets to absolute ones given a length.
+/// Negative offsets are interpreted relative to the end, an end of 0 denotes the end of the text (as in `TextFile::absolute_pos()`).
+pub fn absolute_offsets(
+    mut begin: isize,
+    mut end: isize,
+    len: usize,
+) -> Result<(usize, usize), ApiError> {
+    if begin < 0 {
+        begin += len as isize;
+    }
+    if end <= 0 {
+        end += len as isize;
+    }
+    if begin < 0 || end < 0 || begin > end || end as usize > len {
+        return Err(ApiError::TextError(textframe::Error::OutOfBoundsError {
+            begin,
+            end,
+        }));
+    }
+    Ok((begin as usize, end as usize))
+}
+
+/// Splits a line into tokens, as used by the token index, and returns each token along with its character offset relative to the start of the line.
+pub fn split_tokens(line: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut offset = 0;
+    line.split_word_bounds().filter_map(move |segment| {
+        let begin = offset;
+        offset += segment.chars().count();
+        if segment.chars().all(char::is_whitespace) {
+            None
+        } else {
+            Some((begin, segment))
+        }
+    })
+}
+
+/// Reads a text file line by line and calls the closure for each line (including the trailing newline) along with the character offset where the line begins.
+/// Returns the total number of characters.
+/// Line by line reading keeps memory use low, and none of the units we index ever cross a newline (a grapheme cluster may end with one, which is why it is included).
+pub fn scan_lines<F>(textpath: &Path, mut f: F) -> Result<usize, ApiError>
+where
+    F: FnMut(&str, usize),
+{
+    let mut reader = BufReader::new(File::open(textpath)?);
+    let mut line = String::new();
+    let mut charpos = 0;
+    loop {
+        if reader.read_line(&mut line)? == 0 {
+            //EOF
+            break;
+        }
+        f(line.as_str(), charpos);
+        charpos += line.chars().count();
+        line.clear();
+    }
+    Ok(charpos)
+}
+
+/// Reads the values from an index file, if it is newer than the text file
+fn read_index(indexpath: &Path, textpath: &Path) -> Option<Vec<u64>> {
+    let indexmtime = std::fs::metadata(indexpath).ok()?.modified().ok()?;
+    let textmtime = std::fs::metadata(textpath).ok()?.modified().ok()?;
+    if indexmtime < textmtime {
+        return None;
+    }
+    let mut buffer: Vec<u8> = Vec::new();
+    BufReader::new(File::open(indexpath).ok()?)
+        .read_to_end(&mut buffer)
+        .ok()?;
+    let data = buffer.strip_prefix(MAGIC)?;
+    if data.len() % 8 != 0 {
+        return None;
+    }
+    Some(
+        data.chunks_exact(8)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().expect("chunk must be 8 bytes")))
+            .collect(),
+    )
+}
+
+/// Memory-maps an index file, if it is newer than the text file and has the right magic
+fn map_index(indexpath: &Path, textpath: &Path) -> Option<Mmap> {
+    let indexmtime = std::fs::metadata(indexpath).ok()?.modified().ok()?;
+    let textmtime = std::fs::metadata(textpath).ok()?.modified().ok()?;
+    if indexmtime < textmtime {
+        return None;
+    }
+    //safety: index files are never modified in place, write_index() replaces them, so the mapped file stays intact
+    let map = unsafe { Mmap::map(&File::open(indexpath).ok()?) }.ok()?;
+    map.starts_with(MAGIC).then_some(map)
+}
+
+/// Writes an index file. It is written to a temporary file first and then moved into place, so an index file is never modified while it may be mapped.
+fn write_index(indexpath: &Path, values: &[u64]) -> Result<(), ApiError> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let tmppath = indexpath.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut writer = BufWriter::new(File::create(&tmppath)?);
+    writer.write_all(MAGIC)?;
+    for value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(tmppath, indexpath)?;
+    Ok(())
+}
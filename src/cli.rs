@@ -0,0 +1,777 @@
+//! Commands to interact with a running textsurf server, or directly with a base directory, from the command line
+
+use crate::{open_textpool, ServeArgs};
+use clap::Args;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use textsurf::{ApiError, ApiResponse, TextPool, GIT_CACHE_LIMIT, INVERTED_INDEX_DIR};
+use textsurf_client::{Client, Range};
+use walkdir::WalkDir;
+
+/// Number of seconds before texts are unloaded again, irrelevant for the short-lived pool of a command
+const UNLOAD_TIME: u64 = 600;
+
+/// Where a command operates on: a running server or a base directory
+#[derive(Args, Debug)]
+pub struct Target {
+    #[arg(
+        short,
+        long,
+        help = "URL of a running textsurf server to connect to (e.g. http://localhost:8080). If not set, the base directory is accessed directly."
+    )]
+    url: Option<String>,
+
+    #[arg(
+        short,
+        long,
+        help = "API key to authenticate with at the server, needed for writing if it was started with --apikey"
+    )]
+    apikey: Option<String>,
+
+    #[arg(
+        short = 'd',
+        long,
+        default_value_os = ".",
+        conflicts_with = "url",
+        help = "The base directory to access directly, if no --url is given"
+    )]
+    basedir: String,
+
+    #[arg(
+        short = 'e',
+        long,
+        default_value_os = "txt",
+        conflicts_with = "url",
+        help = "The file extension for plain text files in the base directory, if no --url is given"
+    )]
+    extension: String,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with = "url",
+        help = "The base directory is git-backed (as served with --git), uploads and deletions are committed. Required for writing to a base directory that is a git repository, if no --url is given"
+    )]
+    git: bool,
+
+    #[arg(
+        long,
+        default_value_os = "textsurf <textsurf@localhost>",
+        conflicts_with = "url",
+        help = "Identity (`Name <email>`) used as the author of git commits, in combination with --git"
+    )]
+    git_author: String,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with = "url",
+        help = "The base directory has an inverted index (as served with --inverted-index), which is updated on uploads and deletions. Required for writing to a base directory that has one, if no --url is given"
+    )]
+    inverted_index: bool,
+}
+
+/// A server or a text pool on a base directory
+enum Backend {
+    Server(Client),
+    Local(Box<TextPool>),
+}
+
+impl Target {
+    /// Opens the target for commands that only read, a base directory is opened read-only
+    fn open(&self) -> Result<Backend, Box<dyn Error>> {
+        self.open_with(true)
+    }
+
+    fn open_with(&self, readonly: bool) -> Result<Backend, Box<dyn Error>> {
+        match self.url.as_deref() {
+            Some(url) => {
+                let client = Client::new(url);
+                Ok(Backend::Server(match self.apikey.as_deref() {
+                    Some(apikey) => client.with_token(apikey),
+                    None => client,
+                }))
+            }
+            None => {
+                let textpool = TextPool::new(
+                    self.basedir.as_str(),
+                    self.extension.as_str(),
+                    readonly,
+                    None,
+                    true,
+                    UNLOAD_TIME,
+                )?;
+                //opening git storage may initialise a repository, which is only done when writing
+                let textpool = if self.git && !readonly {
+                    textpool.with_git(&self.git_author, GIT_CACHE_LIMIT)?
+                } else {
+                    textpool
+                };
+                let textpool = if self.inverted_index {
+                    //only the texts that are written need to be (re)indexed, the server loads the rest
+                    textpool.with_inverted_index(Vec::new())
+                } else {
+                    textpool
+                };
+                Ok(Backend::Local(Box::new(textpool)))
+            }
+        }
+    }
+
+    /// Opens the target for commands that write. A base directory is refused if writing to it without the options it is served with would leave it inconsistent:
+    /// without --git, changes to a git repository are not committed, and without --inverted-index, the inverted index becomes stale.
+    fn open_for_writing(&self) -> Result<Backend, Box<dyn Error>> {
+        if self.url.is_none() {
+            let basedir = Path::new(&self.basedir);
+            if !self.git && basedir.join(".git").exists() {
+                return Err(
+                    "The base directory is a git repository, pass --git so changes are committed"
+                        .into(),
+                );
+            }
+            if !self.inverted_index && basedir.join(INVERTED_INDEX_DIR).exists() {
+                return Err("The base directory has an inverted index, pass --inverted-index so it is updated".into());
+            }
+        }
+        self.open_with(false)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct GetArgs {
+    #[command(flatten)]
+    target: Target,
+
+    #[arg(help = "The identifier of the text")]
+    id: String,
+
+    #[arg(
+        long,
+        allow_hyphen_values = true,
+        conflicts_with = "line",
+        help = "Only output this range of unicode points ({begin},{end}, 0-indexed, end is non-inclusive). Negative offsets count from the end of the text and an omitted end denotes the end of the text."
+    )]
+    char: Option<String>,
+
+    #[arg(
+        long,
+        allow_hyphen_values = true,
+        help = "Only output this range of lines ({begin},{end}, 0-indexed, end is non-inclusive). Negative offsets count from the end of the text and an omitted end denotes the end of the text."
+    )]
+    line: Option<String>,
+}
+
+/// Writes a text (or a range therein) to standard output
+pub fn get(args: GetArgs) -> Result<(), Box<dyn Error>> {
+    let range = match (args.char.as_deref(), args.line.as_deref()) {
+        (Some(range), _) => {
+            let (begin, end) = parse_range(range)?;
+            Range::Char(begin, end)
+        }
+        (None, Some(range)) => {
+            let (begin, end) = parse_range(range)?;
+            Range::Line(begin, end)
+        }
+        (None, None) => Range::Full,
+    };
+    let mut stdout = std::io::stdout().lock();
+    match args.target.open()? {
+        Backend::Server(client) => {
+            std::io::copy(&mut client.get_reader(&args.id, range)?, &mut stdout)?;
+        }
+        Backend::Local(textpool) => {
+            let write = |text: &str| Ok(stdout.write_all(text.as_bytes())?);
+            match range {
+                Range::Full => textpool.map(&args.id, 0, 0, write)?,
+                Range::Char(begin, end) => textpool.map(&args.id, begin, end, write)?,
+                Range::Line(begin, end) => textpool.map_lines(&args.id, begin, end, write)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Args, Debug)]
+pub struct PutArgs {
+    #[command(flatten)]
+    target: Target,
+
+    #[arg(help = "The identifier of the text")]
+    id: String,
+
+    #[arg(help = "The file to upload, read from standard input if omitted")]
+    file: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Fail if the text already exists rather than overwriting it"
+    )]
+    new: bool,
+}
+
+/// Uploads a text
+pub fn put(args: PutArgs) -> Result<(), Box<dyn Error>> {
+    let text = match args.file.as_deref() {
+        Some(file) => std::fs::read_to_string(file)?,
+        None => {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            text
+        }
+    };
+    match args.target.open_for_writing()? {
+        Backend::Server(client) if args.new => client.create(&args.id, &text)?,
+        Backend::Server(client) => {
+            client.overwrite(&args.id, &text)?;
+        }
+        Backend::Local(textpool) => {
+            textpool.new_text(&args.id, text, !args.new)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Args, Debug)]
+pub struct RmArgs {
+    #[command(flatten)]
+    target: Target,
+
+    #[arg(required = true, help = "The identifiers of the texts")]
+    ids: Vec<String>,
+}
+
+/// Deletes texts
+pub fn rm(args: RmArgs) -> Result<(), Box<dyn Error>> {
+    match args.target.open_for_writing()? {
+        Backend::Server(client) => {
+            for id in args.ids.iter() {
+                client.delete(id)?;
+            }
+        }
+        Backend::Local(textpool) => {
+            for id in args.ids.iter() {
+                textpool.delete_text(id)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Args, Debug)]
+pub struct LsArgs {
+    #[command(flatten)]
+    target: Target,
+
+    #[arg(help = "Only list the texts under this directory (relative to it)")]
+    dir: Option<String>,
+}
+
+/// Lists the identifiers of all texts, one per line
+pub fn ls(args: LsArgs) -> Result<(), Box<dyn Error>> {
+    let dir = args.dir.as_deref().unwrap_or("").trim_matches('/');
+    let mut ids = match args.target.open()? {
+        Backend::Server(client) => client.list_dir(dir)?,
+        Backend::Local(textpool) if dir.is_empty() => textpool.list("")?,
+        Backend::Local(textpool) => textpool.list(&format!("{}/", dir))?,
+    };
+    ids.sort_unstable();
+    let mut stdout = std::io::stdout().lock();
+    for id in ids {
+        writeln!(stdout, "{}", id)?;
+    }
+    Ok(())
+}
+
+#[derive(Args, Debug)]
+pub struct StatArgs {
+    #[command(flatten)]
+    target: Target,
+
+    #[arg(help = "The identifier of the text")]
+    id: String,
+}
+
+/// Outputs metadata about a text as JSON, with the same keys as the `/stat/` endpoint
+pub fn stat(args: StatArgs) -> Result<(), Box<dyn Error>> {
+    let stat = match args.target.open()? {
+        Backend::Server(client) => serde_json::to_value(client.stat(&args.id)?)?,
+        Backend::Local(textpool) => match textpool.stat(&args.id)? {
+            ApiResponse::Stat {
+                chars,
+                bytes,
+                mtime,
+                checksum,
+                version,
+                revisions,
+            } => serde_json::json!({
+                "bytes": bytes,
+                "chars": chars,
+                "checksum": checksum,
+                "mtime": mtime,
+                "version": version,
+                "revisions": revisions,
+            }),
+            _ => return Err(ApiError::InternalError("unexpected response").into()),
+        },
+    };
+    println!("{}", serde_json::to_string_pretty(&stat)?);
+    Ok(())
+}
+
+#[derive(Args, Debug)]
+pub struct IndexArgs {
+    #[command(flatten)]
+    serve: ServeArgs,
+
+    #[arg(
+        short = 'j',
+        long,
+        help = "Number of texts to index in parallel [default: the number of available CPUs]"
+    )]
+    jobs: Option<usize>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Also build the indices that are otherwise only computed when first needed (for utf16, grapheme, token, para and page ranges)"
+    )]
+    aux: bool,
+}
+
+/// Builds or refreshes the indices of all texts in the base directory, in parallel. The options are the same as for serving, so the indices match what the service needs.
+pub fn index(args: IndexArgs) -> Result<(), Box<dyn Error>> {
+    if args.serve.debug {
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .init();
+    }
+    let start = Instant::now();
+    let textpool = open_textpool(&args.serve);
+    let ids = textpool.list("")?;
+    let jobs = args
+        .jobs
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+        .clamp(1, ids.len().max(1));
+    eprintln!(
+        "[textsurf] indexing {} text(s) with {} job(s)",
+        ids.len(),
+        jobs
+    );
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let failures: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                while let Some(id) = ids.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    let result = textpool.build_indices(id, args.aux);
+                    let done = done.fetch_add(1, Ordering::SeqCst) + 1;
+                    match result {
+                        Ok(()) => eprintln!("[textsurf] [{}/{}] indexed {}", done, ids.len(), id),
+                        Err(e) => {
+                            eprintln!(
+                                "[textsurf] [{}/{}] failed to index {}: {}",
+                                done,
+                                ids.len(),
+                                id,
+                                e
+                            );
+                            failures.lock().expect("lock poisoned").push(id.clone());
+                        }
+                    }
+                }
+            });
+        }
+    });
+    let failures = failures.into_inner().expect("lock poisoned");
+    eprintln!(
+        "[textsurf] indexed {} text(s) in {:.1}s, {} failed",
+        ids.len() - failures.len(),
+        start.elapsed().as_secs_f64(),
+        failures.len()
+    );
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("failed to index: {}", failures.join(", ")).into())
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct SyncArgs {
+    #[arg(help = "The local directory holding the texts")]
+    dir: String,
+
+    #[arg(
+        short,
+        long,
+        help = "URL of the textsurf server to synchronise to (e.g. http://localhost:8080)"
+    )]
+    url: String,
+
+    #[arg(
+        short,
+        long,
+        help = "API key to authenticate with at the server, needed if it was started with --apikey"
+    )]
+    apikey: Option<String>,
+
+    #[arg(
+        short = 'e',
+        long,
+        default_value_os = "txt",
+        help = "The file extension for plain text files in the local directory, this should match the extension the server uses"
+    )]
+    extension: String,
+
+    #[arg(
+        short = 'r',
+        long,
+        default_value_os = "",
+        help = "Synchronise with this directory on the server rather than with all its texts"
+    )]
+    remote_dir: String,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Delete texts from the server that do not exist in the local directory"
+    )]
+    delete: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Also upload texts that were modified on the server since the last synchronisation (or that differ from the server without an earlier synchronisation), discarding those modifications"
+    )]
+    force: bool,
+
+    #[arg(
+        short = 'n',
+        long,
+        default_value_t = false,
+        help = "Only report what would be done, without changing anything on the server"
+    )]
+    dry_run: bool,
+}
+
+/// Uploads the texts in a local directory that are new or changed compared to the server (by checksum), and optionally deletes texts from the server that no longer exist locally.
+/// Texts that were modified on the server since the last synchronisation (according to the checksums recorded in [`SYNC_STATE_FILE`]) are left alone, unless forced.
+/// Every action is reported on standard output, one per line.
+pub fn sync(args: SyncArgs) -> Result<(), Box<dyn Error>> {
+    let client = Client::new(args.url.as_str());
+    let client = match args.apikey.as_deref() {
+        Some(apikey) => client.with_token(apikey),
+        None => client,
+    };
+    let remote_dir = args.remote_dir.trim_matches('/');
+    let remote_id = |id: &str| {
+        if remote_dir.is_empty() {
+            id.to_string()
+        } else {
+            format!("{}/{}", remote_dir, id)
+        }
+    };
+    let local = local_texts(Path::new(&args.dir), &args.extension);
+    let remote: BTreeSet<String> = client.list_dir(remote_dir)?.into_iter().collect();
+    let state_file = Path::new(&args.dir).join(SYNC_STATE_FILE);
+    let mut state = read_sync_state(&state_file)?;
+    let synced = state
+        .entry(args.url.trim_end_matches('/').to_string())
+        .or_default();
+
+    let (mut created, mut updated, mut unchanged, mut skipped, mut deleted) = (0, 0, 0, 0, 0);
+    let mut failures: Vec<String> = Vec::new();
+    let mut stdout = std::io::stdout().lock();
+    for (id, filename) in local.iter() {
+        let exists = remote.contains(id.as_str());
+        match sync_text(&client, &remote_id(id), filename, exists, synced, &args) {
+            Ok(SyncOutcome::Created) => {
+                writeln!(stdout, "create\t{}", id)?;
+                created += 1;
+            }
+            Ok(SyncOutcome::Updated) => {
+                writeln!(stdout, "update\t{}", id)?;
+                updated += 1;
+            }
+            Ok(SyncOutcome::Skipped) => {
+                writeln!(
+                    stdout,
+                    "skip\t{}\t(modified on the server since the last synchronisation)",
+                    id
+                )?;
+                skipped += 1;
+            }
+            Ok(SyncOutcome::Unchanged) => unchanged += 1,
+            Err(e) => {
+                eprintln!("[textsurf] failed to synchronise {}: {}", id, e);
+                failures.push(id.clone());
+            }
+        }
+    }
+    let extra: Vec<&String> = remote
+        .iter()
+        .filter(|id| !local.contains_key(*id))
+        .collect();
+    if args.delete {
+        for id in extra.iter() {
+            writeln!(stdout, "delete\t{}", id)?;
+            if args.dry_run {
+                deleted += 1;
+            } else {
+                match client.delete(&remote_id(id)) {
+                    Ok(()) => {
+                        synced.remove(&remote_id(id));
+                        deleted += 1;
+                    }
+                    Err(e) => {
+                        eprintln!("[textsurf] failed to delete {}: {}", id, e);
+                        failures.push(id.to_string());
+                    }
+                }
+            }
+        }
+    }
+    if !args.dry_run {
+        std::fs::write(&state_file, serde_json::to_string_pretty(&state)?)?;
+    }
+    eprintln!(
+        "[textsurf] {}{} created, {} updated, {} unchanged, {} skipped, {} deleted",
+        if args.dry_run { "(dry run) " } else { "" },
+        created,
+        updated,
+        unchanged,
+        skipped,
+        deleted
+    );
+    if !args.delete && !extra.is_empty() {
+        eprintln!(
+            "[textsurf] {} text(s) on the server do not exist locally (pass --delete to delete them)",
+            extra.len()
+        );
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("failed to synchronise: {}", failures.join(", ")).into())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum SyncOutcome {
+    Created,
+    Updated,
+    Unchanged,
+    Skipped,
+}
+
+/// Name of the (hidden) file in the local directory that records the checksums of the texts as of the last synchronisation, per server
+const SYNC_STATE_FILE: &str = ".textsurf-sync.json";
+
+/// Maps server URLs to the identifiers of the texts on that server and their checksums as of the last synchronisation
+type SyncState = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Reads the state of earlier synchronisations, this is empty if there were none
+fn read_sync_state(filename: &Path) -> Result<SyncState, Box<dyn Error>> {
+    match std::fs::read(filename) {
+        Ok(data) => Ok(serde_json::from_slice(&data)
+            .map_err(|e| format!("invalid {}: {}", filename.display(), e))?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(SyncState::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Uploads a single text if it is new or changed (unless this is a dry run), see [`sync_outcome()`].
+/// `synced` is updated with the checksum of the text as it is now on the server.
+fn sync_text(
+    client: &Client,
+    id: &str,
+    filename: &Path,
+    exists: bool,
+    synced: &mut BTreeMap<String, String>,
+    args: &SyncArgs,
+) -> Result<SyncOutcome, Box<dyn Error>> {
+    let text = String::from_utf8(std::fs::read(filename)?)?;
+    let local_checksum = checksum(text.as_bytes());
+    let server_checksum = if exists {
+        Some(client.stat(id)?.checksum)
+    } else {
+        None
+    };
+    let outcome = sync_outcome(
+        &local_checksum,
+        server_checksum.as_deref(),
+        synced.get(id).map(String::as_str),
+        args.force,
+    );
+    match outcome {
+        SyncOutcome::Unchanged => {
+            synced.insert(id.to_string(), local_checksum);
+        }
+        SyncOutcome::Created | SyncOutcome::Updated if !args.dry_run => {
+            client.overwrite(id, &text)?;
+            synced.insert(id.to_string(), local_checksum);
+        }
+        _ => {}
+    }
+    Ok(outcome)
+}
+
+/// Decides what to do with a local text, given the checksum of the text on the server (if it exists there) and its checksum as recorded at the last synchronisation (if any).
+/// An existing text on the server is only replaced if it still has the recorded checksum (or if forced), otherwise it was modified on the server in the meantime.
+fn sync_outcome(
+    local_checksum: &str,
+    server_checksum: Option<&str>,
+    synced_checksum: Option<&str>,
+    force: bool,
+) -> SyncOutcome {
+    match server_checksum {
+        None => SyncOutcome::Created,
+        Some(server_checksum) if server_checksum == local_checksum => SyncOutcome::Unchanged,
+        Some(server_checksum) if synced_checksum != Some(server_checksum) && !force => {
+            SyncOutcome::Skipped
+        }
+        Some(_) => SyncOutcome::Updated,
+    }
+}
+
+/// Finds all texts in a local directory (recursively, skipping hidden files and directories), by text identifier
+fn local_texts(dir: &Path, extension: &str) -> BTreeMap<String, PathBuf> {
+    let suffix = format!(".{}", extension);
+    WalkDir::new(dir)
+        .follow_links(true)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || e.file_name().as_encoded_bytes().first() != Some(&b'.'))
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| {
+            let path = e.path().strip_prefix(dir).ok()?.to_str()?.to_string();
+            let id = if extension.is_empty() {
+                (!path.ends_with(".index")).then_some(path)?
+            } else {
+                path.strip_suffix(suffix.as_str())?.to_string()
+            };
+            Some((id, e.path().to_path_buf()))
+        })
+        .collect()
+}
+
+/// Computes the SHA-256 checksum of a text, as reported by the server
+fn checksum(data: &[u8]) -> String {
+    hmac_sha256::Hash::hash(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Parses a range (`{begin},{end}`), either may be omitted and then defaults to 0
+fn parse_range(input: &str) -> Result<(isize, isize), ApiError> {
+    let (begin, end) = input.split_once(',').unwrap_or((input, ""));
+    let parse = |field: &str| {
+        if field.is_empty() {
+            Ok(0)
+        } else {
+            field
+                .parse()
+                .map_err(|_| ApiError::ParameterError("range parameter must be an integer"))
+        }
+    };
+    Ok((parse(begin)?, parse(end)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_is_sha256() {
+        assert_eq!(
+            checksum(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn sync_new_text() {
+        assert_eq!(sync_outcome("a", None, None, false), SyncOutcome::Created);
+        //a text that was deleted from the server since the last synchronisation is created again
+        assert_eq!(
+            sync_outcome("a", None, Some("a"), false),
+            SyncOutcome::Created
+        );
+    }
+
+    #[test]
+    fn sync_unchanged_text() {
+        assert_eq!(
+            sync_outcome("a", Some("a"), None, false),
+            SyncOutcome::Unchanged
+        );
+        assert_eq!(
+            sync_outcome("a", Some("a"), Some("b"), false),
+            SyncOutcome::Unchanged
+        );
+    }
+
+    #[test]
+    fn sync_changed_text() {
+        //changed locally, the server still has the text as synchronised last time
+        assert_eq!(
+            sync_outcome("b", Some("a"), Some("a"), false),
+            SyncOutcome::Updated
+        );
+    }
+
+    #[test]
+    fn sync_conflict() {
+        //changed on the server since the last synchronisation
+        assert_eq!(
+            sync_outcome("b", Some("c"), Some("a"), false),
+            SyncOutcome::Skipped
+        );
+        //never synchronised, so the text on the server is not known to be ours
+        assert_eq!(
+            sync_outcome("b", Some("c"), None, false),
+            SyncOutcome::Skipped
+        );
+        assert_eq!(
+            sync_outcome("b", Some("c"), Some("a"), true),
+            SyncOutcome::Updated
+        );
+        assert_eq!(
+            sync_outcome("b", Some("c"), None, true),
+            SyncOutcome::Updated
+        );
+    }
+
+    #[test]
+    fn sync_state() {
+        let filename = std::env::temp_dir().join(format!(
+            "textsurf-cli-{}-sync-state.json",
+            std::process::id()
+        ));
+        std::fs::remove_file(&filename).ok();
+        assert!(read_sync_state(&filename)
+            .expect("reading state")
+            .is_empty());
+        std::fs::write(&filename, r#"{"http://localhost:8080":{"a":"0123"}}"#)
+            .expect("writing state");
+        let state = read_sync_state(&filename).expect("reading state");
+        assert_eq!(
+            state
+                .get("http://localhost:8080")
+                .and_then(|synced| synced.get("a"))
+                .map(String::as_str),
+            Some("0123")
+        );
+        std::fs::write(&filename, "invalid").expect("writing state");
+        assert!(read_sync_state(&filename).is_err());
+        std::fs::remove_file(filename).ok();
+    }
+}
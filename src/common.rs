@@ -19,19 +19,28 @@ pub enum ApiResponse {
     NoContent(),
     Text(String),
     TextStream(Body),
+    NdJsonStream(Body),
+    JsonStream(Body),
+    Csv(String),
+    Tsv(String),
     Stat {
         chars: u64,
         bytes: u64,
         mtime: u64,
         checksum: String,
+        version: usize,
+        revisions: Vec<Value>,
     },
     StatLD {
         chars: u64,
         bytes: u64,
         mtime: u64,
         checksum: String,
+        version: usize,
+        revisions: Vec<Value>,
     },
     JsonList(Vec<Value>),
+    Json(Value),
 }
 
 impl IntoResponse for ApiResponse {
@@ -73,18 +82,75 @@ impl IntoResponse for ApiResponse {
                 stream,
             )
                 .into_response(),
+            Self::NdJsonStream(stream) => (
+                StatusCode::OK,
+                [
+                    cors,
+                    server,
+                    (
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_str("application/x-ndjson").unwrap(),
+                    ),
+                ],
+                stream,
+            )
+                .into_response(),
+            Self::JsonStream(stream) => (
+                StatusCode::OK,
+                [
+                    cors,
+                    server,
+                    (
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_str("application/json").unwrap(),
+                    ),
+                ],
+                stream,
+            )
+                .into_response(),
+            Self::Csv(s) => (
+                StatusCode::OK,
+                [
+                    cors,
+                    server,
+                    (
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_str("text/csv; charset=utf-8").unwrap(),
+                    ),
+                ],
+                s,
+            )
+                .into_response(),
+            Self::Tsv(s) => (
+                StatusCode::OK,
+                [
+                    cors,
+                    server,
+                    (
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_str("text/tab-separated-values; charset=utf-8").unwrap(),
+                    ),
+                ],
+                s,
+            )
+                .into_response(),
             Self::JsonList(data) => (StatusCode::OK, [cors, server], Json(data)).into_response(),
+            Self::Json(data) => (StatusCode::OK, [cors, server], Json(data)).into_response(),
             Self::Stat {
                 chars,
                 bytes,
                 mtime,
                 checksum,
+                version,
+                revisions,
             } => {
                 let mut map: BTreeMap<&'static str, Value> = BTreeMap::new();
                 map.insert("chars", chars.into());
                 map.insert("bytes", bytes.into());
                 map.insert("mtime", mtime.into());
                 map.insert("checksum", checksum.into());
+                map.insert("version", version.into());
+                map.insert("revisions", revisions.into());
                 (StatusCode::OK, [cors, server], Json(map)).into_response()
             }
             Self::StatLD {
@@ -92,6 +158,8 @@ impl IntoResponse for ApiResponse {
                 bytes,
                 mtime,
                 checksum,
+                version,
+                revisions,
             } => {
                 let mut map: BTreeMap<&'static str, Value> = BTreeMap::new();
                 map.insert("@context", "https://w3id.org/textsurf/api2.jsonld".into());
@@ -101,6 +169,8 @@ impl IntoResponse for ApiResponse {
                 map.insert("bytes", bytes.into());
                 map.insert("mtime", mtime.into());
                 map.insert("checksum", checksum.into());
+                map.insert("version", version.into());
+                map.insert("revisions", revisions.into());
                 (StatusCode::OK, [cors, server], Json(map)).into_response()
             }
         }
@@ -155,6 +225,21 @@ impl Serialize for ApiError {
     }
 }
 
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InternalError(s) => write!(f, "internal error: {}", s),
+            Self::NotFound(s) => write!(f, "not found: {}", s),
+            Self::NotAcceptable(s) => write!(f, "not acceptable: {}", s),
+            Self::PermissionDenied(s) => write!(f, "permission denied: {}", s),
+            Self::ParameterError(s) => write!(f, "parameter error: {}", s),
+            Self::TextError(e) => write!(f, "text error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
         let statuscode = match self {
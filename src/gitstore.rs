@@ -0,0 +1,384 @@
+use crate::common::ApiError;
+use crate::storage::{read_file_range, FileCache, FileStat, Storage};
+use git2::{ErrorCode, ObjectType, Oid, Repository, Signature, TreeWalkMode, TreeWalkResult};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+/// Name of the (hidden) directory under the base directory where texts retrieved from git are cached (by blob hash)
+pub const GIT_CACHE_DIR: &str = ".gitcache";
+
+/// Default maximum total size (in bytes) of the texts retrieved from git that are kept in the cache
+pub const GIT_CACHE_LIMIT: u64 = 1 << 30;
+
+/// Ignore rules that the repository must have, so indices and other internal data are never committed
+const GITIGNORE: &str = "*.index\n.versions/\n.invindex/\n.gitcache/\n";
+
+/// Storage mode in which the base directory is a git repository and every write is committed
+pub struct GitStore {
+    repo: Mutex<Repository>,
+    workdir: PathBuf,
+    cache: FileCache,
+    author_name: String,
+    author_email: String,
+}
+
+impl GitStore {
+    /// Opens the git repository in the base directory, or initialises a new one if there is none.
+    /// The default author identity (for writes without an identity of their own) is expected in the form `Name <email>`.
+    /// At most `cache_limit` bytes of texts retrieved from git are kept in the cache (see [`Self::prune()`]).
+    pub fn open(basedir: &Path, author: &str, cache_limit: u64) -> Result<Self, ApiError> {
+        let (author_name, author_email) = parse_identity(author).ok_or(
+            ApiError::ParameterError("git author must be of the form 'Name <email>'"),
+        )?;
+        let repo = match Repository::open(basedir) {
+            Ok(repo) => repo,
+            Err(e) if e.code() == ErrorCode::NotFound => {
+                info!("Initialising git repository in {}", basedir.display());
+                Repository::init(basedir).map_err(git_error)?
+            }
+            Err(e) => return Err(git_error(e)),
+        };
+        update_gitignore(&repo, basedir)?;
+        let workdir = repo
+            .workdir()
+            .ok_or(ApiError::InternalError(
+                "git repository in base directory must not be bare",
+            ))?
+            .canonicalize()?;
+        Ok(Self {
+            repo: Mutex::new(repo),
+            cache: FileCache::open(&basedir.join(GIT_CACHE_DIR), cache_limit, is_blob_file)?,
+            workdir,
+            author_name,
+            author_email,
+        })
+    }
+
+    /// Commits the current state of a file (a path relative to the root of the repository): a file that exists is added, a file that no longer exists is removed.
+    /// The commit is authored by the given identity (`Name <email>`), or by the default author if there is none.
+    pub fn commit(&self, path: &str, message: &str, author: Option<&str>) -> Result<(), ApiError> {
+        let (author_name, author_email) = match author {
+            Some(author) => parse_identity(author).ok_or(ApiError::ParameterError(
+                "git author must be of the form 'Name <email>'",
+            ))?,
+            None => (self.author_name.clone(), self.author_email.clone()),
+        };
+        let repo = self
+            .repo
+            .lock()
+            .map_err(|_| ApiError::InternalError("Lock poisoned: git repository"))?;
+        let mut index = repo.index().map_err(git_error)?;
+        if self.workdir.join(path).exists() {
+            index.add_path(Path::new(path)).map_err(git_error)?;
+        } else {
+            index.remove_path(Path::new(path)).map_err(git_error)?;
+        }
+        index.write().map_err(git_error)?;
+        let tree = repo
+            .find_tree(index.write_tree().map_err(git_error)?)
+            .map_err(git_error)?;
+        let parent = match repo.head() {
+            Ok(head) => Some(head.peel_to_commit().map_err(git_error)?),
+            Err(e) if e.code() == ErrorCode::UnbornBranch => None, //first commit
+            Err(e) => return Err(git_error(e)),
+        };
+        let author = Signature::now(&author_name, &author_email).map_err(git_error)?;
+        let committer = Signature::now("textsurf", "textsurf@localhost").map_err(git_error)?;
+        let parents: Vec<_> = parent.iter().collect();
+        let oid = repo
+            .commit(Some("HEAD"), &author, &committer, message, &tree, &parents)
+            .map_err(git_error)?;
+        info!("Committed {} ({})", path, oid);
+        Ok(())
+    }
+
+    /// Retrieves a file (a path relative to the root of the repository) as it was at a given ref (a branch, tag, commit or any other git revision expression) and returns the filename of a cached copy.
+    /// Cached copies are named after the blob hash, so identical content is only stored (and indexed) once.
+    pub fn checkout(&self, path: &str, reference: &str) -> Result<PathBuf, ApiError> {
+        let repo = self
+            .repo
+            .lock()
+            .map_err(|_| ApiError::InternalError("Lock poisoned: git repository"))?;
+        let commit = repo
+            .revparse_single(reference)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|_| ApiError::NotFound("No such ref"))?;
+        let blob = commit
+            .tree()
+            .and_then(|tree| tree.get_path(Path::new(path)))
+            .and_then(|entry| entry.to_object(&repo))
+            .and_then(|object| object.peel_to_blob())
+            .map_err(|_| ApiError::NotFound("No such text at this ref"))?;
+        cached_blob(&self.cache, blob.id(), path, || Ok(blob.content().to_vec()))
+    }
+
+    /// Removes the least recently used texts retrieved from git (and their indices) from the cache until it is within its size limit again.
+    /// Files for which `in_use` returns true are kept.
+    pub fn prune(&self, in_use: &dyn Fn(&Path) -> bool) -> Result<(), ApiError> {
+        self.cache.prune(in_use)
+    }
+}
+
+/// Adds the ignore rules of [`GITIGNORE`] that are missing from the `.gitignore` file in the base directory (creating it if needed).
+/// A changed `.gitignore` is staged, so it becomes part of the next commit.
+fn update_gitignore(repo: &Repository, basedir: &Path) -> Result<(), ApiError> {
+    let gitignore = basedir.join(".gitignore");
+    let mut content = match std::fs::read_to_string(&gitignore) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let missing: Vec<&str> = GITIGNORE
+        .lines()
+        .filter(|rule| !content.lines().any(|line| line.trim() == *rule))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    for rule in missing {
+        content.push_str(rule);
+        content.push('\n');
+    }
+    std::fs::write(gitignore, content)?;
+    let mut index = repo.index().map_err(git_error)?;
+    index.add_path(Path::new(".gitignore")).map_err(git_error)?;
+    index.write().map_err(git_error)?;
+    Ok(())
+}
+
+/// Returns the filename of the cached copy of a blob (for a file at `path`, whose extension it takes), writing it with the given content if it is not cached yet
+fn cached_blob(
+    cache: &FileCache,
+    oid: Oid,
+    path: &str,
+    content: impl FnOnce() -> Result<Vec<u8>, ApiError>,
+) -> Result<PathBuf, ApiError> {
+    let mut cachefile = cache.dir().join(oid.to_string());
+    if let Some(extension) = Path::new(path).extension() {
+        cachefile.set_extension(extension);
+    }
+    if !cache.touch(&cachefile)? {
+        cache.write(&cachefile, &mut content()?.as_slice())?;
+    }
+    Ok(cachefile)
+}
+
+/// Is this the filename of a blob in the cache (named after its hash, with the extension of the original file)?
+fn is_blob_file(filename: &Path) -> bool {
+    let stem = filename
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("");
+    let extension = filename
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("");
+    stem.len() >= 40
+        && stem.bytes().all(|c| c.is_ascii_hexdigit())
+        && extension != "index"
+        && extension != "tmp"
+}
+
+/// Read-only storage backend that serves texts straight from a (bare) git repository at a given ref, without a checkout.
+/// The ref is resolved to a commit on creation and whenever [`Storage::refresh()`] is called.
+/// Since texts need to be on disk for indexing, blobs are written to a cache directory (named after the blob hash) when first accessed.
+pub struct BareRepository {
+    repo: Mutex<Repository>,
+    reference: String,
+    cache: FileCache,
+    state: RwLock<Snapshot>,
+}
+
+/// The files in a repository at a specific commit
+struct Snapshot {
+    commit: Option<Oid>,
+    /// Commit time, serves as the modification time of all files
+    time: SystemTime,
+    /// Maps paths to blob hashes
+    files: BTreeMap<String, Oid>,
+}
+
+impl BareRepository {
+    /// Opens the repository and resolves the ref. At most `cache_limit` bytes of blobs are kept in the cache directory.
+    pub fn open(
+        path: &Path,
+        reference: &str,
+        cachedir: &Path,
+        cache_limit: u64,
+    ) -> Result<Self, ApiError> {
+        let repo = Repository::open_bare(path)
+            .or_else(|_| Repository::open(path))
+            .map_err(git_error)?;
+        let bare = Self {
+            repo: Mutex::new(repo),
+            reference: reference.to_string(),
+            cache: FileCache::open(cachedir, cache_limit, is_blob_file)?,
+            state: RwLock::new(Snapshot {
+                commit: None,
+                time: UNIX_EPOCH,
+                files: BTreeMap::new(),
+            }),
+        };
+        bare.refresh()?;
+        Ok(bare)
+    }
+
+    fn snapshot(&self) -> Result<RwLockReadGuard<'_, Snapshot>, ApiError> {
+        self.state
+            .read()
+            .map_err(|_| ApiError::InternalError("Lock poisoned: git snapshot"))
+    }
+
+    fn blob_content(&self, oid: Oid) -> Result<Vec<u8>, ApiError> {
+        let repo = self
+            .repo
+            .lock()
+            .map_err(|_| ApiError::InternalError("Lock poisoned: git repository"))?;
+        let blob = repo.find_blob(oid).map_err(git_error)?;
+        Ok(blob.content().to_vec())
+    }
+}
+
+impl Storage for BareRepository {
+    fn list(&self, dir: &str) -> Result<Vec<String>, ApiError> {
+        Ok(self
+            .snapshot()?
+            .files
+            .range(dir.to_string()..)
+            .take_while(|(path, _)| path.starts_with(dir))
+            .map(|(path, _)| path.clone())
+            .filter(|path| {
+                !path[dir.len()..]
+                    .split('/')
+                    .any(|component| component.starts_with('.'))
+            })
+            .collect())
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<FileStat>, ApiError> {
+        let (oid, mtime) = {
+            let state = self.snapshot()?;
+            let Some(oid) = state.files.get(path).copied() else {
+                return Ok(None);
+            };
+            (oid, state.time)
+        };
+        let repo = self
+            .repo
+            .lock()
+            .map_err(|_| ApiError::InternalError("Lock poisoned: git repository"))?;
+        let size = repo.find_blob(oid).map_err(git_error)?.size() as u64;
+        Ok(Some(FileStat { size, mtime }))
+    }
+
+    fn read_range(&self, path: &str, begin: u64, end: u64) -> Result<Vec<u8>, ApiError> {
+        //blobs can only be read as a whole, so ranges are read from the cached copy instead
+        let filename = self
+            .local_path(path)?
+            .ok_or(ApiError::NotFound("No such file"))?;
+        read_file_range(&filename, begin, end)
+    }
+
+    fn write(&self, _path: &str, _data: &[u8]) -> Result<(), ApiError> {
+        Err(ApiError::PermissionDenied("Repository is read-only"))
+    }
+
+    fn delete(&self, _path: &str) -> Result<(), ApiError> {
+        Err(ApiError::PermissionDenied("Repository is read-only"))
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> Result<(), ApiError> {
+        Err(ApiError::PermissionDenied("Repository is read-only"))
+    }
+
+    /// Returns the filename of a cached copy of the file, writing it if needed.
+    fn local_path(&self, path: &str) -> Result<Option<PathBuf>, ApiError> {
+        let Some(oid) = self.snapshot()?.files.get(path).copied() else {
+            return Ok(None);
+        };
+        cached_blob(&self.cache, oid, path, || self.blob_content(oid)).map(Some)
+    }
+
+    fn prune(&self, in_use: &dyn Fn(&Path) -> bool) -> Result<(), ApiError> {
+        self.cache.prune(in_use)
+    }
+
+    /// Resolves the ref again. Returns the paths of all files that changed (or were added or removed) since the last time.
+    fn refresh(&self) -> Result<Vec<String>, ApiError> {
+        let repo = self
+            .repo
+            .lock()
+            .map_err(|_| ApiError::InternalError("Lock poisoned: git repository"))?;
+        let commit = repo
+            .revparse_single(&self.reference)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(git_error)?;
+        if self.snapshot()?.commit == Some(commit.id()) {
+            return Ok(Vec::new());
+        }
+        let mut files = BTreeMap::new();
+        commit
+            .tree()
+            .and_then(|tree| {
+                tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+                    if entry.kind() == Some(ObjectType::Blob) {
+                        if let Ok(name) = entry.name() {
+                            files.insert(format!("{}{}", dir, name), entry.id());
+                        }
+                    }
+                    TreeWalkResult::Ok
+                })
+            })
+            .map_err(git_error)?;
+        let mut state = self
+            .state
+            .write()
+            .map_err(|_| ApiError::InternalError("Lock poisoned: git snapshot"))?;
+        let mut changed: Vec<String> = files
+            .iter()
+            .filter(|(path, oid)| state.files.get(*path) != Some(oid))
+            .map(|(path, _)| path.clone())
+            .collect();
+        changed.extend(
+            state
+                .files
+                .keys()
+                .filter(|path| !files.contains_key(*path))
+                .cloned(),
+        );
+        info!(
+            "{} resolved to commit {} ({} file(s), {} changed)",
+            self.reference,
+            commit.id(),
+            files.len(),
+            changed.len()
+        );
+        *state = Snapshot {
+            commit: Some(commit.id()),
+            time: UNIX_EPOCH + Duration::from_secs(commit.time().seconds().max(0) as u64),
+            files,
+        };
+        Ok(changed)
+    }
+}
+
+/// Parses an identity of the form `Name <email>`
+pub(crate) fn parse_identity(identity: &str) -> Option<(String, String)> {
+    let (name, email) = identity.trim().strip_suffix('>')?.split_once('<')?;
+    let name = name.trim();
+    if name.is_empty() || email.is_empty() {
+        return None;
+    }
+    Some((name.to_string(), email.to_string()))
+}
+
+fn git_error(e: git2::Error) -> ApiError {
+    error!("git: {}", e);
+    ApiError::InternalError("git operation failed")
+}
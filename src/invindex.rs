@@ -0,0 +1,371 @@
+use crate::auxindex::{scan_lines, split_tokens};
+use crate::common::ApiError;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+/// Identifies our postings files
+const MAGIC: &[u8; 4] = b"TSI2";
+
+/// Size of a single occurrence in a postings file (ordinal, begin, end and line)
+const OCCURRENCE_SIZE: u64 = 32;
+
+/// Name of the (hidden) directory under the base directory where the inverted index is stored
+pub const INVERTED_INDEX_DIR: &str = ".invindex";
+
+/// A single occurrence of a term in a text
+#[derive(Clone, Copy, Debug)]
+struct Occurrence {
+    /// Position of the token in the text (0-indexed), used to match phrases
+    ordinal: usize,
+    /// Begin offset in unicode points
+    begin: usize,
+    /// End offset in unicode points (non-inclusive)
+    end: usize,
+    /// Line the token is on (0-indexed)
+    line: usize,
+}
+
+/// Where the occurrences of a term in a text are found in the postings file of that text
+#[derive(Clone, Copy, Debug)]
+struct Postings {
+    /// Offset in the postings file
+    offset: u64,
+    /// Number of occurrences
+    count: u64,
+}
+
+/// A phrase match found via the inverted index
+#[derive(Debug)]
+pub struct IndexMatch {
+    /// The text identifier
+    pub id: Arc<str>,
+    /// Begin offset in unicode points (0-indexed)
+    pub begin: usize,
+    /// End offset in unicode points (0-indexed, non-inclusive)
+    pub end: usize,
+    /// Line the match begins on (0-indexed)
+    pub line: usize,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// The term dictionary: maps (lowercased) terms to the texts they occur in, and where their occurrences therein are stored
+    terms: HashMap<String, BTreeMap<Arc<str>, Postings>>,
+    /// Maps texts to the distinct terms they contain, needed for removal
+    texts: HashMap<Arc<str>, Vec<String>>,
+}
+
+/// An inverted index mapping tokens to the texts and character offsets they occur at, for fast corpus-wide search.
+/// Only the term dictionary is held in memory, the occurrences are stored on disk in one postings file per text (in [`INVERTED_INDEX_DIR`]) and read when a query needs them.
+pub struct InvertedIndex {
+    dir: PathBuf,
+    /// Also guards the postings files: they are only replaced while holding the write lock, so they always correspond to the dictionary
+    inner: RwLock<Inner>,
+}
+
+impl InvertedIndex {
+    pub fn new(basedir: &Path) -> Self {
+        Self {
+            dir: basedir.join(INVERTED_INDEX_DIR),
+            inner: RwLock::new(Inner::default()),
+        }
+    }
+
+    fn postings_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.postings", id))
+    }
+
+    /// Adds a text to the index, from its postings file if that is up to date, otherwise the text is (re)tokenised.
+    pub fn load(&self, id: &str, textpath: &Path) -> Result<(), ApiError> {
+        match read_dictionary(&self.postings_path(id), textpath) {
+            Some(dictionary) => self.insert(id, dictionary, None),
+            None => self.update(id, textpath),
+        }
+    }
+
+    /// (Re)indexes a text after it has been written
+    pub fn update(&self, id: &str, textpath: &Path) -> Result<(), ApiError> {
+        let postings_path = self.postings_path(id);
+        let tmppath = postings_path.with_extension("postings.tmp");
+        let dictionary = write_postings(&tmppath, tokenize(textpath)?)?;
+        self.insert(id, dictionary, Some((&tmppath, &postings_path)))
+    }
+
+    /// Removes a text from the index
+    pub fn remove(&self, id: &str) -> Result<(), ApiError> {
+        let mut inner = self
+            .inner
+            .write()
+            .map_err(|_| ApiError::InternalError("Lock poisoned: inverted index"))?;
+        let postings_path = self.postings_path(id);
+        if postings_path.exists() {
+            std::fs::remove_file(postings_path)?;
+        }
+        inner.remove(id);
+        Ok(())
+    }
+
+    /// Adds the dictionary of a text, optionally moving its newly written postings file into place (this has to happen while holding the lock)
+    fn insert(
+        &self,
+        id: &str,
+        dictionary: Vec<(String, Postings)>,
+        rename: Option<(&Path, &Path)>,
+    ) -> Result<(), ApiError> {
+        let mut inner = self
+            .inner
+            .write()
+            .map_err(|_| ApiError::InternalError("Lock poisoned: inverted index"))?;
+        if let Some((from, to)) = rename {
+            std::fs::rename(from, to)?;
+        }
+        inner.remove(id);
+        let id: Arc<str> = id.into();
+        let mut terms: Vec<String> = Vec::with_capacity(dictionary.len());
+        for (term, postings) in dictionary {
+            if let Some(texts) = inner.terms.get_mut(term.as_str()) {
+                texts.insert(id.clone(), postings);
+            } else {
+                inner
+                    .terms
+                    .insert(term.clone(), BTreeMap::from([(id.clone(), postings)]));
+            }
+            terms.push(term);
+        }
+        inner.texts.insert(id, terms);
+        Ok(())
+    }
+
+    /// Searches for a phrase (a sequence of tokens, matched case-insensitively) in all texts whose identifier starts with the given prefix.
+    /// Matches are passed to the closure ordered by text identifier and position, the closure returns whether the search should continue.
+    /// Returns `false` if the search was stopped prematurely, either by the closure or because the deadline passed.
+    pub fn search<F>(
+        &self,
+        query: &str,
+        prefix: &str,
+        deadline: Instant,
+        mut f: F,
+    ) -> Result<bool, ApiError>
+    where
+        F: FnMut(IndexMatch) -> bool,
+    {
+        let terms: Vec<String> = split_tokens(query)
+            .map(|(_, token)| token.to_lowercase())
+            .collect();
+        if terms.is_empty() {
+            return Err(ApiError::ParameterError(
+                "search query must contain at least one token",
+            ));
+        }
+        let inner = self
+            .inner
+            .read()
+            .map_err(|_| ApiError::InternalError("Lock poisoned: inverted index"))?;
+        let mut dictionary = Vec::with_capacity(terms.len());
+        for term in terms.iter() {
+            if let Some(texts) = inner.terms.get(term) {
+                dictionary.push(texts);
+            } else {
+                //a term that does not occur anywhere can't be part of any match
+                return Ok(true);
+            }
+        }
+        for (id, first_postings) in
+            dictionary[0].range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+        {
+            if !id.starts_with(prefix) {
+                break;
+            }
+            //only texts that hold all terms are candidates, their postings are read from disk
+            let Some(postings) = dictionary
+                .iter()
+                .skip(1)
+                .map(|texts| texts.get(id).copied())
+                .collect::<Option<Vec<Postings>>>()
+            else {
+                continue;
+            };
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+            let mut file = BufReader::new(File::open(self.postings_path(id))?);
+            let first_occurrences = read_occurrences(&mut file, *first_postings)?;
+            let occurrences = postings
+                .into_iter()
+                .map(|postings| read_occurrences(&mut file, postings))
+                .collect::<Result<Vec<_>, _>>()?;
+            'occurrences: for first in first_occurrences.iter() {
+                let mut last = first;
+                for (i, occurrences) in occurrences.iter().enumerate() {
+                    let Ok(index) =
+                        occurrences.binary_search_by_key(&(first.ordinal + i + 1), |o| o.ordinal)
+                    else {
+                        continue 'occurrences;
+                    };
+                    last = &occurrences[index];
+                }
+                if !f(IndexMatch {
+                    id: id.clone(),
+                    begin: first.begin,
+                    end: last.end,
+                    line: first.line,
+                }) {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, id: &str) {
+        if let Some(terms) = self.texts.remove(id) {
+            for term in terms {
+                if let Some(texts) = self.terms.get_mut(&term) {
+                    texts.remove(id);
+                    if texts.is_empty() {
+                        self.terms.remove(&term);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Tokenises a text (in the same way as the token index) and returns the lowercased terms along with their occurrences (in order)
+fn tokenize(textpath: &Path) -> Result<BTreeMap<String, Vec<Occurrence>>, ApiError> {
+    let mut terms: BTreeMap<String, Vec<Occurrence>> = BTreeMap::new();
+    let mut ordinal = 0;
+    let mut linenr = 0;
+    scan_lines(textpath, |line, charpos| {
+        for (offset, token) in split_tokens(line) {
+            let begin = charpos + offset;
+            terms
+                .entry(token.to_lowercase())
+                .or_default()
+                .push(Occurrence {
+                    ordinal,
+                    begin,
+                    end: begin + token.chars().count(),
+                    line: linenr,
+                });
+            ordinal += 1;
+        }
+        linenr += 1;
+    })?;
+    Ok(terms)
+}
+
+/// Reads the dictionary (the terms and where their occurrences are) from a postings file, if it is newer than the text file.
+/// The file starts with the number of terms, followed by each term (its length in bytes, the term itself and the number of occurrences), followed by the occurrences of all terms in that same order.
+/// Each occurrence consists of ordinal, begin, end and line. All numbers are u64, little endian.
+fn read_dictionary(postings_path: &Path, textpath: &Path) -> Option<Vec<(String, Postings)>> {
+    let postingsmtime = std::fs::metadata(postings_path).ok()?.modified().ok()?;
+    let textmtime = std::fs::metadata(textpath).ok()?.modified().ok()?;
+    if postingsmtime < textmtime {
+        return None;
+    }
+    let file = File::open(postings_path).ok()?;
+    let size = file.metadata().ok()?.len();
+    let mut reader = BufReader::new(file);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic).ok()?;
+    if &magic != MAGIC {
+        return None;
+    }
+    let read_u64 = |reader: &mut BufReader<File>| -> Option<u64> {
+        let mut bytes = [0; 8];
+        reader.read_exact(&mut bytes).ok()?;
+        Some(u64::from_le_bytes(bytes))
+    };
+    let num_terms = read_u64(&mut reader)?;
+    let mut dictionary = Vec::new();
+    let mut counts = Vec::new();
+    for _ in 0..num_terms {
+        let len = read_u64(&mut reader)?;
+        if len > size {
+            return None;
+        }
+        let mut term = vec![0; len as usize];
+        reader.read_exact(&mut term).ok()?;
+        dictionary.push(String::from_utf8(term).ok()?);
+        counts.push(read_u64(&mut reader)?);
+    }
+    //the occurrences follow the dictionary
+    let mut offset = reader.stream_position().ok()?;
+    let mut result = Vec::with_capacity(dictionary.len());
+    for (term, count) in dictionary.into_iter().zip(counts) {
+        result.push((term, Postings { offset, count }));
+        offset = offset.checked_add(count.checked_mul(OCCURRENCE_SIZE)?)?;
+    }
+    (offset == size).then_some(result)
+}
+
+/// Reads the occurrences of a single term from a postings file
+fn read_occurrences(
+    reader: &mut BufReader<File>,
+    postings: Postings,
+) -> Result<Vec<Occurrence>, ApiError> {
+    reader.seek(SeekFrom::Start(postings.offset))?;
+    let mut buffer = vec![0; (postings.count * OCCURRENCE_SIZE) as usize];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer
+        .chunks_exact(OCCURRENCE_SIZE as usize)
+        .map(|bytes| {
+            let value = |i: usize| {
+                u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().expect("must be 8 bytes"))
+                    as usize
+            };
+            Occurrence {
+                ordinal: value(0),
+                begin: value(1),
+                end: value(2),
+                line: value(3),
+            }
+        })
+        .collect())
+}
+
+/// Writes a postings file (see `read_dictionary()` for the format) and returns its dictionary
+fn write_postings(
+    postings_path: &Path,
+    terms: BTreeMap<String, Vec<Occurrence>>,
+) -> Result<Vec<(String, Postings)>, ApiError> {
+    if let Some(parentdir) = postings_path.parent() {
+        std::fs::create_dir_all(parentdir)?;
+    }
+    let mut writer = BufWriter::new(File::create(postings_path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(terms.len() as u64).to_le_bytes())?;
+    let mut offset = (MAGIC.len() + 8) as u64;
+    for (term, occurrences) in terms.iter() {
+        writer.write_all(&(term.len() as u64).to_le_bytes())?;
+        writer.write_all(term.as_bytes())?;
+        writer.write_all(&(occurrences.len() as u64).to_le_bytes())?;
+        offset += 16 + term.len() as u64;
+    }
+    let mut dictionary = Vec::with_capacity(terms.len());
+    for (term, occurrences) in terms {
+        for occurrence in occurrences.iter() {
+            for value in [
+                occurrence.ordinal,
+                occurrence.begin,
+                occurrence.end,
+                occurrence.line,
+            ] {
+                writer.write_all(&(value as u64).to_le_bytes())?;
+            }
+        }
+        let count = occurrences.len() as u64;
+        dictionary.push((term, Postings { offset, count }));
+        offset += count * OCCURRENCE_SIZE;
+    }
+    writer.flush()?;
+    Ok(dictionary)
+}
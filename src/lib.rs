@@ -0,0 +1,2381 @@
+//! Textsurf is a webservice for efficiently serving multiple plain text documents or excerpts thereof (by unicode character offset), without loading everything into memory.
+//!
+//! Besides the `textsurf` binary, the service can be embedded in any other [axum](https://docs.rs/axum) application:
+//! configure a [`TextPool`] and mount the routes returned by [`router()`] wherever you like.
+//! Texts that are no longer used are only unloaded again (and caches only pruned) on [`TextPool::flush()`], and changes to
+//! the storage (like new commits in a bare repository) are only picked up on [`TextPool::refresh()`]; [`spawn_maintenance()`]
+//! launches background threads that do both periodically, as the `textsurf` binary does.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use textsurf::{router, spawn_maintenance, TextPool};
+//!
+//! # async fn example() {
+//! let textpool = TextPool::new("/data", "txt", true, None, true, 600).expect("Base directory must exist");
+//! let textpool = Arc::new(textpool);
+//! spawn_maintenance(textpool.clone(), Duration::from_secs(60), None);
+//! let app: axum::Router = axum::Router::new().nest("/texts", router(textpool));
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:8080").await.unwrap();
+//! axum::serve(listener, app).await.unwrap();
+//! # }
+//! ```
+
+use axum::{
+    body::Body, extract::NestedPath, extract::Path, extract::Query, extract::State,
+    http::HeaderMap, http::HeaderValue, http::Request, middleware, middleware::Next,
+    response::Response, routing::delete, routing::get, routing::post, routing::put, Extension,
+    Json, Router,
+};
+use futures::StreamExt as _;
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{borrow::Cow, convert::Infallible};
+use tracing::{debug, error, info};
+
+use serde::Deserialize;
+use utoipa::OpenApi;
+
+mod annotation;
+mod apidocs;
+mod archive;
+mod auxindex;
+mod common;
+mod gitstore;
+mod invindex;
+mod s3storage;
+mod search;
+mod storage;
+mod textpool;
+pub use archive::ARCHIVE_CACHE_LIMIT;
+pub use common::{ApiError, ApiResponse, VERSION};
+use gitstore::GIT_CACHE_DIR;
+pub use gitstore::GIT_CACHE_LIMIT;
+pub use invindex::INVERTED_INDEX_DIR;
+pub use s3storage::{S3Storage, S3_CACHE_DIR, S3_CACHE_LIMIT};
+pub use search::SearchLimits;
+use search::{Pattern, SearchMatch};
+pub use storage::{FileStat, FilesystemStorage, Storage};
+pub use textpool::TextPool;
+use textpool::{ContextUnit, CoordinateUnits, Range, Text};
+
+const CONTENT_TYPE_JSON: &str = "application/json";
+const CONTENT_TYPE_TEXT: &str = "text/plain";
+const CONTENT_TYPE_TSV: &str = "text/tab-separated-values";
+const CONTENT_TYPE_CSV: &str = "text/csv";
+// 16KB
+const CHUNK_SIZE: usize = 1 << 14;
+const STREAM_THRESHOLD: usize = CHUNK_SIZE;
+/// Maximum number of items in a single batch request
+const MAX_BATCH_SIZE: usize = 1000;
+/// First path components that are taken by the endpoints of the service, texts can not be created under these identifiers as they would be shadowed
+const RESERVED_IDS: &[&str] = &[
+    "api2",
+    "stat",
+    "convert",
+    "search",
+    "concordance",
+    "selector",
+    "anchor",
+    "batch",
+    "flush",
+];
+
+/// The OpenAPI specification of all routes served by [`router()`]
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        list_texts,
+        create_text,
+        get_text,
+        delete_all,
+        delete_text,
+        stat_text,
+        get_api2_with_region,
+        get_api2_short,
+        create_text_api2,
+        delete_text_api2,
+        get_batch,
+        get_batch_api2,
+        convert,
+        search,
+        search_all,
+        search_api2,
+        concordance,
+        concordance_all,
+        resolve_selector,
+        resolve_selector_text,
+        anchor_selector,
+        anchor_selector_text,
+    ),
+    tags(
+        (name = "textsurf", description = "Webservice for efficiently serving multiple plain text documents or excerpts thereof (by unicode character offset), without loading everything into memory.")
+    )
+)]
+pub struct ApiDoc;
+
+/// Launches background threads that maintain a text pool: every `flush_interval` texts that were not used for a while are unloaded (see [`TextPool::flush()`]),
+/// and if a `refresh_interval` is given, changes to the storage are picked up that often (see [`TextPool::refresh()`], needed for a bare repository).
+/// Errors are logged. The threads run for as long as the process does.
+pub fn spawn_maintenance(
+    textpool: Arc<TextPool>,
+    flush_interval: Duration,
+    refresh_interval: Option<Duration>,
+) {
+    let textpool_flush = textpool.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(flush_interval);
+        match textpool_flush.flush(false) {
+            Err(e) => error!("Flush failed! {:?}", e),
+            Ok(v) => debug!("Flushed {} text(s)", v.len()),
+        }
+    });
+
+    if let Some(refresh_interval) = refresh_interval {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(refresh_interval);
+            if let Err(e) = textpool.refresh() {
+                error!("Refresh failed! {:?}", e);
+            }
+        });
+    }
+}
+
+/// Builds the router with all routes of the service, backed by the given text pool.
+/// The router can be used with any state type, so it can be nested in or merged into the router of another application.
+/// It does not include the swagger UI or any tracing layer, add these as needed (the specification is available via [`ApiDoc`]).
+pub fn router<S>(textpool: Arc<TextPool>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/", get(list_texts))
+        .route("/", delete(delete_all))
+        .route("/stat/{*text_id}", get(stat_text))
+        .route("/convert/{*text_id}", get(convert))
+        .route("/search/", get(search_all))
+        .route("/search/{*text_id}", get(search))
+        .route("/concordance/", get(concordance_all))
+        .route("/concordance/{*text_id}", get(concordance))
+        .route("/selector", post(resolve_selector))
+        .route("/selector/{*text_id}", post(resolve_selector_text))
+        .route("/anchor", post(anchor_selector))
+        .route("/anchor/{*text_id}", post(anchor_selector_text))
+        .route("/api2/batch", post(get_batch_api2))
+        .route("/api2/search/{text_id}", get(search_api2))
+        .route("/api2/{text_id}", get(get_api2_short))
+        .route("/api2/{text_id}/{region}", get(get_api2_with_region)) //also used for info.json for stat
+        .route("/api2/{text_id}", post(create_text_api2))
+        .route("/api2/{text_id}", put(create_text_overwrite_api2))
+        .route("/api2/{text_id}", delete(delete_text_api2))
+        .route("/{*text_id}", get(get_text))
+        .route("/{*text_id}", post(create_text))
+        .route("/{*text_id}", put(create_text_overwrite))
+        .route("/{*text_id}", delete(delete_text))
+        .route("/flush", post(flush))
+        .route("/batch", post(get_batch))
+        .layer(middleware::from_fn(refuse_git_cache))
+        .with_state(textpool)
+}
+
+/// Middleware that refuses all requests that address the cache of texts retrieved from git directly, these texts are only served via a `ref` parameter (or an `@{ref}` suffix in API 2)
+async fn refuse_git_cache(request: Request<Body>, next: Next) -> Result<Response, ApiError> {
+    check_git_cache(&percent_decode_str(request.uri().path()).decode_utf8_lossy())?;
+    Ok(next.run(request).await)
+}
+
+/// Refuses identifiers (or paths) in the cache of texts retrieved from git, see [`refuse_git_cache()`]
+fn check_git_cache(text_id: &str) -> Result<(), ApiError> {
+    if text_id
+        .split(['/', '|'])
+        .any(|component| component == GIT_CACHE_DIR)
+    {
+        Err(ApiError::NotFound(
+            "No such text exists (no hidden paths allowed)",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/",
+    responses(
+        (status = 200, body = [String], description = "Returns a simple list of all available texts (recursively)"),
+    )
+)]
+/// Returns all available texts, recursively
+async fn list_texts(
+    textpool: State<Arc<TextPool>>,
+    request: Request<Body>,
+) -> Result<ApiResponse, ApiError> {
+    let store_ids: Vec<String> = textpool.list("")?;
+    match negotiate_content_type(request.headers(), &[CONTENT_TYPE_JSON]) {
+        Ok(CONTENT_TYPE_JSON) => {
+            let store_ids: Vec<serde_json::Value> =
+                store_ids.into_iter().map(|s| s.into()).collect();
+            Ok(ApiResponse::JsonList(store_ids))
+        }
+        _ => Err(ApiError::NotAcceptable(
+            "Accept header could not be satisfied (try application/json)",
+        )),
+    }
+}
+
+fn list_texts_subdir(
+    path: String,
+    textpool: State<Arc<TextPool>>,
+    request: Request<Body>,
+) -> Result<ApiResponse, ApiError> {
+    for component in path.split('/') {
+        if component.starts_with('.') {
+            return Err(ApiError::NotFound("Invalid path"));
+        }
+    }
+
+    let store_ids: Vec<String> = textpool.list(&path)?;
+    match negotiate_content_type(request.headers(), &[CONTENT_TYPE_JSON]) {
+        Ok(CONTENT_TYPE_JSON) => {
+            let store_ids: Vec<serde_json::Value> =
+                store_ids.into_iter().map(|s| s.into()).collect();
+            Ok(ApiResponse::JsonList(store_ids))
+        }
+        _ => Err(ApiError::NotAcceptable(
+            "Accept header could not be satisfied (try application/json)",
+        )),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/",
+    responses(
+        (status = 204, description = "Returned when successfully deleted"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or there is no authorization provided or it is rejected", content_type = "application/json")
+    )
+)]
+/// Deletes all texts, recursively
+async fn delete_all(
+    headers: HeaderMap,
+    textpool: State<Arc<TextPool>>,
+) -> Result<ApiResponse, ApiError> {
+    let author = verify_auth(&textpool, headers)?;
+    blocking(move || delete_subdir("", &textpool, author.as_deref())).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/{*text_id}",
+    request_body( content_type = "text/plain", content = String),
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. It may contain zero or more path components."),
+    ),
+    responses(
+        (status = 201, description = "Returned when successfully created"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or the text already exists", content_type = "application/json")
+    )
+)]
+/// Create (upload) a new text, the text is transferred in the request body and must be valid UTF-8. If the text exists already, 403 will be returned
+async fn create_text(
+    Path(text_id): Path<String>,
+    headers: HeaderMap,
+    textpool: State<Arc<TextPool>>,
+    text: String,
+) -> Result<ApiResponse, ApiError> {
+    let author = verify_auth(&textpool, headers)?;
+    check_reserved(&text_id)?;
+    if blocking(move || textpool.new_text_as(&text_id, text, false, author.as_deref())).await? {
+        Ok(ApiResponse::Created())
+    } else {
+        unreachable!("new_text without overwrite always returns true (or error)")
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/{*text_id}",
+    request_body( content_type = "text/plain", content = String),
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. It may contain zero or more path components."),
+    ),
+    responses(
+        (status = 200, description = "Returned when successfully updated"),
+        (status = 201, description = "Returned when successfully newly created"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or the text already exists", content_type = "application/json")
+    )
+)]
+/// Create (upload) a new text, the text is transferred in the request body and must be valid UTF-8. If the text exists already, it will be overwritten.
+async fn create_text_overwrite(
+    Path(text_id): Path<String>,
+    headers: HeaderMap,
+    textpool: State<Arc<TextPool>>,
+    text: String,
+) -> Result<ApiResponse, ApiError> {
+    let author = verify_auth(&textpool, headers)?;
+    check_reserved(&text_id)?;
+    if blocking(move || textpool.new_text_as(&text_id, text, true, author.as_deref())).await? {
+        Ok(ApiResponse::Created())
+    } else {
+        Ok(ApiResponse::Ok())
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api2/{text_id}",
+    request_body( content_type = "text/plain", content = String),
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. It may contain zero or more path components."),
+    ),
+    responses(
+        (status = 201, description = "Returned when successfully created"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only.", content_type = "application/json")
+    )
+)]
+/// Create (upload) a new text, the text is transferred in the request body and must be valid UTF-8. If the text exists already, 403 will be returned
+async fn create_text_api2(
+    Path(text_id): Path<String>,
+    headers: HeaderMap,
+    textpool: State<Arc<TextPool>>,
+    text: String,
+) -> Result<ApiResponse, ApiError> {
+    let author = verify_auth(&textpool, headers)?;
+    let text_id = api2_decode_id(&text_id).into_owned();
+    check_reserved(&text_id)?;
+    blocking(move || textpool.new_text_as(&text_id, text, false, author.as_deref())).await?;
+    Ok(ApiResponse::Created())
+}
+
+#[utoipa::path(
+    put,
+    path = "/api2/{text_id}",
+    request_body( content_type = "text/plain", content = String),
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. It may contain zero or more path components."),
+    ),
+    responses(
+        (status = 200, description = "Returned when successfully updated"),
+        (status = 201, description = "Returned when successfully newly created"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or there is no authorization provided or it is rejected", content_type = "application/json")
+    )
+)]
+/// Create (upload) a new text, the text is transferred in the request body and must be valid UTF-8. If the text exists already, it will be overwritten.
+async fn create_text_overwrite_api2(
+    Path(text_id): Path<String>,
+    headers: HeaderMap,
+    textpool: State<Arc<TextPool>>,
+    text: String,
+) -> Result<ApiResponse, ApiError> {
+    let author = verify_auth(&textpool, headers)?;
+    let text_id = api2_decode_id(&text_id).into_owned();
+    check_reserved(&text_id)?;
+    if blocking(move || textpool.new_text_as(&text_id, text, true, author.as_deref())).await? {
+        Ok(ApiResponse::Created())
+    } else {
+        Ok(ApiResponse::Ok())
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/{*text_id}",
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. It may contain zero or more path components. Important note: If this ends with a trailing slash, the identifier is interpreted as a path and all texts in that path are removed (recursively)!"),
+    ),
+    responses(
+        (status = 204, description = "Returned when successfully deleted"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the text does not exist", content_type = "application/json"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or there is no authorization provided or it is rejected", content_type = "application/json")
+    )
+)]
+/// Permanently delete a text
+async fn delete_text(
+    Path(text_id): Path<String>,
+    headers: HeaderMap,
+    textpool: State<Arc<TextPool>>,
+) -> Result<ApiResponse, ApiError> {
+    let author = verify_auth(&textpool, headers)?;
+    blocking(move || {
+        if text_id.ends_with('/') {
+            //deletion of an entire subdir rather than a single text
+            delete_subdir(text_id.as_str(), &textpool, author.as_deref())
+        } else {
+            textpool.delete_text_as(&text_id, author.as_deref())?;
+            Ok(ApiResponse::NoContent())
+        }
+    })
+    .await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api2/{text_id}",
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. It may contain zero or more path components."),
+    ),
+    responses(
+        (status = 204, description = "Returned when successfully deleted"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the text does not exist", content_type = "application/json"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or there is no authorization provided or it is rejected", content_type = "application/json")
+    )
+)]
+/// Permanently delete a text
+async fn delete_text_api2(
+    Path(text_id): Path<String>,
+    headers: HeaderMap,
+    textpool: State<Arc<TextPool>>,
+) -> Result<ApiResponse, ApiError> {
+    let author = verify_auth(&textpool, headers)?;
+    blocking(move || textpool.delete_text_as(&api2_decode_id(&text_id), author.as_deref())).await?;
+    Ok(ApiResponse::NoContent())
+}
+
+/// Verify API key if set, returns the identity of the holder of the key (if known)
+fn verify_auth(textpool: &TextPool, headers: HeaderMap) -> Result<Option<String>, ApiError> {
+    let token = headers.get("Authorization").map(|authorization| {
+        authorization
+            .to_str()
+            .ok()
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .unwrap_or_default()
+    });
+    Ok(textpool.authorize(token)?.map(|author| author.to_string()))
+}
+
+/// Refuses identifiers that would be shadowed by one of the endpoints of the service (see [`RESERVED_IDS`])
+fn check_reserved(text_id: &str) -> Result<(), ApiError> {
+    let first = text_id.split('/').next().unwrap_or(text_id);
+    if RESERVED_IDS.contains(&first) {
+        Err(ApiError::PermissionDenied(
+            "Identifier is reserved for an endpoint of the service",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn get_text_chars(
+    textpool: Arc<TextPool>,
+    text_id: &str,
+    range: Range,
+    force_no_stream: bool,
+) -> Result<ApiResponse, ApiError> {
+    // get absolute start and end character positions
+    let (begin, end) = textpool.absolute_range(text_id, &range)?;
+
+    if begin == end {
+        //note that we can't pass an absolute (0,0) range on as that would mean the full text
+        return Ok(ApiResponse::Text(String::new()));
+    }
+
+    // asked range is smaller than threshold, just send as non-streamed response
+    if force_no_stream || (end - begin) < STREAM_THRESHOLD {
+        return match range {
+            Range::Lines(begin, end) => textpool.map_lines(text_id, begin, end, |text| {
+                Ok(ApiResponse::Text(text.to_string()))
+            }),
+            _ => textpool.map(text_id, begin as isize, end as isize, |text| {
+                Ok(ApiResponse::Text(text.to_string()))
+            }),
+        };
+    }
+
+    // this check doesn't happen in TextFrame::absolute_pos
+    if end < begin {
+        return Err(ApiError::ParameterError(
+            "The range you requested has a negative length",
+        ));
+    }
+
+    let char_count = (end - begin) as u64;
+
+    let nb_chunks = char_count.div_ceil(CHUNK_SIZE as u64);
+
+    let textpool = Arc::clone(&textpool);
+    let text_id = text_id.to_string();
+
+    let textstream = futures::stream::iter(0..nb_chunks).then(move |chunk| {
+        let textpool = Arc::clone(&textpool);
+        let text_id = text_id.clone();
+        async move {
+            // put textpool operation in a tokio blocking task
+            // in order to not block this tokio executor on slow IO operations
+            let chunk_data = tokio::task::block_in_place(|| {
+                let begin_chunk = (begin as u64 + chunk * CHUNK_SIZE as u64) as isize;
+                let end_chunk =
+                    (begin as u64 + ((chunk + 1) * CHUNK_SIZE as u64)).min(end as u64) as isize;
+
+                textpool
+                    .map(
+                        &text_id,
+                        begin_chunk,
+                        end_chunk,
+                        |text| Ok(text.to_string()),
+                    )
+                    .unwrap()
+            });
+            Ok::<_, Infallible>(chunk_data)
+        }
+    });
+
+    let body = Body::from_stream(textstream);
+
+    Ok(ApiResponse::TextStream(body))
+}
+
+fn parse_range(input: &str) -> Result<(isize, isize), ApiError> {
+    let mut fields = input.splitn(2, ',');
+
+    let begin = fields.next().unwrap_or("");
+    let end = fields.next().unwrap_or("");
+
+    let parse = |field: &str| {
+        if field.is_empty() {
+            Ok(0)
+        } else {
+            field
+                .parse()
+                .map_err(|_| ApiError::ParameterError("range parameter must be an integer"))
+        }
+    };
+
+    let begin = parse(begin)?;
+    let end = parse(end)?;
+
+    Ok((begin, end))
+}
+
+#[derive(Deserialize)]
+struct TextParams {
+    begin: Option<isize>,
+    end: Option<isize>,
+    char: Option<String>,
+    line: Option<String>,
+    utf16: Option<String>,
+    grapheme: Option<String>,
+    token: Option<String>,
+    para: Option<String>,
+    page: Option<String>,
+    context: Option<usize>,
+    context_lines: Option<usize>,
+    length: Option<usize>,
+    md5: Option<String>,
+    version: Option<usize>,
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/{*text_id}",
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk. It may contain zero or more path components."),
+        ("begin" = Option<isize>, Query, description = "An integer indicating the begin offset in unicode points (0-indexed). This may be a negative integer for end-aligned cursors. The default value is 0."),
+        ("end" = Option<isize>, Query, description = "An integer indicating the non-inclusive end offset in unicode points (0-indexed). This may be a negative integer for end-aligned cursors and `0` for actual end. The default value is 0."),
+        ("char" = Option<isize>, Query, description = "Character range specification conforming to RFC5147, begin and end values are separated by a comma, 0-indexed, end is non-inclusive"),
+        ("line" = Option<isize>, Query, description = "Line range specification conforming to RFC5147, begin and end values are separated by a comma, 0-indexed (first line is 0!), end is non-inclusive"),
+        ("utf16" = Option<isize>, Query, description = "Range specification in UTF-16 code units (as used by JavaScript), with the same syntax as `char`. Offsets must not fall within a surrogate pair."),
+        ("grapheme" = Option<isize>, Query, description = "Range specification in extended grapheme clusters (user-perceived characters, so combining characters and emoji sequences are never split), with the same syntax as `char`."),
+        ("token" = Option<isize>, Query, description = "Range specification in tokens (words and punctuation as determined by unicode word segmentation), with the same syntax as `char`. The returned text spans from the beginning of the first token to the end of the last token."),
+        ("para" = Option<isize>, Query, description = "Range specification in paragraphs (separated by blank lines or form feeds), with the same syntax as `char`. The returned text spans from the beginning of the first paragraph to the end of the last paragraph."),
+        ("page" = Option<isize>, Query, description = "Range specification in pages (separated by form feeds), with the same syntax as `char`. The form feeds themselves are not part of any page."),
+        ("context" = Option<usize>, Query, description = "Number of characters of context to return before and after the selection. If set, a JSON envelope is returned rather than plain text."),
+        ("context_lines" = Option<usize>, Query, description = "Number of lines of context to return before and after the selection, the lines holding the begin and end of the selection are always completed. If set, a JSON envelope is returned rather than plain text. Can not be combined with `context`."),
+        ("length" = Option<usize>, Query, description = "Optional length validity check (as in RFC5147, an encoding parameter is NOT supported though as textsurf only does UTF-8 anyway). This is not an alternative for `end`. If the check fails, a 403 will be returned."),
+        ("md5" = Option<String>, Query, description = "MD5 checksum for the text that is being referenced (as defined by RFC5147). If the check fails, a 403 will be returned"),
+        ("version" = Option<usize>, Query, description = "The version of the text to return (numbered from 1). Prior versions are kept whenever a text is overwritten, the current version and the available revisions are listed by `/stat/{text_id}`. Defaults to the current version."),
+        ("ref" = Option<String>, Query, description = "Return the text as it was at this git ref (a branch, tag, commit or any other git revision expression). Only available if the server uses git-backed storage. Can not be combined with `version`."),
+    ),
+    responses(
+        (status = 200, description = "The text, or a JSON envelope if requested via the Accept header (`TextEnvelope`) or if context was requested (`ContextEnvelope`)",content(
+            (String = "text/plain"),
+            (apidocs::TextEnvelope = "application/json"),
+            (apidocs::ContextEnvelope = "application/json"),
+        )),
+        (status = 403, body = apidocs::ApiError, description = "Return when an explicitly passed check (length,md5) fails", content_type = "application/json"),
+        (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
+    )
+)]
+/// Returns a text given a text identifier. Returns either a full text or a portion thereof if offsets were specified.
+/// If a path is specified (trailing slash), this returns an index of all files under that path instead (as JSON)
+async fn get_text(
+    Path(text_id): Path<String>,
+    Query(params): Query<TextParams>,
+    State(textpool): State<Arc<TextPool>>,
+    request: Request<Body>,
+) -> Result<ApiResponse, ApiError> {
+    if text_id.ends_with('/') {
+        //request for index rather than a text
+        return list_texts_subdir(text_id, State(textpool), request);
+    }
+
+    let text = {
+        let textpool = textpool.clone();
+        let (version, reference) = (params.version, params.reference.clone());
+        //retrieving the text from git is blocking I/O
+        blocking(move || TextSelection::resolve(&textpool, text_id, version, reference)).await?
+    };
+
+    let force_no_stream = params.length.is_some() || params.md5.is_some();
+
+    let units = [
+        ("char", &params.char),
+        ("line", &params.line),
+        ("utf16", &params.utf16),
+        ("grapheme", &params.grapheme),
+        ("token", &params.token),
+        ("para", &params.para),
+        ("page", &params.page),
+    ];
+    let range = if let Some((unit, value)) = first_unit(&units) {
+        let (begin, end) = parse_range(value)?;
+        Range::from_unit(unit, begin, end).expect("unit must be valid")
+    } else {
+        // both begin and end default to 0 when they're not given
+        let begin = params.begin.unwrap_or(0);
+        let end = params.end.unwrap_or(0);
+        Range::Chars(begin, end)
+    };
+
+    if let Some((size, unit)) = context_param(params.context, params.context_lines)? {
+        let (envelope, selection) = get_text_with_context(&textpool, &text, range, size, unit)?;
+        verify_checks(&selection, params.length, params.md5.as_deref())?;
+        return Ok(ApiResponse::Json(envelope));
+    }
+
+    if wants_json(request.headers()) {
+        if !force_no_stream {
+            return get_text_envelope_stream(textpool, &text, range);
+        }
+        let (envelope, text) = get_text_envelope(&textpool, &text, range)?;
+        verify_checks(&text, params.length, params.md5.as_deref())?;
+        return Ok(ApiResponse::Json(envelope));
+    }
+
+    let response = get_text_chars(textpool, &text.loaded_id, range, force_no_stream);
+
+    if let Ok(ApiResponse::Text(text)) = &response {
+        verify_checks(text, params.length, params.md5.as_deref())?;
+    }
+    response
+}
+
+/// Determines whether the client prefers a JSON envelope over plain text (via the Accept header).
+/// If the Accept header can not be satisfied at all we stick to plain text, as we always did.
+fn wants_json(headers: &HeaderMap<HeaderValue>) -> bool {
+    matches!(
+        negotiate_content_type(headers, &[CONTENT_TYPE_TEXT, CONTENT_TYPE_JSON]),
+        Ok(CONTENT_TYPE_JSON)
+    )
+}
+
+/// Returns a text selection as a self-describing JSON envelope, holding the resolved absolute offsets, the number of lines and characters, the md5 checksum and the text itself.
+/// The text is returned as well.
+fn get_text_envelope(
+    textpool: &TextPool,
+    selection: &TextSelection,
+    range: Range,
+) -> Result<(serde_json::Value, String), ApiError> {
+    let text_id = selection.loaded_id.as_str();
+    let (begin, end) = textpool.absolute_range(text_id, &range)?;
+    if end < begin {
+        return Err(ApiError::ParameterError(
+            "The range you requested has a negative length",
+        ));
+    }
+    let text = textpool.excerpt(text_id, begin, end)?;
+    let lines = text.lines().count();
+    let mut envelope = serde_json::json!({
+        "begin": begin,
+        "end": end,
+        "lines": lines,
+        "chars": end - begin,
+        "md5": format!("{:x}", md5::compute(text.as_bytes())),
+        "text": text,
+    });
+    selection.describe(&mut envelope);
+    Ok((envelope, text))
+}
+
+/// Returns a text selection as a JSON envelope (as [`get_text_envelope()`]), large selections are streamed in chunks rather than held in memory.
+/// In a streamed envelope the `lines` and `md5` keys follow the text, as they are only known once all of it has been read.
+fn get_text_envelope_stream(
+    textpool: Arc<TextPool>,
+    selection: &TextSelection,
+    range: Range,
+) -> Result<ApiResponse, ApiError> {
+    let text_id = selection.loaded_id.as_str();
+    let (begin, end) = textpool.absolute_range(text_id, &range)?;
+    if end < begin {
+        return Err(ApiError::ParameterError(
+            "The range you requested has a negative length",
+        ));
+    }
+    if end - begin < STREAM_THRESHOLD {
+        let (envelope, _) = get_text_envelope(&textpool, selection, range)?;
+        return Ok(ApiResponse::Json(envelope));
+    }
+
+    let mut head = serde_json::json!({
+        "begin": begin,
+        "end": end,
+        "chars": end - begin,
+    });
+    selection.describe(&mut head);
+    let mut head = head.to_string();
+    head.pop(); //closing brace
+    head += ",\"text\":\"";
+
+    let text_id = text_id.to_string();
+    let (sender, receiver) = tokio::sync::mpsc::channel::<String>(4);
+    tokio::task::spawn_blocking(move || {
+        if sender.blocking_send(head).is_err() {
+            return;
+        }
+        let mut checksum = md5::Context::new();
+        let mut lines = 0;
+        let mut last = None;
+        for begin_chunk in (begin..end).step_by(CHUNK_SIZE) {
+            let end_chunk = (begin_chunk + CHUNK_SIZE).min(end);
+            let chunk = textpool.map(&text_id, begin_chunk as isize, end_chunk as isize, |text| {
+                checksum.consume(text.as_bytes());
+                lines += text.bytes().filter(|c| *c == b'\n').count();
+                last = text.chars().last();
+                let escaped = serde_json::to_string(text)
+                    .map_err(|_| ApiError::InternalError("serialisation failed"))?;
+                Ok(escaped[1..escaped.len() - 1].to_string())
+            });
+            match chunk {
+                Ok(chunk) => {
+                    if sender.blocking_send(chunk).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    //the response has already started, all we can do is end it prematurely
+                    error!("Streaming {} failed: {}", text_id, e);
+                    return;
+                }
+            }
+        }
+        if last.is_some_and(|c| c != '\n') {
+            //a final line without trailing newline
+            lines += 1;
+        }
+        let _ = sender.blocking_send(format!(
+            "\",\"lines\":{},\"md5\":\"{:x}\"}}",
+            lines,
+            checksum.finalize()
+        ));
+    });
+    Ok(ApiResponse::JsonStream(Body::from_stream(
+        futures::stream::unfold(receiver, |mut receiver| async move {
+            receiver
+                .recv()
+                .await
+                .map(|chunk| (Ok::<_, Infallible>(chunk), receiver))
+        }),
+    )))
+}
+
+/// Verifies the length and md5 checks (as in RFC5147) against a text
+fn verify_checks(text: &str, length: Option<usize>, md5: Option<&str>) -> Result<(), ApiError> {
+    if let Some(length) = length {
+        if text.chars().count() != length {
+            return Err(ApiError::PermissionDenied("length check failed"));
+        }
+    }
+    if let Some(md5ref) = md5 {
+        let checksum = format!("{:x}", md5::compute(text.as_bytes()));
+        if checksum != md5ref {
+            return Err(ApiError::PermissionDenied("md5 check failed"));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the size and unit of the context window requested via the `context` (characters) or `context_lines` parameters, if any
+fn context_param(
+    context: Option<usize>,
+    context_lines: Option<usize>,
+) -> Result<Option<(usize, ContextUnit)>, ApiError> {
+    match (context, context_lines) {
+        (Some(_), Some(_)) => Err(ApiError::ParameterError(
+            "context and context_lines can not be combined",
+        )),
+        (Some(size), None) => Ok(Some((size, ContextUnit::Chars))),
+        (None, Some(size)) => Ok(Some((size, ContextUnit::Lines))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Returns a text selection along with the context before and after it, as a JSON envelope holding the absolute offsets and text of each part.
+/// The selection itself is returned as well.
+fn get_text_with_context(
+    textpool: &TextPool,
+    text: &TextSelection,
+    range: Range,
+    size: usize,
+    unit: ContextUnit,
+) -> Result<(serde_json::Value, String), ApiError> {
+    let text_id = text.loaded_id.as_str();
+    let (begin, end) = textpool.absolute_range(text_id, &range)?;
+    if end < begin {
+        return Err(ApiError::ParameterError(
+            "The range you requested has a negative length",
+        ));
+    }
+    let (before, after) = textpool.context(text_id, begin, end, size, unit)?;
+    let selection = textpool.excerpt(text_id, begin, end)?;
+    let mut envelope = serde_json::json!({
+        "before": {
+            "begin": before,
+            "end": begin,
+            "text": textpool.excerpt(text_id, before, begin)?,
+        },
+        "selection": {
+            "begin": begin,
+            "end": end,
+            "text": selection,
+        },
+        "after": {
+            "begin": end,
+            "end": after,
+            "text": textpool.excerpt(text_id, end, after)?,
+        },
+    });
+    text.describe(&mut envelope);
+    Ok((envelope, selection))
+}
+
+#[derive(Deserialize)]
+struct BatchItem {
+    id: String,
+    char: Option<String>,
+    line: Option<String>,
+    utf16: Option<String>,
+    grapheme: Option<String>,
+    token: Option<String>,
+    para: Option<String>,
+    page: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/batch",
+    request_body( content_type = "application/json", content = [apidocs::BatchItem]),
+    responses(
+        (status = 200, body = [apidocs::BatchResult], description = "A JSON list with one result for each requested range, in the same order as the request. Results that could not be resolved carry an `error` key rather than `text`.", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'ParameterError` is returned if the request holds more than 1000 items", content_type = "application/json"),
+    )
+)]
+/// Returns multiple text excerpts, possibly from multiple texts, in a single request. Each item in the request body selects a range by `char` or `line` (RFC5147 syntax, as in the query parameters of `GET /{text_id}`). The results contain the resolved absolute character offsets.
+async fn get_batch(
+    State(textpool): State<Arc<TextPool>>,
+    Json(items): Json<Vec<BatchItem>>,
+) -> Result<ApiResponse, ApiError> {
+    blocking(move || batch_helper(&textpool, items, false)).await
+}
+
+fn batch_helper(
+    textpool: &TextPool,
+    items: Vec<BatchItem>,
+    api2: bool,
+) -> Result<ApiResponse, ApiError> {
+    if items.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::ParameterError(
+            "Too many items in batch request (the maximum is 1000)",
+        ));
+    }
+    let mut ranges: Vec<Option<Result<Range, ApiError>>> = Vec::with_capacity(items.len());
+    let mut ids: Vec<Cow<str>> = Vec::with_capacity(items.len());
+    for item in items.iter() {
+        let units = [
+            ("char", &item.char),
+            ("line", &item.line),
+            ("utf16", &item.utf16),
+            ("grapheme", &item.grapheme),
+            ("token", &item.token),
+            ("para", &item.para),
+            ("page", &item.page),
+        ];
+        ranges.push(Some(if let Some((unit, value)) = first_unit(&units) {
+            parse_range(value)
+                .map(|(begin, end)| Range::from_unit(unit, begin, end).expect("unit must be valid"))
+        } else {
+            Ok(Range::Chars(0, 0))
+        }));
+        let id = if api2 {
+            api2_decode_id(item.id.as_str())
+        } else {
+            Cow::Borrowed(item.id.as_str())
+        };
+        check_git_cache(&id)?;
+        ids.push(id);
+    }
+
+    //group all ranges for the same text so it is loaded only once, groups keep the order of first appearance
+    let mut groups: Vec<(&str, Vec<usize>)> = Vec::new();
+    let mut group_index: HashMap<&str, usize> = HashMap::new();
+    for (i, id) in ids.iter().enumerate() {
+        let g = *group_index.entry(id.as_ref()).or_insert_with(|| {
+            groups.push((id.as_ref(), Vec::new()));
+            groups.len() - 1
+        });
+        groups[g].1.push(i);
+    }
+
+    let mut results: Vec<Option<serde_json::Value>> = vec![None; items.len()];
+    for (id, group) in groups {
+        let loaded = textpool.with_text(id, |text| {
+            for j in group.iter() {
+                let result = ranges[*j]
+                    .take()
+                    .expect("range must exist")
+                    .and_then(|range| excerpt_batch(text, &range));
+                results[*j] = Some(match result {
+                    Ok((begin, end, text)) => serde_json::json!({
+                        "id": id,
+                        "begin": begin,
+                        "end": end,
+                        "text": text,
+                    }),
+                    Err(e) => serde_json::json!({ "id": id, "error": e }),
+                });
+            }
+            Ok(())
+        });
+        if let Err(e) = loaded {
+            let e = serde_json::to_value(e).expect("error must serialize");
+            for j in group {
+                results[j] = Some(serde_json::json!({ "id": id, "error": e }));
+            }
+        }
+    }
+    Ok(ApiResponse::JsonList(
+        results
+            .into_iter()
+            .map(|result| result.expect("all results must be resolved"))
+            .collect(),
+    ))
+}
+
+/// Resolves a single range for a batch request, returns absolute character offsets and the text
+fn excerpt_batch(text: &mut Text, range: &Range) -> Result<(usize, usize, String), ApiError> {
+    let (begin, end) = text.absolute_range(range)?;
+    if begin == end {
+        //an empty range, note that we can't pass (0,0) to get_or_load as that means the full text
+        return Ok((begin, end, String::new()));
+    }
+    let text = text.get_or_load(begin as isize, end as isize)?;
+    Ok((begin, end, text.to_string()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/flush",
+    responses(
+        (status = 204, description = "Returned when successfully flushed all files"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or there is no authorization provided or it is rejected", content_type = "application/json")
+    )
+)]
+/// Forcibly flushes all texts from memory; clearing the cache
+async fn flush(
+    headers: HeaderMap,
+    textpool: State<Arc<TextPool>>,
+) -> Result<ApiResponse, ApiError> {
+    verify_auth(&textpool, headers)?;
+    let v = textpool.flush(true)?;
+    info!("Force-flushed {} text(s) on request", v.len());
+    Ok(ApiResponse::Ok())
+}
+
+#[utoipa::path(
+    get,
+    path = "/stat/{*text_id}",
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk. It may contain zero or more path components."),
+    ),
+    responses(
+        (status = 200, description = "The text identifier",content(
+            (String = "text/plain"),
+        )),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
+    )
+)]
+/// Returns metadata about a text. Returns a JSON response with fields "bytes" (filesize), "chars" (length in unicode characters), "checksum" (SHA-256), "mtime" (unix timestamp for the file modification),
+/// "version" (the current version number, starting at 1) and "revisions" (a list of all prior versions, each with fields "version", "checksum" and "mtime")
+async fn stat_text(
+    Path(text_id): Path<String>,
+    textpool: State<Arc<TextPool>>,
+) -> Result<ApiResponse, ApiError> {
+    textpool.stat(&text_id)
+}
+
+#[derive(Deserialize)]
+struct ConvertParams {
+    char: Option<String>,
+    line: Option<String>,
+    byte: Option<String>,
+    utf16: Option<String>,
+    grapheme: Option<String>,
+    token: Option<String>,
+    para: Option<String>,
+    page: Option<String>,
+    units: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/convert/{*text_id}",
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk. It may contain zero or more path components."),
+        ("char" = Option<String>, Query, description = "A character position (a single integer) or a character range (begin and end separated by a comma, as in RFC5147), 0-indexed, end is non-inclusive. Negative values are interpreted relative to the end of the text."),
+        ("line" = Option<String>, Query, description = "A line position (a single integer, denoting the start of the line) or a line range (begin and end separated by a comma, as in RFC5147), 0-indexed, end is non-inclusive."),
+        ("byte" = Option<String>, Query, description = "A UTF-8 byte position (a single integer) or a byte range (begin and end separated by a comma). The offsets must be at character boundaries."),
+        ("utf16" = Option<String>, Query, description = "A UTF-16 code unit position (a single integer) or a range (begin and end separated by a comma), 0-indexed, end is non-inclusive. Negative values are interpreted relative to the end of the text. The offsets must not fall within a surrogate pair."),
+        ("grapheme" = Option<String>, Query, description = "A grapheme cluster position (a single integer) or a range (begin and end separated by a comma), 0-indexed, end is non-inclusive. Negative values are interpreted relative to the end of the text."),
+        ("token" = Option<String>, Query, description = "A token position (a single integer, denoting the beginning of the token) or a token range (begin and end separated by a comma), 0-indexed, end is non-inclusive. Negative values are interpreted relative to the end of the text."),
+        ("para" = Option<String>, Query, description = "A paragraph position (a single integer, denoting the beginning of the paragraph) or a paragraph range (begin and end separated by a comma), 0-indexed, end is non-inclusive. Negative values are interpreted relative to the end of the text."),
+        ("page" = Option<String>, Query, description = "A page position (a single integer, denoting the beginning of the page) or a page range (begin and end separated by a comma), 0-indexed, end is non-inclusive. Negative values are interpreted relative to the end of the text."),
+        ("units" = Option<String>, Query, description = "A comma-separated list of the coordinate systems to return (`utf16`, `grapheme`, `token`, `para`, `page` and `line`, the latter includes the column), `char` and `byte` are always returned. Defaults to all of them, restricting this avoids building indices that are not needed."),
+    ),
+    responses(
+        (status = 200, body = apidocs::Coordinates, description = "The position expressed in all coordinate systems. For a range, a JSON object with keys `begin` and `end` is returned instead, each holding such a position.", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
+    )
+)]
+/// Converts a position or range between coordinate systems. Returns the unicode character offset (`char`), UTF-8 byte offset (`byte`), UTF-16 code unit offset (`utf16`), grapheme cluster offset (`grapheme`, only if the position is at a cluster boundary), the index of the token, paragraph and page holding the position (`token`, `para`, `page`, if any), line number (`line`) and column (`column`, in unicode characters relative to the start of the line).
+async fn convert(
+    Path(text_id): Path<String>,
+    Query(params): Query<ConvertParams>,
+    State(textpool): State<Arc<TextPool>>,
+) -> Result<ApiResponse, ApiError> {
+    let units = [
+        ("char", &params.char),
+        ("line", &params.line),
+        ("utf16", &params.utf16),
+        ("grapheme", &params.grapheme),
+        ("token", &params.token),
+        ("para", &params.para),
+        ("page", &params.page),
+    ];
+    let (begin, end) = if let Some((unit, value)) = first_unit(&units) {
+        if value.contains(',') {
+            let (begin, end) = parse_range(value)?;
+            let range = Range::from_unit(unit, begin, end).expect("unit must be valid");
+            let (begin, end) = textpool.absolute_range(&text_id, &range)?;
+            (begin, Some(end))
+        } else {
+            let range =
+                Range::from_unit(unit, parse_position(value)?, 0).expect("unit must be valid");
+            let (begin, _) = textpool.absolute_range(&text_id, &range)?;
+            (begin, None)
+        }
+    } else if let Some(byte) = params.byte.as_deref() {
+        let mut fields = byte.splitn(2, ',');
+        let begin = parse_position(fields.next().unwrap_or(""))?;
+        let end = fields.next().map(parse_position).transpose()?;
+        if begin < 0 || end.is_some_and(|end| end < begin) {
+            return Err(ApiError::ParameterError(
+                "byte offsets must be absolute and begin may not exceed end",
+            ));
+        }
+        (
+            textpool.byte_to_char_pos(&text_id, begin as usize)?,
+            end.map(|end| textpool.byte_to_char_pos(&text_id, end as usize))
+                .transpose()?,
+        )
+    } else {
+        return Err(ApiError::ParameterError(
+            "expected one of the parameters char, line, byte, utf16, grapheme, token, para or page",
+        ));
+    };
+
+    let units = match params.units.as_deref() {
+        Some(units) => CoordinateUnits::parse(units)?,
+        None => CoordinateUnits::all(),
+    };
+    let begin = serde_json::to_value(textpool.coordinates(&text_id, begin, units)?)
+        .map_err(|_| ApiError::InternalError("serialisation failed"))?;
+    if let Some(end) = end {
+        let end = serde_json::to_value(textpool.coordinates(&text_id, end, units)?)
+            .map_err(|_| ApiError::InternalError("serialisation failed"))?;
+        Ok(ApiResponse::Json(
+            serde_json::json!({ "begin": begin, "end": end }),
+        ))
+    } else {
+        Ok(ApiResponse::Json(begin))
+    }
+}
+
+/// Returns the first unit for which a parameter was passed, along with its value
+fn first_unit<'a>(units: &[(&'static str, &'a Option<String>)]) -> Option<(&'static str, &'a str)> {
+    units
+        .iter()
+        .find_map(|(unit, value)| value.as_deref().map(|value| (*unit, value)))
+}
+
+fn parse_position(input: &str) -> Result<isize, ApiError> {
+    input
+        .parse()
+        .map_err(|_| ApiError::ParameterError("position must be an integer"))
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    q: Option<String>,
+    regex: Option<String>,
+    case_insensitive: Option<bool>,
+    tokens: Option<bool>,
+    max: Option<usize>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/search/{*text_id}",
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk. It may contain zero or more path components. If it ends in a slash, it is interpreted as a path and all texts under it are searched."),
+        ("q" = Option<String>, Query, description = "The text to search for (matched literally)"),
+        ("regex" = Option<String>, Query, description = "A regular expression to search for, as an alternative to `q`. The expression is matched against each line separately (excluding the newline), so matches can not span multiple lines."),
+        ("case_insensitive" = Option<bool>, Query, description = "Match case-insensitively. Defaults to false."),
+        ("tokens" = Option<bool>, Query, description = "Only return matches that consist of whole tokens (as in `token` ranges): they must begin at the beginning of a token and end at the end of one. A literal search (`q`) under a path is then answered by the inverted index, if the server maintains one. Defaults to false."),
+        ("max" = Option<usize>, Query, description = "The maximum number of matches to return. Can not exceed the limit configured on the server."),
+    ),
+    responses(
+        (status = 200, body = [apidocs::SearchMatch], description = "A JSON list of all (non-overlapping) matches, in order of occurrence. This is returned for a literal search (`q`) in a single text.", content_type = "application/json"),
+        (status = 200, body = apidocs::SearchMatch, description = "Newline-delimited JSON, one match per line, with an extra `id` key holding the text identifier. This is streamed for a regular expression search, or for a search under a path. Texts that could not be searched yield a line with keys `id` and `error`. If the server-side time or match limit is reached, the last line is an object with `truncated` set to true and a `reason`.", content_type = "application/x-ndjson"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
+    )
+)]
+/// Searches a text for a literal string or regular expression and returns the matches with their unicode character offsets (which can be used directly in a `char` range) and the line they begin on.
+/// If a path is specified (trailing slash), all texts under it are searched.
+async fn search(
+    Path(text_id): Path<String>,
+    Query(params): Query<SearchParams>,
+    State(textpool): State<Arc<TextPool>>,
+) -> Result<ApiResponse, ApiError> {
+    search_helper(textpool, &text_id, params).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/search/",
+    params(
+        ("q" = Option<String>, Query, description = "The text to search for (matched literally)"),
+        ("regex" = Option<String>, Query, description = "A regular expression to search for, as an alternative to `q`. The expression is matched against each line separately (excluding the newline), so matches can not span multiple lines."),
+        ("case_insensitive" = Option<bool>, Query, description = "Match case-insensitively. Defaults to false."),
+        ("tokens" = Option<bool>, Query, description = "Only return matches that consist of whole tokens (as in `token` ranges): they must begin at the beginning of a token and end at the end of one. A literal search (`q`) under a path is then answered by the inverted index, if the server maintains one. Defaults to false."),
+        ("max" = Option<usize>, Query, description = "The maximum number of matches to return. Can not exceed the limit configured on the server."),
+    ),
+    responses(
+        (status = 200, body = apidocs::SearchMatch, description = "Newline-delimited JSON, one match per line, as for `GET /search/{text_id}`.", content_type = "application/x-ndjson"),
+    )
+)]
+/// Searches all texts for a literal string or regular expression
+async fn search_all(
+    Query(params): Query<SearchParams>,
+    State(textpool): State<Arc<TextPool>>,
+) -> Result<ApiResponse, ApiError> {
+    search_helper(textpool, "", params).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api2/search/{text_id}",
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk."),
+        ("q" = Option<String>, Query, description = "The text to search for (matched literally)"),
+        ("regex" = Option<String>, Query, description = "A regular expression to search for, as an alternative to `q`. The expression is matched against each line separately (excluding the newline), so matches can not span multiple lines."),
+        ("case_insensitive" = Option<bool>, Query, description = "Match case-insensitively. Defaults to false."),
+        ("tokens" = Option<bool>, Query, description = "Only return matches that consist of whole tokens (as in `token` ranges): they must begin at the beginning of a token and end at the end of one. A literal search (`q`) under a path is then answered by the inverted index, if the server maintains one. Defaults to false."),
+        ("max" = Option<usize>, Query, description = "The maximum number of matches to return. Can not exceed the limit configured on the server."),
+    ),
+    responses(
+        (status = 200, body = [apidocs::SearchMatch], description = "A JSON list of all (non-overlapping) matches, in order of occurrence. This is returned for a literal search (`q`) in a single text.", content_type = "application/json"),
+        (status = 200, body = apidocs::SearchMatch, description = "Newline-delimited JSON, one match per line, as for `GET /search/{text_id}`.", content_type = "application/x-ndjson"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
+    )
+)]
+/// Searches a text for a literal string or regular expression (Text Referencing API 2). Identical to `GET /search/{text_id}`, except that identifiers may use pipes as a substitute for slashes.
+async fn search_api2(
+    Path(text_id): Path<String>,
+    Query(params): Query<SearchParams>,
+    State(textpool): State<Arc<TextPool>>,
+) -> Result<ApiResponse, ApiError> {
+    let text_id = api2_decode_id(text_id.as_str()).into_owned();
+    search_helper(textpool, &text_id, params).await
+}
+
+async fn search_helper(
+    textpool: Arc<TextPool>,
+    text_id: &str,
+    params: SearchParams,
+) -> Result<ApiResponse, ApiError> {
+    let limits = textpool.search_limits();
+    let max = params.max.unwrap_or(limits.max_hits).min(limits.max_hits);
+    let case_insensitive = params.case_insensitive.unwrap_or(false);
+    let tokens = params.tokens.unwrap_or(false);
+    let subtree = text_id.is_empty() || text_id.ends_with('/');
+    if params.regex.is_none() && !subtree {
+        let Some(q) = params.q.as_deref() else {
+            return Err(ApiError::ParameterError("expected parameter q or regex"));
+        };
+        let pattern = Pattern::new(q, case_insensitive)?;
+        let text_id = text_id.to_string();
+        let matches = blocking(move || {
+            let deadline = std::time::Instant::now() + limits.time;
+            let (matches, complete) =
+                textpool.search(&text_id, &pattern, Some(max), deadline, tokens)?;
+            if !complete {
+                info!("Search in {} stopped at the time limit", text_id);
+            }
+            Ok(matches)
+        })
+        .await?;
+        return Ok(ApiResponse::JsonList(
+            matches
+                .into_iter()
+                .map(|m| {
+                    serde_json::to_value(m)
+                        .map_err(|_| ApiError::InternalError("serialisation failed"))
+                })
+                .collect::<Result<_, _>>()?,
+        ));
+    }
+
+    if let (true, Some(_), None, Some(q)) = (
+        tokens,
+        textpool.inverted_index(),
+        params.regex.as_deref(),
+        params.q.as_deref(),
+    ) {
+        //a literal search for whole tokens under a path can be answered by the inverted index
+        let pattern = Pattern::new(q, case_insensitive)?;
+        return Ok(ApiResponse::NdJsonStream(Body::from_stream(
+            index_search_stream(
+                textpool,
+                text_id.to_string(),
+                q.to_string(),
+                pattern,
+                max,
+                limits.time,
+            ),
+        )));
+    }
+
+    let regex = search_regex(&params)?;
+    let text_ids = search_text_ids(&textpool, text_id)?;
+
+    Ok(ApiResponse::NdJsonStream(Body::from_stream(
+        regex_search_stream(textpool, text_ids, regex, tokens, max, limits.time),
+    )))
+}
+
+/// Runs a blocking operation (like scanning a text) on a thread where blocking is acceptable, rather than on the async executor
+async fn blocking<T, F>(f: F) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, ApiError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|_| ApiError::InternalError("Blocking task failed"))?
+}
+
+#[derive(Deserialize)]
+struct ConcordanceParams {
+    q: Option<String>,
+    regex: Option<String>,
+    case_insensitive: Option<bool>,
+    tokens: Option<bool>,
+    max: Option<usize>,
+    context: Option<usize>,
+    unit: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/concordance/{*text_id}",
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk. It may contain zero or more path components. If it ends in a slash, it is interpreted as a path and all texts under it are searched."),
+        ("q" = Option<String>, Query, description = "The text to search for (matched literally)"),
+        ("regex" = Option<String>, Query, description = "A regular expression to search for, as an alternative to `q`. The expression is matched against each line separately (excluding the newline), so matches can not span multiple lines."),
+        ("case_insensitive" = Option<bool>, Query, description = "Match case-insensitively. Defaults to false."),
+        ("tokens" = Option<bool>, Query, description = "Only return matches that consist of whole tokens (as in `token` ranges): they must begin at the beginning of a token and end at the end of one. A literal search (`q`) under a path is then answered by the inverted index, if the server maintains one. Defaults to false."),
+        ("max" = Option<usize>, Query, description = "The maximum number of matches to return. Can not exceed the limit configured on the server."),
+        ("context" = Option<usize>, Query, description = "The amount of context to the left and to the right of each match, in the unit specified by `unit`. Defaults to 40 characters, 5 words or 0 lines."),
+        ("unit" = Option<String>, Query, description = "The unit for `context`, either `char` (default), `word` (tokens as in `token` ranges, `token` is accepted as an alias) or `line` (the lines holding the match are always completed, so the default of 0 yields the full lines)."),
+    ),
+    responses(
+        (status = 200, body = [apidocs::ConcordanceLine], description = "A concordance with one entry per match, in order of occurrence. The tabular formats contain a header row and have line breaks, tabs and other control characters in the text replaced by spaces.", content(
+            ([apidocs::ConcordanceLine] = "application/json"),
+            (String = "text/tab-separated-values"),
+            (String = "text/csv"),
+        )),
+        (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
+    )
+)]
+/// Returns a keyword-in-context (KWIC) concordance: every match of a query along with its left and right context.
+/// The output format is negotiated via the Accept header and may be JSON, TSV or CSV.
+async fn concordance(
+    Path(text_id): Path<String>,
+    Query(params): Query<ConcordanceParams>,
+    State(textpool): State<Arc<TextPool>>,
+    request: Request<Body>,
+) -> Result<ApiResponse, ApiError> {
+    concordance_helper(textpool, &text_id, params, request.headers()).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/concordance/",
+    params(
+        ("q" = Option<String>, Query, description = "The text to search for (matched literally)"),
+        ("regex" = Option<String>, Query, description = "A regular expression to search for, as an alternative to `q`."),
+        ("case_insensitive" = Option<bool>, Query, description = "Match case-insensitively. Defaults to false."),
+        ("tokens" = Option<bool>, Query, description = "Only return matches that consist of whole tokens (as in `token` ranges): they must begin at the beginning of a token and end at the end of one. A literal search (`q`) under a path is then answered by the inverted index, if the server maintains one. Defaults to false."),
+        ("max" = Option<usize>, Query, description = "The maximum number of matches to return. Can not exceed the limit configured on the server."),
+        ("context" = Option<usize>, Query, description = "The amount of context to the left and to the right of each match, in the unit specified by `unit`. Defaults to 40 characters, 5 words or 0 lines."),
+        ("unit" = Option<String>, Query, description = "The unit for `context`, either `char` (default), `word` (or its alias `token`) or `line`."),
+    ),
+    responses(
+        (status = 200, body = [apidocs::ConcordanceLine], description = "A concordance with one entry per match, as for `GET /concordance/{text_id}`.", content(
+            ([apidocs::ConcordanceLine] = "application/json"),
+            (String = "text/tab-separated-values"),
+            (String = "text/csv"),
+        )),
+        (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered", content_type = "application/json"),
+    )
+)]
+/// Returns a keyword-in-context (KWIC) concordance over all texts
+async fn concordance_all(
+    Query(params): Query<ConcordanceParams>,
+    State(textpool): State<Arc<TextPool>>,
+    request: Request<Body>,
+) -> Result<ApiResponse, ApiError> {
+    concordance_helper(textpool, "", params, request.headers()).await
+}
+
+async fn concordance_helper(
+    textpool: Arc<TextPool>,
+    text_id: &str,
+    params: ConcordanceParams,
+    headers: &HeaderMap<HeaderValue>,
+) -> Result<ApiResponse, ApiError> {
+    let content_type = negotiate_content_type(
+        headers,
+        &[CONTENT_TYPE_JSON, CONTENT_TYPE_TSV, CONTENT_TYPE_CSV],
+    )?;
+    let unit = match params.unit.as_deref() {
+        Some(unit) => ContextUnit::from_unit(unit).ok_or(ApiError::ParameterError(
+            "invalid unit, must be 'char', 'word' (or 'token') or 'line'",
+        ))?,
+        None => ContextUnit::Chars,
+    };
+    let size = params.context.unwrap_or(match unit {
+        ContextUnit::Chars => 40,
+        ContextUnit::Tokens => 5,
+        ContextUnit::Lines => 0,
+    });
+    let search_params = SearchParams {
+        q: params.q,
+        regex: params.regex,
+        case_insensitive: params.case_insensitive,
+        tokens: params.tokens,
+        max: params.max,
+    };
+    let text_id = text_id.to_string();
+    let lines = blocking(move || {
+        let matches = collect_matches(&textpool, &text_id, &search_params)?;
+        let mut lines = Vec::with_capacity(matches.len());
+        for (id, m) in matches {
+            let (left, right) = textpool.context(&id, m.begin, m.end, size, unit)?;
+            let left = textpool.excerpt(&id, left, m.begin)?;
+            let right = textpool.excerpt(&id, m.end, right)?;
+            lines.push((id, m.begin, m.end, left, m.text, right));
+        }
+        Ok(lines)
+    })
+    .await?;
+
+    if content_type == CONTENT_TYPE_JSON {
+        return Ok(ApiResponse::JsonList(
+            lines
+                .into_iter()
+                .map(|(id, begin, end, left, text, right)| {
+                    serde_json::json!({
+                        "id": id,
+                        "begin": begin,
+                        "end": end,
+                        "left": left,
+                        "match": text,
+                        "right": right,
+                    })
+                })
+                .collect(),
+        ));
+    }
+
+    let separator = if content_type == CONTENT_TYPE_TSV {
+        "\t"
+    } else {
+        ","
+    };
+    let mut table = ["id", "begin", "end", "left", "match", "right"].join(separator);
+    table.push('\n');
+    for (id, begin, end, left, text, right) in lines {
+        let fields = [id, begin.to_string(), end.to_string(), left, text, right].map(|field| {
+            //line breaks, tabs and other control characters would break the alignment of a concordance
+            let field = field.replace(|c: char| c.is_control(), " ");
+            if separator == "," && field.contains([',', '"']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field
+            }
+        });
+        table += &fields.join(separator);
+        table.push('\n');
+    }
+    if content_type == CONTENT_TYPE_TSV {
+        Ok(ApiResponse::Tsv(table))
+    } else {
+        Ok(ApiResponse::Csv(table))
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/selector",
+    request_body( content_type = "application/json", content = apidocs::SelectorRequest, description = "A W3C Web Annotation, an annotation target, or one or more selectors. A `source` holding the URI of a text on this server is required here."),
+    responses(
+        (status = 200, body = apidocs::SelectorResult, description = "The selected text, along with both a TextPositionSelector and a TextQuoteSelector for it.", content_type = "application/json"),
+        (status = 400, body = apidocs::ApiError, description = "An ApiError with name 'ParameterError` is returned if there is no (usable) source or selector", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the text does not exist or the quote could not be found", content_type = "application/json"),
+    )
+)]
+/// Resolves W3C Web Annotation text selectors (TextPositionSelector and/or TextQuoteSelector) against the text referenced by the `source` of the annotation target.
+async fn resolve_selector(
+    State(textpool): State<Arc<TextPool>>,
+    nested_path: Option<Extension<NestedPath>>,
+    Json(request): Json<annotation::SelectorRequest>,
+) -> Result<ApiResponse, ApiError> {
+    let (source, selectors) = request.into_parts();
+    let source = source.ok_or(ApiError::ParameterError(
+        "A source is required, or pass the text identifier in the path",
+    ))?;
+    let mount = nested_path.map(|Extension(path)| path);
+    blocking(move || {
+        let mount = mount.as_ref().map_or("", |path| path.as_str());
+        let text = source_to_text(&textpool, &source, mount)?;
+        let mut result = annotation::resolve(&textpool, &text.loaded_id, selectors)?;
+        text.describe(&mut result);
+        result["source"] = source.into();
+        Ok(ApiResponse::Json(result))
+    })
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/selector/{text_id}",
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk. It may contain zero or more path components."),
+    ),
+    request_body( content_type = "application/json", content = apidocs::SelectorRequest, description = "A W3C Web Annotation, an annotation target, or one or more selectors. Any `source` is ignored in favour of the text identifier in the path."),
+    responses(
+        (status = 200, body = apidocs::SelectorResult, description = "The selected text, along with both a TextPositionSelector and a TextQuoteSelector for it.", content_type = "application/json"),
+        (status = 400, body = apidocs::ApiError, description = "An ApiError with name 'ParameterError` is returned if there is no (usable) selector", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the text does not exist or the quote could not be found", content_type = "application/json"),
+    )
+)]
+/// Resolves W3C Web Annotation text selectors (TextPositionSelector and/or TextQuoteSelector) against a text.
+/// If both a position and a quote are given, the position is leading and the result reports whether the quote still matches there.
+async fn resolve_selector_text(
+    Path(text_id): Path<String>,
+    State(textpool): State<Arc<TextPool>>,
+    Json(request): Json<annotation::SelectorRequest>,
+) -> Result<ApiResponse, ApiError> {
+    let (_, selectors) = request.into_parts();
+    Ok(ApiResponse::Json(
+        blocking(move || annotation::resolve(&textpool, &text_id, selectors)).await?,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/anchor",
+    request_body( content_type = "application/json", content = apidocs::SelectorRequest, description = "A W3C Web Annotation, an annotation target, or one or more selectors. A TextQuoteSelector is required, a TextPositionSelector is used as a hint for the expected position. A `source` holding the URI of a text on this server is required here."),
+    responses(
+        (status = 200, body = apidocs::SelectorResult, description = "The text where the quote was anchored, along with both a TextPositionSelector and a TextQuoteSelector for it and a `confidence` score.", content_type = "application/json"),
+        (status = 400, body = apidocs::ApiError, description = "An ApiError with name 'ParameterError` is returned if there is no (usable) source or quote selector", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the text does not exist or the quote could not be anchored", content_type = "application/json"),
+    )
+)]
+/// Anchors a W3C Web Annotation TextQuoteSelector in the text referenced by the `source` of the annotation target, tolerating small edits made to the text since.
+async fn anchor_selector(
+    State(textpool): State<Arc<TextPool>>,
+    nested_path: Option<Extension<NestedPath>>,
+    Json(request): Json<annotation::SelectorRequest>,
+) -> Result<ApiResponse, ApiError> {
+    let (source, selectors) = request.into_parts();
+    let source = source.ok_or(ApiError::ParameterError(
+        "A source is required, or pass the text identifier in the path",
+    ))?;
+    let mount = nested_path.map(|Extension(path)| path);
+    blocking(move || {
+        let mount = mount.as_ref().map_or("", |path| path.as_str());
+        let text = source_to_text(&textpool, &source, mount)?;
+        let mut result = annotation::anchor(&textpool, &text.loaded_id, selectors)?;
+        text.describe(&mut result);
+        result["source"] = source.into();
+        Ok(ApiResponse::Json(result))
+    })
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/anchor/{text_id}",
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk. It may contain zero or more path components."),
+    ),
+    request_body( content_type = "application/json", content = apidocs::SelectorRequest, description = "A W3C Web Annotation, an annotation target, or one or more selectors. A TextQuoteSelector is required, a TextPositionSelector is used as a hint for the expected position. Any `source` is ignored in favour of the text identifier in the path."),
+    responses(
+        (status = 200, body = apidocs::SelectorResult, description = "The text where the quote was anchored, along with both a TextPositionSelector and a TextQuoteSelector for it and a `confidence` score.", content_type = "application/json"),
+        (status = 400, body = apidocs::ApiError, description = "An ApiError with name 'ParameterError` is returned if there is no (usable) quote selector", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the text does not exist or the quote could not be anchored", content_type = "application/json"),
+    )
+)]
+/// Anchors a W3C Web Annotation TextQuoteSelector (`exact` with optional `prefix` and `suffix`) in a text, tolerating small edits made to the text since the selector was created.
+/// The best match is determined by the similarity of quote, prefix and suffix, and the distance to the expected position (if a TextPositionSelector is given).
+async fn anchor_selector_text(
+    Path(text_id): Path<String>,
+    State(textpool): State<Arc<TextPool>>,
+    Json(request): Json<annotation::SelectorRequest>,
+) -> Result<ApiResponse, ApiError> {
+    let (_, selectors) = request.into_parts();
+    Ok(ApiResponse::Json(
+        blocking(move || annotation::anchor(&textpool, &text_id, selectors)).await?,
+    ))
+}
+
+/// Derives a text identifier from a URI that refers to a text on this server, e.g. `https://example.org/path/to/text`
+/// or `https://example.org/api2/path|to|text`. Only the path of the URI is considered, so it does not matter under what host the server is known.
+/// If the routes are nested under a path (the `mount`, e.g. `/texts`), absolute paths must start with it and it is stripped first.
+/// A version selected in the URI (`?version={n}` or `?ref={ref}`, or `@{version}` in API 2) selects that version of the text.
+fn source_to_text(
+    textpool: &TextPool,
+    source: &str,
+    mount: &str,
+) -> Result<TextSelection, ApiError> {
+    let path = source
+        .split_once("://")
+        .map(|(_, remainder)| {
+            remainder
+                .find('/')
+                .map(|pos| &remainder[pos..])
+                .unwrap_or("")
+        })
+        .unwrap_or(source);
+    let path = path.split('#').next().unwrap_or(path);
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let mount = mount.trim_end_matches('/');
+    let path = match path.strip_prefix(mount) {
+        //a relative reference holds just the identifier
+        _ if mount.is_empty() || !path.starts_with('/') => path,
+        Some(remainder) if remainder.is_empty() || remainder.starts_with('/') => remainder,
+        _ => {
+            return Err(ApiError::ParameterError(
+                "The source does not refer to a text on this server",
+            ))
+        }
+    };
+    if let Some(api2) = path.strip_prefix("/api2/") {
+        //the region (if any) is not part of the identifier
+        let id = api2.split('/').next().unwrap_or(api2);
+        check_git_cache(&percent_decode_str(id).decode_utf8_lossy())?;
+        if id.is_empty() {
+            return Err(ApiError::ParameterError(
+                "The source does not refer to a text",
+            ));
+        }
+        return api2_versioned_id(textpool, &percent_decode_str(id).decode_utf8_lossy());
+    }
+    let id = percent_decode_str(path.trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .into_owned();
+    check_git_cache(&id)?;
+    if id.is_empty() {
+        return Err(ApiError::ParameterError(
+            "The source does not refer to a text",
+        ));
+    }
+    let mut version = None;
+    let mut reference = None;
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        match key {
+            "version" => {
+                version =
+                    Some(value.parse().map_err(|_| {
+                        ApiError::ParameterError("version must be a positive integer")
+                    })?)
+            }
+            "ref" => reference = Some(percent_decode_str(value).decode_utf8_lossy().into_owned()),
+            _ => {}
+        }
+    }
+    TextSelection::resolve(textpool, id, version, reference)
+}
+
+/// Collects the matches of a search over one or more texts (see `search_text_ids()`), up to the configured limits.
+/// Unlike the search endpoint, this does not stream, and texts that can not be searched are skipped when searching under a path.
+fn collect_matches(
+    textpool: &TextPool,
+    text_id: &str,
+    params: &SearchParams,
+) -> Result<Vec<(String, SearchMatch)>, ApiError> {
+    let limits = textpool.search_limits();
+    let max = params.max.unwrap_or(limits.max_hits).min(limits.max_hits);
+    let subtree = text_id.is_empty() || text_id.ends_with('/');
+    let case_insensitive = params.case_insensitive.unwrap_or(false);
+    let tokens = params.tokens.unwrap_or(false);
+    if let (None, Some(q)) = (params.regex.as_deref(), params.q.as_deref()) {
+        let deadline = std::time::Instant::now() + limits.time;
+        if !subtree {
+            let pattern = Pattern::new(q, case_insensitive)?;
+            return Ok(textpool
+                .search(text_id, &pattern, Some(max), deadline, tokens)?
+                .0
+                .into_iter()
+                .map(|m| (text_id.to_string(), m))
+                .collect());
+        } else if let (true, Some(invindex)) = (tokens, textpool.inverted_index()) {
+            let pattern = Pattern::new(q, case_insensitive)?;
+            let mut matches = Vec::new();
+            let mut result = Ok(());
+            invindex.search(q, text_id, deadline, |m| {
+                match textpool.excerpt(&m.id, m.begin, m.end) {
+                    Ok(text) if pattern.is_match(&text) => matches.push((
+                        m.id.to_string(),
+                        SearchMatch {
+                            text,
+                            begin: m.begin,
+                            end: m.end,
+                            line: m.line,
+                        },
+                    )),
+                    Ok(_) => {}
+                    Err(e) => result = Err(e),
+                }
+                result.is_ok() && matches.len() < max
+            })?;
+            return result.map(|_| matches);
+        }
+    }
+
+    let regex = search_regex(params)?;
+    let deadline = std::time::Instant::now() + limits.time;
+    let mut matches = Vec::new();
+    for id in search_text_ids(textpool, text_id)? {
+        let result = textpool.search_regex(&id, &regex, deadline, tokens, |m| {
+            if matches.len() >= max {
+                return false;
+            }
+            matches.push((id.clone(), m));
+            true
+        });
+        match result {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) if subtree => error!("Unable to search {}: {:?}", id, e),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(matches)
+}
+
+/// Compiles the regular expression for a search, a literal query is escaped so it can be used as a regular expression too
+fn search_regex(params: &SearchParams) -> Result<regex::Regex, ApiError> {
+    let expression = match (params.regex.as_deref(), params.q.as_deref()) {
+        (Some(regex), _) => Cow::Borrowed(regex),
+        (None, Some(q)) if !q.is_empty() => regex::escape(q).into(),
+        _ => return Err(ApiError::ParameterError("expected parameter q or regex")),
+    };
+    regex::RegexBuilder::new(&expression)
+        .case_insensitive(params.case_insensitive.unwrap_or(false))
+        .build()
+        .map_err(|_| ApiError::ParameterError("invalid regular expression"))
+}
+
+/// Returns the identifiers of the texts to search, this is either a single text or all texts under a path (if it ends in a slash or is empty)
+fn search_text_ids(textpool: &TextPool, text_id: &str) -> Result<Vec<String>, ApiError> {
+    if text_id.is_empty() || text_id.ends_with('/') {
+        for component in text_id.split('/') {
+            if component.starts_with('.') {
+                return Err(ApiError::NotFound("Invalid path"));
+            }
+        }
+        Ok(textpool
+            .list(text_id)?
+            .into_iter()
+            .map(|id| format!("{}{}", text_id, id))
+            .collect())
+    } else {
+        //make sure the text exists before we commit to a response
+        textpool.with_text(text_id, |_| Ok(()))?;
+        Ok(vec![text_id.to_string()])
+    }
+}
+
+/// Runs a regular expression search over one or more texts in a blocking thread, and streams the results as newline-delimited JSON
+fn regex_search_stream(
+    textpool: Arc<TextPool>,
+    text_ids: Vec<String>,
+    regex: regex::Regex,
+    tokens: bool,
+    max: usize,
+    time_limit: Duration,
+) -> impl futures::Stream<Item = Result<String, Infallible>> {
+    let (sender, receiver) = tokio::sync::mpsc::channel::<String>(64);
+    tokio::task::spawn_blocking(move || {
+        let deadline = std::time::Instant::now() + time_limit;
+        let mut hits = 0;
+        for text_id in text_ids.iter() {
+            let mut disconnected = false;
+            let result = textpool.search_regex(text_id, &regex, deadline, tokens, |m| {
+                if hits >= max {
+                    return false;
+                }
+                hits += 1;
+                disconnected = sender.blocking_send(match_line(text_id, &m)).is_err();
+                !disconnected
+            });
+            let trailer = match result {
+                Ok(true) => continue,
+                Ok(false) if disconnected => return,
+                Ok(false) if hits >= max => {
+                    serde_json::json!({ "truncated": true, "reason": "maximum number of matches reached" })
+                }
+                Ok(false) => {
+                    serde_json::json!({ "truncated": true, "reason": "time limit exceeded" })
+                }
+                Err(e) => {
+                    //report the error for this text and move on to the next
+                    let mut line = serde_json::json!({ "id": text_id, "error": e }).to_string();
+                    line.push('\n');
+                    if sender.blocking_send(line).is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let _ = sender.blocking_send(format!("{}\n", trailer));
+            return;
+        }
+    });
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver
+            .recv()
+            .await
+            .map(|line| (Ok::<_, Infallible>(line), receiver))
+    })
+}
+
+/// Runs a phrase search via the inverted index in a blocking thread, and streams the results as newline-delimited JSON.
+/// The index matches tokens case-insensitively, so candidates are checked against the pattern (which may be case-sensitive) before they are returned.
+fn index_search_stream(
+    textpool: Arc<TextPool>,
+    prefix: String,
+    q: String,
+    pattern: Pattern,
+    max: usize,
+    time_limit: Duration,
+) -> impl futures::Stream<Item = Result<String, Infallible>> {
+    let (sender, receiver) = tokio::sync::mpsc::channel::<String>(64);
+    tokio::task::spawn_blocking(move || {
+        let deadline = std::time::Instant::now() + time_limit;
+        let invindex = textpool
+            .inverted_index()
+            .expect("inverted index must be enabled");
+        let mut hits = 0;
+        let mut disconnected = false;
+        let result = invindex.search(&q, &prefix, deadline, |m| {
+            if hits >= max {
+                return false;
+            }
+            let line = match textpool.excerpt(&m.id, m.begin, m.end) {
+                Ok(text) if pattern.is_match(&text) => {
+                    hits += 1;
+                    match_line(
+                        &m.id,
+                        &SearchMatch {
+                            begin: m.begin,
+                            end: m.end,
+                            line: m.line,
+                            text,
+                        },
+                    )
+                }
+                Ok(_) => return true,
+                Err(e) => format!("{}\n", serde_json::json!({ "id": m.id, "error": e })),
+            };
+            disconnected = sender.blocking_send(line).is_err();
+            !disconnected
+        });
+        let trailer = match result {
+            Ok(true) => return,
+            Ok(false) if disconnected => return,
+            Ok(false) if hits >= max => {
+                serde_json::json!({ "truncated": true, "reason": "maximum number of matches reached" })
+            }
+            Ok(false) => {
+                serde_json::json!({ "truncated": true, "reason": "time limit exceeded" })
+            }
+            Err(e) => serde_json::json!({ "error": e }),
+        };
+        let _ = sender.blocking_send(format!("{}\n", trailer));
+    });
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver
+            .recv()
+            .await
+            .map(|line| (Ok::<_, Infallible>(line), receiver))
+    })
+}
+
+/// Formats a search match as a line of newline-delimited JSON
+fn match_line(text_id: &str, m: &SearchMatch) -> String {
+    let mut line = serde_json::json!({
+        "id": text_id,
+        "begin": m.begin,
+        "end": m.end,
+        "line": m.line,
+        "text": m.text,
+    })
+    .to_string();
+    line.push('\n');
+    line
+}
+
+#[utoipa::path(
+    get,
+    path = "/api2/{text_id}/{region}",
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk. It may be suffixed with `@{version}` to select a prior version of the text (numbered from 1, see `info.json`), or with `@{ref}` to select the text as it was at a git ref (if git-backed storage is enabled)."),
+        ("region" = isize, Path, description = "A region specification in the form: `[{prefix:}]{begin},{end}`. Where begin is an integer indicating the begin offset in unicode points (0-indexed, this may be a negative integer for end-aligned cursors). End is integer indicating the non-inclusive end offset in unicode points (0-indexed). This may be a negative integer for end-aligned cursors and `0` for actual end. Prefix can be `char`, `line`, `utf16`, `grapheme`, `token`, `word` (an alias of `token`), `para` or `page`, the first is the default if omitted entirely, in the case of `line` begin and end arguments will be interpreted to be lines rather than characters (0-indexed, non-inclusive end), in the case of `utf16` they will be interpreted as UTF-16 code units (as used by JavaScript), in the case of `grapheme` as extended grapheme clusters (user-perceived characters), in the case of `token` as tokens (words and punctuation, the text from the beginning of the first token to the end of the last token is returned), in the case of `para` as paragraphs (separated by blank lines or form feeds) and in the case of `page` as pages (separated by form feeds). Instead of a range, you can also use the keyword `full` to get the full text, which is identical to just omitted the region parameter entirely. The region may be followed by options separated by semicolons: `;context={n}` returns `n` characters of context before and after the selection, `;context_lines={n}` returns `n` lines of context (completing the lines the selection starts and ends on), in both cases a JSON envelope is returned rather than plain text. Last, instead of a region you can also specify `info.json` to get metadata about a text, including the current version and a list of prior revisions."),
+    ),
+    responses(
+        (status = 200, description = "The requested text excerpt, or a JSON envelope if requested via the Accept header (`TextEnvelope`) or if context was requested (`ContextEnvelope`)",content(
+            (String = "text/plain"),
+            (apidocs::TextEnvelope = "application/json"),
+            (apidocs::ContextEnvelope = "application/json"),
+        )),
+        (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
+    )
+)]
+/// Returns a text or a text slice according to Text Referencing API 2
+async fn get_api2_with_region(
+    Path((text_id, region)): Path<(String, String)>,
+    State(textpool): State<Arc<TextPool>>,
+    headers: HeaderMap,
+) -> Result<ApiResponse, ApiError> {
+    let text = api2_versioned_id_blocking(&textpool, text_id).await?;
+
+    if region == "info.json" {
+        return textpool.stat_api2(&text.loaded_id);
+    }
+
+    //options may follow the region, separated by semicolons (as in RFC5147)
+    let mut options = region.split(';');
+    let region = options.next().unwrap_or_default();
+    let mut context = None;
+    let mut context_lines = None;
+    for option in options {
+        match option.split_once('=') {
+            Some(("context", value)) => context = Some(parse_option(value)?),
+            Some(("context_lines", value)) => context_lines = Some(parse_option(value)?),
+            _ => {
+                return Err(ApiError::ParameterError(
+                    "invalid region option, must be 'context' or 'context_lines'",
+                ))
+            }
+        }
+    }
+
+    let range = if let Some((prefix, remainder)) = region.split_once(':') {
+        let (begin, end) = get_text_slice_helper(remainder)?;
+        Range::from_unit(prefix, begin, end).ok_or(ApiError::ParameterError(
+            "invalid prefix for region parameter, must be 'char', 'line', 'utf16', 'grapheme', 'token', 'word', 'para' or 'page'",
+        ))?
+    } else {
+        let (begin, end) = get_text_slice_helper(region)?;
+        Range::Chars(begin, end)
+    };
+
+    if let Some((size, unit)) = context_param(context, context_lines)? {
+        let (envelope, _) = get_text_with_context(&textpool, &text, range, size, unit)?;
+        Ok(ApiResponse::Json(envelope))
+    } else if wants_json(&headers) {
+        get_text_envelope_stream(textpool, &text, range)
+    } else {
+        get_text_chars(textpool, &text.loaded_id, range, false)
+    }
+}
+
+fn parse_option(value: &str) -> Result<usize, ApiError> {
+    value
+        .parse()
+        .map_err(|_| ApiError::ParameterError("region option must be a non-negative integer"))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api2/{text_id}",
+    params(
+        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk. It may be suffixed with `@{version}` to select a prior version of the text, or with `@{ref}` to select the text as it was at a git ref (if git-backed storage is enabled)."),
+    ),
+    responses(
+        (status = 200, description = "The requested text excerpt, or a JSON envelope describing it if requested via the Accept header",content(
+            (String = "text/plain"),
+            (apidocs::TextEnvelope = "application/json"),
+        )),
+        (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
+    )
+)]
+async fn get_api2_short(
+    Path(text_id): Path<String>,
+    State(textpool): State<Arc<TextPool>>,
+    headers: HeaderMap,
+) -> Result<ApiResponse, ApiError> {
+    let text = api2_versioned_id_blocking(&textpool, text_id).await?;
+    if wants_json(&headers) {
+        get_text_envelope_stream(textpool, &text, Range::Chars(0, 0))
+    } else {
+        get_text_chars(textpool, &text.loaded_id, Range::Chars(0, 0), false)
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api2/batch",
+    request_body( content_type = "application/json", content = [apidocs::BatchItem]),
+    responses(
+        (status = 200, body = [apidocs::BatchResult], description = "A JSON list with one result for each requested range, in the same order as the request. Results that could not be resolved carry an `error` key rather than `text`.", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'ParameterError` is returned if the request holds more than 1000 items", content_type = "application/json"),
+    )
+)]
+/// Returns multiple text excerpts, possibly from multiple texts, in a single request (Text Referencing API 2). Identical to `POST /batch`, except that identifiers may use pipes as a substitute for slashes.
+async fn get_batch_api2(
+    State(textpool): State<Arc<TextPool>>,
+    Json(items): Json<Vec<BatchItem>>,
+) -> Result<ApiResponse, ApiError> {
+    blocking(move || batch_helper(&textpool, items, true)).await
+}
+
+/// Resolves a suffix on an API2 text identifier to the identifier of a specific version: `@{version}` (numeric) selects a prior revision,
+/// `@{ref}` selects the text as it was at a git ref (if git-backed storage is enabled). Pipes are decoded to slashes in both parts.
+/// Identifiers that themselves contain an `@` remain accessible as long as the suffix does not resolve.
+fn api2_versioned_id(textpool: &TextPool, text_id: &str) -> Result<TextSelection, ApiError> {
+    let Some((id, suffix)) = text_id
+        .rsplit_once('@')
+        .filter(|(_, suffix)| !suffix.is_empty())
+    else {
+        return Ok(TextSelection::current(api2_decode_id(text_id).into_owned()));
+    };
+    let id = api2_decode_id(id).into_owned();
+    let result = if suffix.bytes().all(|c| c.is_ascii_digit()) {
+        suffix
+            .parse()
+            .map_err(|_| ApiError::ParameterError("version must be a positive integer"))
+            .and_then(|version| TextSelection::resolve(textpool, id, Some(version), None))
+    } else {
+        let reference = api2_decode_id(suffix).into_owned();
+        TextSelection::resolve(textpool, id, None, Some(reference))
+    };
+    match result {
+        Ok(text) => Ok(text),
+        Err(_) if textpool.exists(&api2_decode_id(text_id)) => {
+            Ok(TextSelection::current(api2_decode_id(text_id).into_owned()))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// As [`api2_versioned_id()`], but off the async executor, as it may retrieve the text from git
+async fn api2_versioned_id_blocking(
+    textpool: &Arc<TextPool>,
+    text_id: String,
+) -> Result<TextSelection, ApiError> {
+    let textpool = textpool.clone();
+    blocking(move || api2_versioned_id(&textpool, &text_id)).await
+}
+
+/// A text as selected by a request: its current version, a prior version, or the text as it was at a git ref.
+/// Results report the text identifier along with the version or ref (if any), rather than the internal identifier under which the selected version is loaded.
+struct TextSelection {
+    /// The text identifier
+    id: String,
+    /// The version number, if a specific version was selected
+    version: Option<usize>,
+    /// The git ref the text is retrieved at, if any
+    reference: Option<String>,
+    /// The identifier under which the selected version of the text is loaded (for prior revisions and texts retrieved from git this is an internal identifier)
+    loaded_id: String,
+}
+
+impl TextSelection {
+    /// Selects the current version of a text
+    fn current(id: String) -> Self {
+        Self {
+            loaded_id: id.clone(),
+            id,
+            version: None,
+            reference: None,
+        }
+    }
+
+    /// Selects a prior version of a text, or the text as it was at a git ref (if any is given), retrieving it from git if needed
+    fn resolve(
+        textpool: &TextPool,
+        id: String,
+        version: Option<usize>,
+        reference: Option<String>,
+    ) -> Result<Self, ApiError> {
+        match (version, reference) {
+            (Some(version), None) => Ok(Self {
+                loaded_id: textpool.revision_id(&id, version)?,
+                id,
+                version: Some(version),
+                reference: None,
+            }),
+            (None, Some(reference)) => Ok(Self {
+                loaded_id: textpool.ref_id(&id, &reference)?,
+                id,
+                version: None,
+                reference: Some(reference),
+            }),
+            (None, None) => Ok(Self::current(id)),
+            (Some(_), Some(_)) => Err(ApiError::ParameterError(
+                "version and ref can not be combined",
+            )),
+        }
+    }
+
+    /// Adds the text identifier (and the version or ref, if any) to a JSON result
+    fn describe(&self, result: &mut serde_json::Value) {
+        result["id"] = self.id.as_str().into();
+        if let Some(version) = self.version {
+            result["version"] = version.into();
+        }
+        if let Some(reference) = &self.reference {
+            result["ref"] = reference.as_str().into();
+        }
+    }
+}
+
+/// Extra patch to allow pipes as a substitute for slashes in URLs
+fn api2_decode_id<'a>(s: &'a str) -> Cow<'a, str> {
+    if s.find('|').is_some() {
+        Cow::Owned(s.replace("|", "/"))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+fn get_text_slice_helper(s: &str) -> Result<(isize, isize), ApiError> {
+    if s == "full" {
+        return Ok((0, 0));
+    }
+    if let Some((begin, end)) = s.split_once(',') {
+        let begin: isize = begin
+            .parse()
+            .map_err(|_| ApiError::ParameterError("region begin parameter must be an integer"))?;
+        let end: isize = end
+            .parse()
+            .map_err(|_| ApiError::ParameterError("region end parameter must be an integer"))?;
+        Ok((begin, end))
+    } else {
+        Err(ApiError::ParameterError(
+            "region parameter must have a comma to express a range",
+        ))
+    }
+}
+
+/// Picks the offered content type the client prefers according to the Accept header (RFC 9110): the offer with the highest quality (`q`) wins,
+/// ties are settled by the order in the Accept header and then by the order of the offers. Types with quality 0 are never picked.
+/// The first offer is returned if there is no Accept header.
+fn negotiate_content_type(
+    headers: &HeaderMap<HeaderValue>,
+    offer_types: &[&'static str],
+) -> Result<&'static str, ApiError> {
+    let Some(accept_types) = headers.get(axum::http::header::ACCEPT) else {
+        return Ok(offer_types[0]);
+    };
+    let accept_types: Vec<(&str, f32)> = accept_types
+        .to_str()
+        .unwrap_or(CONTENT_TYPE_JSON)
+        .split(',')
+        .map(|accept_type| {
+            let mut parameters = accept_type.split(';');
+            let media_type = parameters.next().unwrap_or_default().trim();
+            let quality = parameters
+                .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+                .next()
+                .map_or(1.0, |q| q.trim().parse().unwrap_or(0.0));
+            (media_type, quality)
+        })
+        .collect();
+
+    let mut best: Option<(&'static str, f32, usize)> = None;
+    for offer_type in offer_types.iter() {
+        //the most specific media range that matches the offer determines its quality
+        let mut matched: Option<(u8, f32, usize)> = None;
+        for (i, (media_type, quality)) in accept_types.iter().enumerate() {
+            let specificity = if media_type.eq_ignore_ascii_case(offer_type) {
+                2
+            } else if media_type.strip_suffix("/*").is_some_and(|prefix| {
+                offer_type
+                    .split_once('/')
+                    .is_some_and(|(offer_prefix, _)| offer_prefix.eq_ignore_ascii_case(prefix))
+            }) {
+                1
+            } else if *media_type == "*/*" {
+                0
+            } else {
+                continue;
+            };
+            if matched.is_none_or(|(s, _, _)| specificity > s) {
+                matched = Some((specificity, *quality, i));
+            }
+        }
+        if let Some((_, quality, i)) = matched {
+            if quality > 0.0
+                && best.is_none_or(|(_, best_quality, best_i)| {
+                    quality > best_quality || (quality == best_quality && i < best_i)
+                })
+            {
+                best = Some((offer_type, quality, i));
+            }
+        }
+    }
+    if let Some((matching_offer, _, _)) = best {
+        Ok(matching_offer)
+    } else {
+        Err(ApiError::NotAcceptable("No matching content type on offer"))
+    }
+}
+
+fn delete_subdir(
+    dir: &str,
+    textpool: &TextPool,
+    author: Option<&str>,
+) -> Result<ApiResponse, ApiError> {
+    for component in dir.split('/') {
+        if component.starts_with('.') {
+            return Err(ApiError::NotFound("Invalid path"));
+        }
+    }
+
+    let dir = format!("{}/", dir.trim_matches('/'));
+    let dir = if dir == "/" { "" } else { dir.as_str() };
+    for text_id in textpool.list(dir)? {
+        textpool.delete_text_as(&format!("{}{}", dir, text_id), author)?;
+    }
+    //TODO: clean up remaining empty directories
+    Ok(ApiResponse::NoContent())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Negotiates between the given offers for an Accept header
+    fn negotiate(accept: &str, offers: &[&'static str]) -> Option<&'static str> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            axum::http::header::ACCEPT,
+            HeaderValue::from_str(accept).expect("valid header"),
+        );
+        negotiate_content_type(&headers, offers).ok()
+    }
+
+    const OFFERS: &[&str] = &[CONTENT_TYPE_TEXT, CONTENT_TYPE_JSON];
+
+    /// Creates a text pool in an empty directory for a test
+    fn textpool(name: &str) -> (TextPool, std::path::PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("textsurf-lib-{}-{}", std::process::id(), name));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).expect("creating test directory");
+        let textpool =
+            TextPool::new(&dir, "txt", false, None, true, 600).expect("creating text pool");
+        (textpool, dir)
+    }
+
+    /// Returns the JSON envelope of a selected text, as returned to clients
+    fn text_envelope(textpool: &TextPool, text: &TextSelection) -> serde_json::Value {
+        get_text_envelope(textpool, text, Range::Chars(0, 0))
+            .expect("envelope")
+            .0
+    }
+
+    #[test]
+    fn negotiate_without_accept() {
+        assert_eq!(
+            negotiate_content_type(&HeaderMap::new(), OFFERS).ok(),
+            Some(CONTENT_TYPE_TEXT)
+        );
+    }
+
+    #[test]
+    fn negotiate_exact() {
+        assert_eq!(
+            negotiate("application/json", OFFERS),
+            Some(CONTENT_TYPE_JSON)
+        );
+        assert_eq!(negotiate("text/plain", OFFERS), Some(CONTENT_TYPE_TEXT));
+        assert_eq!(
+            negotiate("Application/JSON", OFFERS),
+            Some(CONTENT_TYPE_JSON)
+        );
+        assert_eq!(negotiate("image/png", OFFERS), None);
+    }
+
+    #[test]
+    fn negotiate_quality() {
+        assert_eq!(
+            negotiate("text/plain;q=0.5, application/json", OFFERS),
+            Some(CONTENT_TYPE_JSON)
+        );
+        assert_eq!(
+            negotiate("application/json; q=0.2, text/plain; q=0.9", OFFERS),
+            Some(CONTENT_TYPE_TEXT)
+        );
+        //ties are settled by the order in the header
+        assert_eq!(
+            negotiate("application/json;q=0.5, text/plain;q=0.5", OFFERS),
+            Some(CONTENT_TYPE_JSON)
+        );
+        //unparsable qualities count as 0
+        assert_eq!(
+            negotiate("application/json;q=high, text/plain;q=0.1", OFFERS),
+            Some(CONTENT_TYPE_TEXT)
+        );
+    }
+
+    #[test]
+    fn negotiate_quality_zero() {
+        assert_eq!(negotiate("application/json;q=0", OFFERS), None);
+        assert_eq!(
+            negotiate("*/*, text/plain;q=0", OFFERS),
+            Some(CONTENT_TYPE_JSON)
+        );
+    }
+
+    #[test]
+    fn negotiate_wildcards() {
+        assert_eq!(negotiate("*/*", OFFERS), Some(CONTENT_TYPE_TEXT));
+        assert_eq!(negotiate("application/*", OFFERS), Some(CONTENT_TYPE_JSON));
+        //the most specific range determines the quality
+        assert_eq!(
+            negotiate(
+                "text/*;q=0.9, text/plain;q=0.1, application/json;q=0.5",
+                OFFERS
+            ),
+            Some(CONTENT_TYPE_JSON)
+        );
+        assert_eq!(
+            negotiate(
+                "text/*;q=0.8, */*;q=0.1",
+                &[CONTENT_TYPE_JSON, CONTENT_TYPE_TSV, CONTENT_TYPE_CSV]
+            ),
+            Some(CONTENT_TYPE_TSV)
+        );
+    }
+
+    #[test]
+    fn ref_output() {
+        let (textpool, dir) = textpool("ref");
+        let textpool = textpool
+            .with_git("test <test@localhost>", GIT_CACHE_LIMIT)
+            .expect("initialising git");
+        textpool
+            .new_text("a", "first".to_string(), false)
+            .expect("writing text");
+        textpool
+            .new_text("a", "second".to_string(), true)
+            .expect("writing text");
+        let text =
+            TextSelection::resolve(&textpool, "a".to_string(), None, Some("HEAD~1".to_string()))
+                .expect("resolving ref");
+        //the text is loaded from the git cache, but this is never reported
+        assert!(text.loaded_id.starts_with(GIT_CACHE_DIR));
+        let envelope = text_envelope(&textpool, &text);
+        assert_eq!(envelope["id"], "a");
+        assert_eq!(envelope["ref"], "HEAD~1");
+        assert_eq!(envelope["text"], "first");
+        assert!(check_git_cache(envelope["id"].as_str().expect("id")).is_ok());
+
+        for source in ["http://localhost/a?ref=HEAD~1", "/api2/a@HEAD~1/char:0,5"] {
+            let text = source_to_text(&textpool, source, "").expect("resolving source");
+            let mut result = serde_json::json!({});
+            text.describe(&mut result);
+            assert_eq!(
+                result,
+                serde_json::json!({ "id": "a", "ref": "HEAD~1" }),
+                "{}",
+                source
+            );
+        }
+        let envelope = text_envelope(&textpool, &TextSelection::current("a".to_string()));
+        assert_eq!(envelope["text"], "second");
+        assert!(envelope.get("ref").is_none());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn revision_output() {
+        let (textpool, dir) = textpool("revision");
+        textpool
+            .new_text("a", "first".to_string(), false)
+            .expect("writing text");
+        textpool
+            .new_text("a", "second".to_string(), true)
+            .expect("writing text");
+        let text = TextSelection::resolve(&textpool, "a".to_string(), Some(1), None)
+            .expect("resolving version");
+        let envelope = text_envelope(&textpool, &text);
+        assert_eq!(envelope["id"], "a");
+        assert_eq!(envelope["version"], 1);
+        assert_eq!(envelope["text"], "first");
+        //the current version
+        let text = TextSelection::resolve(&textpool, "a".to_string(), Some(2), None)
+            .expect("resolving version");
+        let envelope = text_envelope(&textpool, &text);
+        assert_eq!(envelope["id"], "a");
+        assert_eq!(envelope["version"], 2);
+        assert_eq!(envelope["text"], "second");
+        assert!(TextSelection::resolve(&textpool, "a".to_string(), Some(3), None).is_err());
+
+        for source in ["/a?version=1", "/api2/a@1"] {
+            let text = source_to_text(&textpool, source, "").expect("resolving source");
+            let mut result = serde_json::json!({});
+            text.describe(&mut result);
+            assert_eq!(
+                result,
+                serde_json::json!({ "id": "a", "version": 1 }),
+                "{}",
+                source
+            );
+        }
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
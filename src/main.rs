@@ -1,36 +1,52 @@
-use axum::{
-    body::Body, extract::Path, extract::Query, extract::State, http::HeaderMap, http::HeaderValue,
-    http::Request, routing::delete, routing::get, routing::post, routing::put, Router,
-};
-use clap::Parser;
-use futures::StreamExt as _;
+use axum::Router;
+use clap::{Parser, Subcommand};
+use std::process::ExitCode;
 use std::sync::Arc;
 use std::time::Duration;
-use std::{borrow::Cow, convert::Infallible};
 use tokio::signal;
 use tower_http::trace::TraceLayer;
-use tracing::{debug, error, info};
-
-use serde::Deserialize;
+use tracing::info;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
-mod apidocs;
-mod common;
-mod textpool;
-use common::{ApiError, ApiResponse};
-use textpool::TextPool;
-use walkdir::WalkDir;
+use textsurf::{spawn_maintenance, ApiDoc, S3Storage, SearchLimits, TextPool, S3_CACHE_DIR};
+
+mod cli;
 
-pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
-const CONTENT_TYPE_JSON: &str = "application/json";
-// 16KB
-const CHUNK_SIZE: usize = 1 << 14;
-const STREAM_THRESHOLD: usize = CHUNK_SIZE;
 
 #[derive(Parser, Debug)]
-struct Args {
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Start the webservice (this is the default if no command is given)
+    Serve(ServeArgs),
+    /// Output a text, or a range therein
+    Get(cli::GetArgs),
+    /// Upload a text, overwriting it if it exists
+    Put(cli::PutArgs),
+    /// Delete texts
+    Rm(cli::RmArgs),
+    /// List all texts
+    Ls(cli::LsArgs),
+    /// Output metadata about a text (size, checksum, modification time, versions) as JSON
+    Stat(cli::StatArgs),
+    /// Build or refresh the indices of all texts ahead of time, takes the same options as serve
+    Index(cli::IndexArgs),
+    /// Synchronise a local directory to a server: upload new and changed texts and optionally delete removed ones
+    Sync(cli::SyncArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
     #[arg(
         short,
         long,
@@ -73,9 +89,9 @@ struct Args {
     #[arg(
         short,
         long,
-        help = "Allow upload and deletion of texts for bearers of this API key. Do not also specify --writable"
+        help = "Allow upload and deletion of texts for bearers of this API key. May be specified multiple times. The key may be followed by the identity of its holder (`KEY Name <email>`), who is then the author of git commits for writes with this key. Do not also specify --writable"
     )]
-    apikey: Option<String>,
+    apikey: Vec<String>,
 
     #[arg(
         short = 'L',
@@ -85,6 +101,112 @@ struct Args {
     )]
     no_lines: bool,
 
+    #[arg(
+        long,
+        default_value_t = 30,
+        help = "Maximum number of seconds a single search request may take"
+    )]
+    search_time_limit: u64,
+
+    #[arg(
+        long,
+        default_value_t = 10000,
+        help = "Maximum number of matches a single search request may return"
+    )]
+    search_max_hits: usize,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Maintain an inverted index of all tokens (stored in the base directory), speeds up literal whole-token searches (tokens=true) across texts considerably, at the cost of disk space and startup time"
+    )]
+    inverted_index: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Git-backed storage: the base directory is a git repository (initialised if it is not one yet) and every upload or deletion is committed. Texts can then also be retrieved as they were at any git ref."
+    )]
+    git: bool,
+
+    #[arg(
+        long,
+        default_value_os = "textsurf <textsurf@localhost>",
+        help = "Identity (`Name <email>`) used as the author of git commits for anonymous writers (if --writable) and for API keys without an identity of their own"
+    )]
+    git_author: String,
+
+    #[arg(
+        long,
+        default_value_t = textsurf::GIT_CACHE_LIMIT >> 20,
+        help = "Maximum size (in MiB) of the cache of texts retrieved from git (with --git or --bare-repository), the least recently used texts are removed from it when it grows larger"
+    )]
+    git_cache_size: u64,
+
+    #[arg(
+        long,
+        help = "Serve texts straight from this (bare) git repository rather than from the base directory, which then only serves as a cache for texts and indices. This makes the service read-only."
+    )]
+    bare_repository: Option<String>,
+
+    #[arg(
+        long = "ref",
+        default_value_os = "HEAD",
+        help = "The git ref (branch, tag or commit) to serve texts from, in combination with --bare-repository"
+    )]
+    reference: String,
+
+    #[arg(
+        long,
+        default_value_t = 60,
+        help = "Number of seconds between resolving the ref of the bare repository again, so updates pushed to it are picked up"
+    )]
+    refresh_interval: u64,
+
+    #[arg(
+        long,
+        help = "Serve texts from a bucket in this S3-compatible object store (e.g. https://s3.eu-west-1.amazonaws.com or http://localhost:9000) rather than from the base directory, which then only holds the indices. Credentials are read from the AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment variables."
+    )]
+    s3_endpoint: Option<String>,
+
+    #[arg(long, help = "The bucket to use, in combination with --s3-endpoint")]
+    s3_bucket: Option<String>,
+
+    #[arg(
+        long,
+        default_value_os = "us-east-1",
+        help = "The region of the bucket, in combination with --s3-endpoint"
+    )]
+    s3_region: String,
+
+    #[arg(
+        long,
+        default_value_os = "",
+        help = "Only serve objects with keys under this prefix, in combination with --s3-endpoint"
+    )]
+    s3_prefix: String,
+
+    #[arg(
+        long,
+        default_value_t = textsurf::S3_CACHE_LIMIT >> 20,
+        help = "Maximum size (in MiB) of the cache of full copies of objects downloaded from the bucket (needed for indexing and searching), the least recently used copies are removed from it when it grows larger"
+    )]
+    s3_cache_size: u64,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Serve the members of tar and zip archives (files ending in .tar or .zip) as read-only texts, with identifiers like `archive.zip/path/file`, without extracting them. The archives themselves are then no longer listed."
+    )]
+    archives: bool,
+
+    #[arg(
+        long,
+        default_value_t = textsurf::ARCHIVE_CACHE_LIMIT >> 20,
+        help = "Maximum size (in MiB) of the cache of archive members extracted in full (needed for indexing and searching, with --archives), the least recently used members are removed from it when it grows larger"
+    )]
+    archive_cache_size: u64,
+
     #[arg(
         long,
         default_value_t = false,
@@ -93,79 +215,57 @@ struct Args {
     debug: bool,
 }
 
-#[derive(OpenApi)]
-#[openapi(
-    paths(
-        list_texts,
-        create_text,
-        get_text,
-        delete_all,
-        delete_text,
-        stat_text,
-        get_api2_with_region,
-        get_api2_short,
-        create_text_api2,
-        delete_text_api2,
-    ),
-    tags(
-        (name = "textsurf", description = "Webservice for efficiently serving multiple plain text documents or excerpts thereof (by unicode character offset), without loading everything into memory.")
-    )
-)]
-pub struct ApiDoc;
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        None => {
+            serve(cli.serve);
+            Ok(())
+        }
+        Some(Command::Serve(args)) => {
+            serve(args);
+            Ok(())
+        }
+        Some(Command::Get(args)) => cli::get(args),
+        Some(Command::Put(args)) => cli::put(args),
+        Some(Command::Rm(args)) => cli::rm(args),
+        Some(Command::Ls(args)) => cli::ls(args),
+        Some(Command::Stat(args)) => cli::stat(args),
+        Some(Command::Index(args)) => cli::index(args),
+        Some(Command::Sync(args)) => cli::sync(args),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("[textsurf] {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
 #[tokio::main]
-async fn main() {
-    let args = Args::parse();
-
-    let textpool = TextPool::new(
-        args.basedir,
-        args.extension,
-        !args.writable && args.apikey.is_none(),
-        args.apikey,
-        !args.no_lines,
-        args.unload_time,
-    )
-    .expect("Base directory must exist");
-
+async fn serve(args: ServeArgs) {
     if args.debug {
         tracing_subscriber::fmt()
             .with_max_level(tracing::Level::DEBUG)
             .init();
     }
 
-    let textpool: Arc<TextPool> = textpool.into();
-    let textpool_flush = textpool.clone();
-
-    //launch a background thread that flushes texts out of the pool if they're not used for a while
-    std::thread::spawn(move || loop {
-        std::thread::sleep(FLUSH_INTERVAL);
-        match textpool_flush.flush(false) {
-            Err(e) => error!("Flush failed! {:?}", e),
-            Ok(v) => {
-                if args.debug {
-                    debug!("Flushed {} text(s)", v.len());
-                }
-            }
-        }
-    });
+    let textpool: Arc<TextPool> = open_textpool(&args).into();
+
+    //launch background threads that flush texts out of the pool if they're not used for a while, and that pick up updates to the bare repository
+    spawn_maintenance(
+        textpool.clone(),
+        FLUSH_INTERVAL,
+        args.bare_repository
+            .is_some()
+            .then(|| Duration::from_secs(args.refresh_interval)),
+    );
 
     let app = Router::new()
-        .route("/", get(list_texts))
-        .route("/", delete(delete_all))
-        .route("/stat/{*text_id}", get(stat_text))
-        .route("/api2/{text_id}", get(get_api2_short))
-        .route("/api2/{text_id}/{region}", get(get_api2_with_region)) //also used for info.json for stat
-        .route("/api2/{text_id}", post(create_text_api2))
-        .route("/api2/{text_id}", put(create_text_overwrite_api2))
-        .route("/api2/{text_id}", delete(delete_text_api2))
-        .route("/{*text_id}", get(get_text))
-        .route("/{*text_id}", post(create_text))
-        .route("/{*text_id}", put(create_text_overwrite))
-        .route("/{*text_id}", delete(delete_text))
-        .route("/flush", post(flush))
+        .merge(textsurf::router(textpool.clone()))
         .merge(SwaggerUi::new("/swagger-ui").url("/api-doc/openapi.json", ApiDoc::openapi()))
-        .layer(TraceLayer::new_for_http())
-        .with_state(textpool.clone());
+        .layer(TraceLayer::new_for_http());
 
     //allow trailing slashes as well: (conflicts with swagger-ui!)
     //let app = NormalizePathLayer::trim_trailing_slash().layer(app);
@@ -181,6 +281,93 @@ async fn main() {
     .unwrap();
 }
 
+/// Sets up the text pool as configured, this panics if that fails
+fn open_textpool(args: &ServeArgs) -> TextPool {
+    let textpool = TextPool::new(
+        args.basedir.as_str(),
+        args.extension.as_str(),
+        !args.writable && args.apikey.is_empty(),
+        None,
+        !args.no_lines,
+        args.unload_time,
+    )
+    .expect("Base directory must exist")
+    .with_search_limits(SearchLimits {
+        time: Duration::from_secs(args.search_time_limit),
+        max_hits: args.search_max_hits,
+    });
+
+    let textpool = args.apikey.iter().fold(textpool, |textpool, apikey| {
+        //the key may be followed by the identity of its holder
+        let (apikey, author) = match apikey.trim().split_once(char::is_whitespace) {
+            Some((apikey, author)) => (apikey, Some(author.trim())),
+            None => (apikey.trim(), None),
+        };
+        textpool
+            .with_apikey(apikey, author)
+            .expect("The identity following an API key must be of the form 'Name <email>'")
+    });
+
+    let textpool = if let Some(bare_repository) = args.bare_repository.as_deref() {
+        if args.git {
+            panic!("--git can not be combined with --bare-repository");
+        }
+        textpool
+            .with_bare_repository(
+                std::path::Path::new(bare_repository),
+                &args.reference,
+                args.git_cache_size << 20,
+            )
+            .expect("Unable to open the bare git repository")
+    } else {
+        textpool
+    };
+
+    let textpool = if let Some(s3_endpoint) = args.s3_endpoint.as_deref() {
+        if args.git || args.bare_repository.is_some() {
+            panic!("--s3-endpoint can not be combined with --git or --bare-repository");
+        }
+        let storage = S3Storage::open(
+            s3_endpoint,
+            args.s3_bucket
+                .as_deref()
+                .expect("--s3-bucket is required with --s3-endpoint"),
+            &args.s3_region,
+            &args.s3_prefix,
+            &textpool.basedir().join(S3_CACHE_DIR),
+            args.s3_cache_size << 20,
+        )
+        .expect("Unable to access the S3 bucket");
+        textpool.with_storage(storage)
+    } else {
+        textpool
+    };
+
+    let textpool = if args.archives {
+        textpool
+            .with_archives(args.archive_cache_size << 20)
+            .expect("Unable to open the archive cache")
+    } else {
+        textpool
+    };
+
+    let textpool = if args.git {
+        textpool
+            .with_git(&args.git_author, args.git_cache_size << 20)
+            .expect("Unable to open or initialise a git repository in the base directory")
+    } else {
+        textpool
+    };
+
+    if args.inverted_index {
+        let text_ids = textpool.list("").expect("Unable to list texts");
+        info!("Loading inverted index for {} text(s)...", text_ids.len());
+        textpool.with_inverted_index(text_ids)
+    } else {
+        textpool
+    }
+}
+
 async fn shutdown_signal(textpool: Arc<TextPool>) {
     let ctrl_c = async {
         signal::ctrl_c()
@@ -208,659 +395,3 @@ async fn shutdown_signal(textpool: Arc<TextPool>) {
         }
     }
 }
-
-#[utoipa::path(
-    get,
-    path = "/",
-    responses(
-        (status = 200, body = [String], description = "Returns a simple list of all available texts (recursively)"),
-    )
-)]
-/// Returns all available texts, recursively
-async fn list_texts(
-    textpool: State<Arc<TextPool>>,
-    request: Request<Body>,
-) -> Result<ApiResponse, ApiError> {
-    let store_ids: Vec<String> =
-        file_index(textpool.basedir(), &format!(".{}", textpool.extension()));
-    match negotiate_content_type(request.headers(), &[CONTENT_TYPE_JSON]) {
-        Ok(CONTENT_TYPE_JSON) => {
-            let store_ids: Vec<serde_json::Value> =
-                store_ids.into_iter().map(|s| s.into()).collect();
-            Ok(ApiResponse::JsonList(store_ids))
-        }
-        _ => Err(ApiError::NotAcceptable(
-            "Accept header could not be satisfied (try application/json)",
-        )),
-    }
-}
-
-fn list_texts_subdir(
-    path: String,
-    textpool: State<Arc<TextPool>>,
-    request: Request<Body>,
-) -> Result<ApiResponse, ApiError> {
-    for component in path.split('/') {
-        if component.starts_with('.') {
-            return Err(ApiError::NotFound("Invalid path"));
-        }
-    }
-
-    let store_ids: Vec<String> = file_index(
-        textpool.basedir().join(path.as_str()).as_path(),
-        &format!(".{}", textpool.extension()),
-    );
-    match negotiate_content_type(request.headers(), &[CONTENT_TYPE_JSON]) {
-        Ok(CONTENT_TYPE_JSON) => {
-            let store_ids: Vec<serde_json::Value> =
-                store_ids.into_iter().map(|s| s.into()).collect();
-            Ok(ApiResponse::JsonList(store_ids))
-        }
-        _ => Err(ApiError::NotAcceptable(
-            "Accept header could not be satisfied (try application/json)",
-        )),
-    }
-}
-
-#[utoipa::path(
-    delete,
-    path = "/",
-    responses(
-        (status = 204, description = "Returned when successfully deleted"),
-        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or there is no authorization provided or it is rejected", content_type = "application/json")
-    )
-)]
-/// Deletes all texts, recursively
-async fn delete_all(
-    headers: HeaderMap,
-    textpool: State<Arc<TextPool>>,
-) -> Result<ApiResponse, ApiError> {
-    verify_auth(&**textpool, headers)?;
-    delete_subdir("", textpool)
-}
-
-#[utoipa::path(
-    post,
-    path = "/{*text_id}",
-    request_body( content_type = "text/plain", content = String),
-    params(
-        ("text_id" = String, Path, description = "The identifier of the text. It may contain zero or more path components."),
-    ),
-    responses(
-        (status = 201, description = "Returned when successfully created"),
-        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or the text already exists", content_type = "application/json")
-    )
-)]
-/// Create (upload) a new text, the text is transferred in the request body and must be valid UTF-8. If the text exists already, 403 will be returned
-async fn create_text(
-    Path(text_id): Path<String>,
-    headers: HeaderMap,
-    textpool: State<Arc<TextPool>>,
-    text: String,
-) -> Result<ApiResponse, ApiError> {
-    verify_auth(&**textpool, headers)?;
-    if textpool.new_text(&text_id, text, false)? {
-        Ok(ApiResponse::Created())
-    } else {
-        unreachable!("new_text without overwrite always returns true (or error)")
-    }
-}
-
-#[utoipa::path(
-    put,
-    path = "/{*text_id}",
-    request_body( content_type = "text/plain", content = String),
-    params(
-        ("text_id" = String, Path, description = "The identifier of the text. It may contain zero or more path components."),
-    ),
-    responses(
-        (status = 200, description = "Returned when successfully updated"),
-        (status = 201, description = "Returned when successfully newly created"),
-        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or the text already exists", content_type = "application/json")
-    )
-)]
-/// Create (upload) a new text, the text is transferred in the request body and must be valid UTF-8. If the text exists already, it will be overwritten.
-async fn create_text_overwrite(
-    Path(text_id): Path<String>,
-    headers: HeaderMap,
-    textpool: State<Arc<TextPool>>,
-    text: String,
-) -> Result<ApiResponse, ApiError> {
-    verify_auth(&**textpool, headers)?;
-    if textpool.new_text(&text_id, text, true)? {
-        Ok(ApiResponse::Created())
-    } else {
-        Ok(ApiResponse::Ok())
-    }
-}
-
-#[utoipa::path(
-    post,
-    path = "/api2/{text_id}",
-    request_body( content_type = "text/plain", content = String),
-    params(
-        ("text_id" = String, Path, description = "The identifier of the text. It may contain zero or more path components."),
-    ),
-    responses(
-        (status = 201, description = "Returned when successfully created"),
-        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only.", content_type = "application/json")
-    )
-)]
-/// Create (upload) a new text, the text is transferred in the request body and must be valid UTF-8. If the text exists already, 403 will be returned
-async fn create_text_api2(
-    Path(text_id): Path<String>,
-    textpool: State<Arc<TextPool>>,
-    text: String,
-) -> Result<ApiResponse, ApiError> {
-    textpool.new_text(&api2_decode_id(&text_id), text, false)?;
-    Ok(ApiResponse::Created())
-}
-
-#[utoipa::path(
-    put,
-    path = "/api2/{text_id}",
-    request_body( content_type = "text/plain", content = String),
-    params(
-        ("text_id" = String, Path, description = "The identifier of the text. It may contain zero or more path components."),
-    ),
-    responses(
-        (status = 200, description = "Returned when successfully updated"),
-        (status = 201, description = "Returned when successfully newly created"),
-        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or there is no authorization provided or it is rejected", content_type = "application/json")
-    )
-)]
-/// Create (upload) a new text, the text is transferred in the request body and must be valid UTF-8. If the text exists already, it will be overwritten.
-async fn create_text_overwrite_api2(
-    Path(text_id): Path<String>,
-    headers: HeaderMap,
-    textpool: State<Arc<TextPool>>,
-    text: String,
-) -> Result<ApiResponse, ApiError> {
-    verify_auth(&**textpool, headers)?;
-    if textpool.new_text(&api2_decode_id(&text_id), text, true)? {
-        Ok(ApiResponse::Created())
-    } else {
-        Ok(ApiResponse::Ok())
-    }
-}
-
-#[utoipa::path(
-    delete,
-    path = "/{*text_id}",
-    params(
-        ("text_id" = String, Path, description = "The identifier of the text. It may contain zero or more path components. Important note: If this ends with a trailing slash, the identifier is interpreted as a path and all texts in that path are removed (recursively)!"),
-    ),
-    responses(
-        (status = 204, description = "Returned when successfully deleted"),
-        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the text does not exist", content_type = "application/json"),
-        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or there is no authorization provided or it is rejected", content_type = "application/json")
-    )
-)]
-/// Permanently delete a text
-async fn delete_text(
-    Path(text_id): Path<String>,
-    headers: HeaderMap,
-    textpool: State<Arc<TextPool>>,
-) -> Result<ApiResponse, ApiError> {
-    verify_auth(&**textpool, headers)?;
-    if text_id.ends_with('/') {
-        //deletion of an entire subdir rather than a single text
-        delete_subdir(text_id.as_str(), textpool)
-    } else {
-        textpool.delete_text(&text_id)?;
-        Ok(ApiResponse::NoContent())
-    }
-}
-
-#[utoipa::path(
-    delete,
-    path = "/api2/{text_id}",
-    params(
-        ("text_id" = String, Path, description = "The identifier of the text. It may contain zero or more path components."),
-    ),
-    responses(
-        (status = 204, description = "Returned when successfully deleted"),
-        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the text does not exist", content_type = "application/json"),
-        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or there is no authorization provided or it is rejected", content_type = "application/json")
-    )
-)]
-/// Permanently delete a text
-async fn delete_text_api2(
-    Path(text_id): Path<String>,
-    headers: HeaderMap,
-    textpool: State<Arc<TextPool>>,
-) -> Result<ApiResponse, ApiError> {
-    verify_auth(&**textpool, headers)?;
-    textpool.delete_text(&api2_decode_id(&text_id))?;
-    Ok(ApiResponse::NoContent())
-}
-
-/// Verify API key if set
-fn verify_auth(textpool: &TextPool, headers: HeaderMap) -> Result<(), ApiError> {
-    if let Some(apikey) = textpool.apikey() {
-        let mut auth = false;
-        if let Some(authorization) = headers.get("Authorization") {
-            if let Ok(authorization) = authorization.to_str() {
-                if authorization.starts_with("Bearer ") {
-                    let token = &authorization[7..];
-                    auth = token == apikey.trim();
-                }
-            }
-        } else {
-            return Err(ApiError::PermissionDenied("Authorization required"));
-        }
-        if !auth {
-            return Err(ApiError::PermissionDenied("Invalid authorization"));
-        }
-    }
-    Ok(())
-}
-enum Range {
-    Chars(isize, isize),
-    Lines(isize, isize),
-}
-
-fn get_text_chars(
-    textpool: Arc<TextPool>,
-    text_id: &str,
-    range: Range,
-    force_no_stream: bool,
-) -> Result<ApiResponse, ApiError> {
-    // get absolute start and end character positions
-    let (begin, end) = match range {
-        Range::Chars(begin, end) => textpool.absolute_pos(text_id, begin, end),
-        Range::Lines(begin, end) => textpool.absolute_line_pos(text_id, begin, end),
-    }?;
-
-    // asked range is smaller than threshold, just send as non-streamed response
-    if force_no_stream || (end - begin) < STREAM_THRESHOLD {
-        return match range {
-            Range::Chars(begin, end) => textpool.map(text_id, begin, end, |text| {
-                Ok(ApiResponse::Text(text.to_string()))
-            }),
-            Range::Lines(begin, end) => textpool.map_lines(text_id, begin, end, |text| {
-                Ok(ApiResponse::Text(text.to_string()))
-            }),
-        };
-    }
-
-    // this check doesn't happen in TextFrame::absolute_pos
-    if end < begin {
-        return Err(ApiError::ParameterError(
-            "The range you requested has a negative length",
-        ));
-    }
-
-    let char_count = (end - begin) as u64;
-
-    let nb_chunks = char_count.div_ceil(CHUNK_SIZE as u64);
-
-    let textpool = Arc::clone(&textpool);
-    let text_id = text_id.to_string();
-
-    let textstream = futures::stream::iter(0..nb_chunks).then(move |chunk| {
-        let textpool = Arc::clone(&textpool);
-        let text_id = text_id.clone();
-        async move {
-            // put textpool operation in a tokio blocking task
-            // in order to not block this tokio executor on slow IO operations
-            let chunk_data = tokio::task::block_in_place(|| {
-                let begin_chunk = (begin as u64 + chunk * CHUNK_SIZE as u64) as isize;
-                let end_chunk =
-                    (begin as u64 + ((chunk + 1) * CHUNK_SIZE as u64)).min(end as u64) as isize;
-
-                textpool
-                    .map(
-                        &text_id,
-                        begin_chunk,
-                        end_chunk,
-                        |text| Ok(text.to_string()),
-                    )
-                    .unwrap()
-            });
-            Ok::<_, Infallible>(chunk_data)
-        }
-    });
-
-    let body = Body::from_stream(textstream);
-
-    Ok(ApiResponse::TextStream(body))
-}
-
-fn parse_range(input: &str) -> Result<(isize, isize), ApiError> {
-    let mut fields = input.splitn(2, ',');
-
-    let begin = fields.next().unwrap_or("");
-    let end = fields.next().unwrap_or("");
-
-    let parse = |field: &str| {
-        if field.is_empty() {
-            Ok(0)
-        } else {
-            field
-                .parse()
-                .map_err(|_| ApiError::ParameterError("range parameter must be an integer"))
-        }
-    };
-
-    let begin = parse(begin)?;
-    let end = parse(end)?;
-
-    Ok((begin, end))
-}
-
-#[derive(Deserialize)]
-struct TextParams {
-    begin: Option<isize>,
-    end: Option<isize>,
-    char: Option<String>,
-    line: Option<String>,
-    length: Option<usize>,
-    md5: Option<String>,
-}
-
-#[utoipa::path(
-    get,
-    path = "/{*text_id}",
-    params(
-        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk. It may contain zero or more path components."),
-        ("begin" = Option<isize>, Query, description = "An integer indicating the begin offset in unicode points (0-indexed). This may be a negative integer for end-aligned cursors. The default value is 0."),
-        ("end" = Option<isize>, Query, description = "An integer indicating the non-inclusive end offset in unicode points (0-indexed). This may be a negative integer for end-aligned cursors and `0` for actual end. The default value is 0."),
-        ("char" = Option<isize>, Query, description = "Character range specification conforming to RFC5147, begin and end values are separated by a comma, 0-indexed, end is non-inclusive"),
-        ("line" = Option<isize>, Query, description = "Line range specification conforming to RFC5147, begin and end values are separated by a comma, 0-indexed (first line is 0!), end is non-inclusive"),
-        ("length" = Option<usize>, Query, description = "Optional length validity check (as in RFC5147, an encoding parameter is NOT supported though as textsurf only does UTF-8 anyway). This is not an alternative for `end`. If the check fails, a 403 will be returned."),
-        ("md5" = Option<String>, Query, description = "MD5 checksum for the text that is being referenced (as defined by RFC5147). If the check fails, a 403 will be returned"),
-    ),
-    responses(
-        (status = 200, description = "The text",content(
-            (String = "text/plain"),
-        )),
-        (status = 403, body = apidocs::ApiError, description = "Return when an explicitly passed check (length,md5) fails", content_type = "application/json"),
-        (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered", content_type = "application/json"),
-        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
-    )
-)]
-/// Returns a text given a text identifier. Returns either a full text or a portion thereof if offsets were specified.
-/// If a path is specified (trailing slash), this returns an index of all files under that path instead (as JSON)
-async fn get_text(
-    Path(text_id): Path<String>,
-    Query(params): Query<TextParams>,
-    State(textpool): State<Arc<TextPool>>,
-    request: Request<Body>,
-) -> Result<ApiResponse, ApiError> {
-    if text_id.ends_with('/') {
-        //request for index rather than a text
-        return list_texts_subdir(text_id, State(textpool), request);
-    }
-
-    let force_no_stream = params.length.is_some() || params.md5.is_some();
-
-    let range = if let Some(char) = params.char {
-        let (begin, end) = parse_range(&char)?;
-        Range::Chars(begin, end)
-    } else if let Some(line) = params.line {
-        let (begin, end) = parse_range(&line)?;
-        Range::Lines(begin, end)
-    } else {
-        // both begin and end default to 0 when they're not given
-        let begin = params.begin.unwrap_or(0);
-        let end = params.end.unwrap_or(0);
-        Range::Chars(begin, end)
-    };
-
-    let response = get_text_chars(textpool, &text_id, range, force_no_stream);
-
-    if let Ok(ApiResponse::Text(text)) = &response {
-        if let Some(length) = params.length {
-            if text.chars().count() != length {
-                return Err(ApiError::PermissionDenied("length check failed"));
-            }
-        }
-        if let Some(md5ref) = params.md5 {
-            let checksum = format!("{:x}", md5::compute(text.as_bytes()));
-            if checksum != md5ref {
-                return Err(ApiError::PermissionDenied("md5 check failed"));
-            }
-        }
-    }
-    response
-}
-
-#[utoipa::path(
-    post,
-    path = "/flush",
-    responses(
-        (status = 204, description = "Returned when successfully flushed all files"),
-        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the service is configured as read-only or there is no authorization provided or it is rejected", content_type = "application/json")
-    )
-)]
-/// Forcibly flushes all texts from memory; clearing the cache
-async fn flush(
-    headers: HeaderMap,
-    textpool: State<Arc<TextPool>>,
-) -> Result<ApiResponse, ApiError> {
-    verify_auth(&**textpool, headers)?;
-    let v = textpool.flush(true)?;
-    info!("Force-flushed {} text(s) on request", v.len());
-    Ok(ApiResponse::Ok())
-}
-
-#[utoipa::path(
-    get,
-    path = "/stat/{*text_id}",
-    params(
-        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk. It may contain zero or more path components."),
-    ),
-    responses(
-        (status = 200, description = "The text identifier",content(
-            (String = "text/plain"),
-        )),
-        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
-    )
-)]
-/// Returns metadata about a text. Returns a JSON response with fields "bytes" (filesize), "chars" (length in unicode characters), "checksum" (SHA-256) and "mtime" (unix timestamp for the file modification)
-async fn stat_text(
-    Path(text_id): Path<String>,
-    textpool: State<Arc<TextPool>>,
-) -> Result<ApiResponse, ApiError> {
-    textpool.stat(&text_id)
-}
-
-#[utoipa::path(
-    get,
-    path = "/api2/{text_id}/{region}",
-    params(
-        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk."),
-        ("region" = isize, Path, description = "A region specification in the form: `[{prefix:}]{begin},{end}`. Where begin is an integer indicating the begin offset in unicode points (0-indexed, this may be a negative integer for end-aligned cursors). End is integer indicating the non-inclusive end offset in unicode points (0-indexed). This may be a negative integer for end-aligned cursors and `0` for actual end. Prefix can be `char` or `line`, the former is the default if omitted entirely, in the latter case begin and end arguments will be interpreted to be lines rather than characters (0-indexed, non-inclusive end). Instead of a range, you can also use the keyword `full` to get the full text, which is identical to just omitted the region parameter entirely. Last, instead of a region you can also specify `info.json` to get metadata about a text."),
-    ),
-    responses(
-        (status = 200, description = "The requested text excerpt",content(
-            (String = "text/plain"),
-        )),
-        (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered", content_type = "application/json"),
-        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
-    )
-)]
-/// Returns a text or a text slice according to Text Referencing API 2
-async fn get_api2_with_region(
-    Path((text_id, region)): Path<(String, String)>,
-    State(textpool): State<Arc<TextPool>>,
-) -> Result<ApiResponse, ApiError> {
-    let text_id = api2_decode_id(text_id.as_str());
-
-    if region == "info.json" {
-        textpool.stat_api2(&text_id)
-    } else if let Some((prefix, remainder)) = region.split_once(':') {
-        let (begin, end) = get_text_slice_helper(remainder)?;
-        match prefix {
-            "char" => get_text_chars(textpool, &text_id, Range::Chars(begin, end), false),
-            "line" => get_text_chars(textpool, &text_id, Range::Lines(begin, end), false),
-            _ => Err(ApiError::ParameterError(
-                "invalid prefix for region parameter, must be 'char' or 'line'",
-            )),
-        }
-    } else {
-        let (begin, end) = get_text_slice_helper(region.as_str())?;
-
-        get_text_chars(textpool, &text_id, Range::Chars(begin, end), false)
-    }
-}
-
-#[utoipa::path(
-    get,
-    path = "/api2/{text_id}",
-    params(
-        ("text_id" = String, Path, description = "The identifier of the text. The identifier corresponds to the filename without extension on disk."),
-    ),
-    responses(
-        (status = 200, description = "The requested text excerpt",content(
-            (String = "text/plain"),
-        )),
-        (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered", content_type = "application/json"),
-        (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
-    )
-)]
-async fn get_api2_short(
-    Path(text_id): Path<String>,
-    State(textpool): State<Arc<TextPool>>,
-) -> Result<ApiResponse, ApiError> {
-    get_text_chars(textpool, &text_id, Range::Chars(0, 0), false)
-}
-
-/// Extra patch to allow pipes as a substitute for slashes in URLs
-fn api2_decode_id<'a>(s: &'a str) -> Cow<'a, str> {
-    if s.find('|').is_some() {
-        Cow::Owned(s.replace("|", "/"))
-    } else {
-        Cow::Borrowed(s)
-    }
-}
-
-fn file_index(dir: &std::path::Path, extension: &str) -> Vec<String> {
-    let mut store_ids: Vec<String> = Vec::new();
-    for entry in WalkDir::new(dir)
-        .follow_links(true)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        let filepath = entry
-            .path()
-            .strip_prefix(dir)
-            .expect("prefix should be there");
-        if !extension.is_empty() {
-            if let Some(filepath_s) = filepath.to_str() {
-                if let Some(pos) = filepath_s.find(extension) {
-                    store_ids.push(filepath_s[0..pos].to_string());
-                }
-            }
-        } else if filepath.is_file()
-            && filepath.extension().map(|x| x.as_encoded_bytes()) != Some(b"index")
-            && filepath
-                .to_str()
-                .map(|s| !s.starts_with("."))
-                .unwrap_or(false)
-        {
-            store_ids.push(
-                filepath
-                    .to_str()
-                    .expect("filename must be UTF-8")
-                    .to_string(),
-            );
-        }
-    }
-    store_ids
-}
-
-fn get_text_slice_helper(s: &str) -> Result<(isize, isize), ApiError> {
-    if s == "full" {
-        return Ok((0, 0));
-    }
-    if let Some((begin, end)) = s.split_once(',') {
-        let begin: isize = begin
-            .parse()
-            .map_err(|_| ApiError::ParameterError("region begin parameter must be an integer"))?;
-        let end: isize = end
-            .parse()
-            .map_err(|_| ApiError::ParameterError("region end parameter must be an integer"))?;
-        Ok((begin, end))
-    } else {
-        Err(ApiError::ParameterError(
-            "region parameter must have a comma to express a range",
-        ))
-    }
-}
-
-fn negotiate_content_type(
-    headers: &HeaderMap<HeaderValue>,
-    offer_types: &[&'static str],
-) -> Result<&'static str, ApiError> {
-    if let Some(accept_types) = headers.get(axum::http::header::ACCEPT) {
-        let mut match_accept_index = None;
-        let mut matching_offer = None;
-        for (i, accept_type) in accept_types
-            .to_str()
-            .map_err(|_| ApiError::NotAcceptable("Invalid Accept header"))
-            .unwrap_or(CONTENT_TYPE_JSON)
-            .split(",")
-            .enumerate()
-        {
-            let accept_type = accept_type.split(";").next().unwrap();
-            for offer_type in offer_types.iter() {
-                if *offer_type == accept_type
-                    || accept_type == "*/*"
-                        && (match_accept_index.is_none()
-                            || (match_accept_index.is_some() && match_accept_index.unwrap() > i))
-                {
-                    match_accept_index = Some(i);
-                    matching_offer = Some(*offer_type);
-                }
-            }
-        }
-        if let Some(matching_offer) = matching_offer {
-            Ok(matching_offer)
-        } else {
-            Err(ApiError::NotAcceptable("No matching content type on offer"))
-        }
-    } else {
-        Ok(offer_types[0])
-    }
-}
-
-fn delete_subdir(dir: &str, textpool: State<Arc<TextPool>>) -> Result<ApiResponse, ApiError> {
-    for component in dir.split('/') {
-        if component.starts_with('.') {
-            return Err(ApiError::NotFound("Invalid path"));
-        }
-    }
-
-    let dir: std::path::PathBuf = textpool.basedir().join(dir.trim_matches('/'));
-    let extension = format!(".{}", textpool.extension());
-
-    for entry in WalkDir::new(dir.as_path())
-        .follow_links(true)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        let filepath = entry
-            .path()
-            .strip_prefix(textpool.basedir())
-            .expect("prefix should be there");
-        if !extension.is_empty() {
-            if let Some(filepath_s) = filepath.to_str() {
-                if filepath_s.contains(&extension) {
-                    textpool.delete_text(filepath_s)?;
-                }
-            }
-        } else if filepath.is_file()
-            && filepath.extension().map(|x| x.as_encoded_bytes()) != Some(b"index")
-            && filepath
-                .to_str()
-                .map(|s| !s.starts_with("."))
-                .unwrap_or(false)
-        {
-            textpool.delete_text(filepath.to_str().expect("filename must be utf-8"))?;
-        }
-    }
-    //TODO: clean up remaining empty directories
-    Ok(ApiResponse::NoContent())
-}
@@ -0,0 +1,280 @@
+use crate::common::ApiError;
+use crate::storage::{is_cached_copy, FileCache, FileStat, Storage};
+use rusty_s3::actions::ListObjectsV2;
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, info};
+use ureq::http::{Response, StatusCode};
+use ureq::{Agent, Body};
+
+/// Name of the (hidden) directory under the base directory where full copies of objects are cached, for operations that need to scan a whole text (like search)
+pub const S3_CACHE_DIR: &str = ".s3cache";
+
+/// Default maximum total size (in bytes) of the full copies of objects that are kept in the cache
+pub const S3_CACHE_LIMIT: u64 = 1 << 30;
+
+/// How long the signed request URLs are valid
+const SIGNATURE_EXPIRY: Duration = Duration::from_secs(300);
+
+/// Storage backend that holds the texts in a bucket of an S3-compatible object store.
+/// Texts are read with HTTP range requests, so they never need to be downloaded as a whole for serving excerpts.
+/// Credentials are taken from the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` (and optionally `AWS_SESSION_TOKEN`) environment variables, without them requests are anonymous.
+pub struct S3Storage {
+    bucket: Bucket,
+    credentials: Option<Credentials>,
+    /// Prefix for all object keys, either empty or ending in a slash
+    prefix: String,
+    cache: FileCache,
+    agent: Agent,
+}
+
+impl S3Storage {
+    /// Connects to a bucket. The endpoint is the URL of the object store (e.g. `https://s3.eu-west-1.amazonaws.com` or `http://localhost:9000`), buckets are addressed path-style as that is what all S3-compatible stores support.
+    /// Objects are only considered if their key starts with the prefix (which may be empty).
+    /// At most `cache_limit` bytes of full copies of objects are kept in the cache directory.
+    pub fn open(
+        endpoint: &str,
+        bucket: &str,
+        region: &str,
+        prefix: &str,
+        cachedir: &Path,
+        cache_limit: u64,
+    ) -> Result<Self, ApiError> {
+        let endpoint = endpoint
+            .parse()
+            .map_err(|_| ApiError::ParameterError("Invalid S3 endpoint URL"))?;
+        let bucket = Bucket::new(
+            endpoint,
+            UrlStyle::Path,
+            bucket.to_string(),
+            region.to_string(),
+        )
+        .map_err(|_| ApiError::ParameterError("Invalid S3 endpoint URL"))?;
+        let prefix = prefix.trim_matches('/');
+        let storage = Self {
+            bucket,
+            credentials: Credentials::from_env(),
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", prefix)
+            },
+            cache: FileCache::open(cachedir, cache_limit, is_cached_copy)?,
+            agent: Agent::config_builder()
+                .http_status_as_error(false)
+                .build()
+                .into(),
+        };
+        //fail early if the bucket is not accessible
+        let mut action = storage.bucket.list_objects_v2(storage.credentials.as_ref());
+        action.with_max_keys(1);
+        check_status(
+            storage
+                .agent
+                .get(action.sign(SIGNATURE_EXPIRY).as_str())
+                .call(),
+        )?;
+        info!("Connected to bucket {}", storage.bucket.name());
+        Ok(storage)
+    }
+
+    fn key(&self, path: &str) -> String {
+        format!("{}{}", self.prefix, path)
+    }
+
+    /// Lists the objects under a directory, recursively or only those directly in it
+    fn list_objects(&self, dir: &str, recursive: bool) -> Result<Vec<String>, ApiError> {
+        let mut paths = Vec::new();
+        let prefix = self.key(dir);
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut action = self.bucket.list_objects_v2(self.credentials.as_ref());
+            action.with_prefix(prefix.as_str());
+            if !recursive {
+                //objects in subdirectories are then grouped under common prefixes, which are not listed
+                action.with_delimiter("/");
+            }
+            if let Some(continuation_token) = continuation_token.as_deref() {
+                action.with_continuation_token(continuation_token);
+            }
+            let mut response = check_status(
+                self.agent
+                    .get(action.sign(SIGNATURE_EXPIRY).as_str())
+                    .call(),
+            )?;
+            let body = response.body_mut().read_to_string().map_err(s3_error)?;
+            let result = ListObjectsV2::parse_response(&body).map_err(|e| {
+                error!("S3: invalid listing: {}", e);
+                ApiError::InternalError("invalid response from object storage")
+            })?;
+            paths.extend(
+                result
+                    .contents
+                    .into_iter()
+                    .filter_map(|object| {
+                        object
+                            .key
+                            .strip_prefix(self.prefix.as_str())
+                            .map(|path| path.to_string())
+                    })
+                    .filter(|path| {
+                        !path[dir.len()..]
+                            .split('/')
+                            .any(|component| component.starts_with('.'))
+                    }),
+            );
+            continuation_token = result.next_continuation_token;
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+        Ok(paths)
+    }
+}
+
+impl Storage for S3Storage {
+    fn list(&self, dir: &str) -> Result<Vec<String>, ApiError> {
+        self.list_objects(dir, true)
+    }
+
+    fn list_dir(&self, dir: &str) -> Result<Vec<String>, ApiError> {
+        self.list_objects(dir, false)
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<FileStat>, ApiError> {
+        let key = self.key(path);
+        let action = self.bucket.head_object(self.credentials.as_ref(), &key);
+        let response = self
+            .agent
+            .head(action.sign(SIGNATURE_EXPIRY).as_str())
+            .call()
+            .map_err(s3_error)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = check_status(Ok(response))?;
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or(ApiError::InternalError(
+                    "invalid response from object storage",
+                ))
+        };
+        Ok(Some(FileStat {
+            size: header("content-length")?
+                .parse()
+                .map_err(|_| ApiError::InternalError("invalid response from object storage"))?,
+            mtime: httpdate::parse_http_date(header("last-modified")?)
+                .map_err(|_| ApiError::InternalError("invalid response from object storage"))?,
+        }))
+    }
+
+    fn read_range(&self, path: &str, begin: u64, end: u64) -> Result<Vec<u8>, ApiError> {
+        if end <= begin {
+            return Ok(Vec::new());
+        }
+        let key = self.key(path);
+        let action = self.bucket.get_object(self.credentials.as_ref(), &key);
+        let mut response = check_status(
+            self.agent
+                .get(action.sign(SIGNATURE_EXPIRY).as_str())
+                .header("Range", format!("bytes={}-{}", begin, end - 1))
+                .call(),
+        )?;
+        //the limit is one more than needed, so a response that is too large is detected below rather than failing inside ureq
+        let data = response
+            .body_mut()
+            .with_config()
+            .limit(end - begin + 1)
+            .read_to_vec()
+            .map_err(s3_error)?;
+        if data.len() as u64 != end - begin {
+            return Err(ApiError::InternalError(
+                "object storage returned an incomplete range",
+            ));
+        }
+        Ok(data)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> Result<(), ApiError> {
+        let key = self.key(path);
+        let action = self.bucket.put_object(self.credentials.as_ref(), &key);
+        check_status(
+            self.agent
+                .put(action.sign(SIGNATURE_EXPIRY).as_str())
+                .send(data),
+        )?;
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> Result<(), ApiError> {
+        let key = self.key(path);
+        let action = self.bucket.delete_object(self.credentials.as_ref(), &key);
+        check_status(
+            self.agent
+                .delete(action.sign(SIGNATURE_EXPIRY).as_str())
+                .call(),
+        )?;
+        Ok(())
+    }
+
+    /// Returns the filename of a cached full copy of the object, downloading it if there is no up-to-date copy yet
+    fn local_path(&self, path: &str) -> Result<Option<PathBuf>, ApiError> {
+        let Some(stat) = self.stat(path)? else {
+            return Ok(None);
+        };
+        let cachefile = self.cache.dir().join(path);
+        if let Ok(metadata) = std::fs::metadata(&cachefile) {
+            if metadata.len() == stat.size
+                && metadata.modified().is_ok_and(|mtime| mtime >= stat.mtime)
+            {
+                self.cache.touch(&cachefile)?;
+                return Ok(Some(cachefile));
+            }
+        }
+        info!("Downloading {}", path);
+        let key = self.key(path);
+        let action = self.bucket.get_object(self.credentials.as_ref(), &key);
+        let response = check_status(
+            self.agent
+                .get(action.sign(SIGNATURE_EXPIRY).as_str())
+                .call(),
+        )?;
+        self.cache
+            .write(&cachefile, &mut response.into_body().into_reader())?;
+        Ok(Some(cachefile))
+    }
+
+    fn is_remote(&self, _path: &str) -> bool {
+        true
+    }
+
+    /// Removes the least recently used full copies of objects from the cache until it is within its size limit again
+    fn prune(&self, in_use: &dyn Fn(&Path) -> bool) -> Result<(), ApiError> {
+        self.cache.prune(in_use)
+    }
+}
+
+/// Turns responses with an unsuccessful status into errors
+fn check_status(response: Result<Response<Body>, ureq::Error>) -> Result<Response<Body>, ApiError> {
+    let response = response.map_err(s3_error)?;
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        StatusCode::NOT_FOUND => Err(ApiError::NotFound("No such object")),
+        StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => Err(ApiError::PermissionDenied(
+            "Access to object storage denied",
+        )),
+        status => {
+            error!("S3: unexpected status {}", status);
+            Err(ApiError::InternalError("object storage request failed"))
+        }
+    }
+}
+
+fn s3_error(e: ureq::Error) -> ApiError {
+    error!("S3: {}", e);
+    ApiError::InternalError("object storage request failed")
+}
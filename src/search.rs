@@ -0,0 +1,662 @@
+use crate::auxindex::Spans;
+use crate::common::ApiError;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Size of the chunks (in bytes) in which texts are read when searching, the deadline is checked after every chunk
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Lines longer than this (in bytes) are matched against regular expressions in pieces of this size, so a text without newlines need not be held in memory as a whole
+const MAX_LINE_SIZE: usize = 1024 * 1024;
+
+/// Limits that protect the service against expensive searches
+#[derive(Clone, Copy, Debug)]
+pub struct SearchLimits {
+    /// Maximum duration of a single search request
+    pub time: Duration,
+    /// Maximum number of matches returned by a single search request
+    pub max_hits: usize,
+}
+
+impl Default for SearchLimits {
+    fn default() -> Self {
+        Self {
+            time: Duration::from_secs(30),
+            max_hits: 10000,
+        }
+    }
+}
+
+/// A literal search query, matched as a sequence of unicode points using the Knuth-Morris-Pratt algorithm
+/// so a text can be searched in a single streaming pass without loading it into memory.
+pub struct Pattern {
+    chars: Vec<char>,
+    /// KMP failure function: length of the longest proper prefix of `chars[..=i]` that is also a suffix of it
+    failure: Vec<usize>,
+    case_insensitive: bool,
+    /// Number of newlines in the query, needed to determine the line a match starts on
+    newlines: usize,
+}
+
+/// A single match in a text
+#[derive(Serialize, Debug)]
+pub struct SearchMatch {
+    /// Begin offset in unicode points (0-indexed)
+    pub begin: usize,
+    /// End offset in unicode points (0-indexed, non-inclusive)
+    pub end: usize,
+    /// Line the match begins on (0-indexed)
+    pub line: usize,
+    /// The matched text (may differ from the query if matching case-insensitively)
+    pub text: String,
+}
+
+impl Pattern {
+    pub fn new(query: &str, case_insensitive: bool) -> Result<Self, ApiError> {
+        if query.is_empty() {
+            return Err(ApiError::ParameterError("search query may not be empty"));
+        }
+        let chars: Vec<char> = query.chars().map(|c| fold(c, case_insensitive)).collect();
+        let mut failure = vec![0; chars.len()];
+        let mut k = 0;
+        for i in 1..chars.len() {
+            while k > 0 && chars[i] != chars[k] {
+                k = failure[k - 1];
+            }
+            if chars[i] == chars[k] {
+                k += 1;
+            }
+            failure[i] = k;
+        }
+        Ok(Self {
+            newlines: chars.iter().filter(|c| **c == '\n').count(),
+            chars,
+            failure,
+            case_insensitive,
+        })
+    }
+
+    /// Searches a text file for non-overlapping occurrences of the pattern, stopping after `max` hits (if set).
+    /// If a token index is passed, only occurrences that consist of whole tokens are returned.
+    /// Returns the matches and whether the search was complete (it is stopped prematurely if the deadline passes).
+    pub fn search_file(
+        &self,
+        textpath: &Path,
+        max: Option<usize>,
+        deadline: Instant,
+        tokens: Option<&Spans>,
+    ) -> Result<(Vec<SearchMatch>, bool), ApiError> {
+        let mut matches = Vec::new();
+        if max == Some(0) {
+            return Ok((matches, true));
+        }
+        let complete = self.search_file_with(textpath, deadline, tokens, |m| {
+            matches.push(m);
+            max.is_none_or(|max| matches.len() < max)
+        })?;
+        //reaching the maximum number of hits also stops the search, but that does not make it incomplete
+        let complete = complete || max.is_some_and(|max| matches.len() >= max);
+        Ok((matches, complete))
+    }
+
+    /// Searches a text file for non-overlapping occurrences of the pattern, calling the closure for each one; it returns whether the search should continue.
+    /// If a token index is passed, only occurrences that consist of whole tokens are passed.
+    /// Returns `false` if the search was stopped prematurely, either by the closure or because the deadline passed.
+    pub fn search_file_with<F>(
+        &self,
+        textpath: &Path,
+        deadline: Instant,
+        tokens: Option<&Spans>,
+        mut f: F,
+    ) -> Result<bool, ApiError>
+    where
+        F: FnMut(SearchMatch) -> bool,
+    {
+        let mut state = SearchState::default();
+        read_chunks(textpath, deadline, |chunk| {
+            self.search_chunk(chunk, &mut state, |m| {
+                if tokens.is_none_or(|tokens| tokens.aligned(m.begin, m.end)) {
+                    f(m)
+                } else {
+                    true
+                }
+            })
+        })
+    }
+
+    /// Does the text match the pattern exactly (as opposed to containing it)?
+    pub fn is_match(&self, text: &str) -> bool {
+        let mut chars = self.chars.iter();
+        text.chars()
+            .all(|c| chars.next() == Some(&fold(c, self.case_insensitive)))
+            && chars.next().is_none()
+    }
+
+    /// Searches the next chunk of a text, the state carries partial matches over from the previous chunk.
+    /// The closure is called for each match and returns whether the search should continue, this returns `false` if it was stopped.
+    fn search_chunk(
+        &self,
+        chunk: &str,
+        state: &mut SearchState,
+        mut f: impl FnMut(SearchMatch) -> bool,
+    ) -> bool {
+        for c in chunk.chars() {
+            if state.window.len() == self.chars.len() {
+                state.window.pop_front();
+            }
+            state.window.push_back(c);
+            let folded = fold(c, self.case_insensitive);
+            while state.matched > 0 && folded != self.chars[state.matched] {
+                state.matched = self.failure[state.matched - 1];
+            }
+            if folded == self.chars[state.matched] {
+                state.matched += 1;
+            }
+            state.charpos += 1;
+            if c == '\n' {
+                state.linenr += 1;
+            }
+            if state.matched == self.chars.len() {
+                let found = SearchMatch {
+                    begin: state.charpos - state.matched,
+                    end: state.charpos,
+                    line: state.linenr - self.newlines,
+                    text: state.window.iter().collect(),
+                };
+                state.matched = 0;
+                if !f(found) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// The state of a literal search between chunks
+#[derive(Default)]
+struct SearchState {
+    /// Number of characters read so far
+    charpos: usize,
+    /// Number of newlines read so far
+    linenr: usize,
+    /// Number of pattern characters matched so far
+    matched: usize,
+    /// The last characters read (as many as the pattern is long), these make up the text of a match
+    window: VecDeque<char>,
+}
+
+/// Searches a text file for a regular expression, line by line (so matches never cross a line boundary; the newline itself is not part of the line that is matched against).
+/// Lines longer than `MAX_LINE_SIZE` are matched in pieces, so matches also do not cross the boundaries of those pieces.
+/// The closure is called for each match and returns whether the search should continue.
+/// Returns `false` if the search was stopped prematurely, either by the closure or because the deadline passed.
+pub fn regex_search_file<F>(
+    textpath: &Path,
+    regex: &Regex,
+    deadline: Instant,
+    mut f: F,
+) -> Result<bool, ApiError>
+where
+    F: FnMut(SearchMatch) -> bool,
+{
+    let mut line = String::new();
+    let mut linenr = 0;
+    let mut charpos = 0;
+    let mut stopped = false;
+    //matches a (piece of a) line and advances the offsets past it
+    let mut match_line = |line: &str, linenr: usize, charpos: &mut usize| -> bool {
+        //matches are returned in order, so we can count characters incrementally
+        let mut bytepos = 0;
+        let mut linecharpos = 0;
+        for m in regex.find_iter(line.trim_end_matches(['\n', '\r'])) {
+            if m.is_empty() {
+                continue;
+            }
+            linecharpos += line[bytepos..m.start()].chars().count();
+            let length = m.as_str().chars().count();
+            if !f(SearchMatch {
+                begin: *charpos + linecharpos,
+                end: *charpos + linecharpos + length,
+                line: linenr,
+                text: m.as_str().to_string(),
+            }) {
+                return false;
+            }
+            linecharpos += length;
+            bytepos = m.end();
+        }
+        *charpos += line.chars().count();
+        true
+    };
+    let complete = read_chunks(textpath, deadline, |mut chunk| {
+        while let Some(end) = chunk.find('\n') {
+            line.push_str(&chunk[..=end]);
+            chunk = &chunk[end + 1..];
+            if !match_line(&line, linenr, &mut charpos) {
+                stopped = true;
+                return false;
+            }
+            linenr += 1;
+            line.clear();
+        }
+        line.push_str(chunk);
+        if line.len() >= MAX_LINE_SIZE {
+            if !match_line(&line, linenr, &mut charpos) {
+                stopped = true;
+                return false;
+            }
+            line.clear();
+        }
+        true
+    })?;
+    if !complete || stopped {
+        return Ok(false);
+    }
+    Ok(line.is_empty() || match_line(&line, linenr, &mut charpos))
+}
+
+/// A match found by approximate search
+#[derive(Clone, Copy, Debug)]
+pub struct ApproximateMatch {
+    /// Begin offset in unicode points (0-indexed)
+    pub begin: usize,
+    /// End offset in unicode points (0-indexed, non-inclusive)
+    pub end: usize,
+    /// The number of edits (insertions, deletions, substitutions) needed to turn the match into the query
+    pub errors: usize,
+}
+
+/// Searches a text file for approximate occurrences of a query, allowing at most `max_errors` edits (Levenshtein distance).
+/// This uses Sellers' dynamic programming algorithm with Ukkonen's cut-off, in a single streaming pass.
+/// Of overlapping matches (ending at consecutive positions) only the best one is returned.
+/// Returns the matches and whether the search was complete (it is stopped prematurely if the deadline passes).
+pub fn approximate_search_file(
+    textpath: &Path,
+    query: &str,
+    max_errors: usize,
+    deadline: Instant,
+) -> Result<(Vec<ApproximateMatch>, bool), ApiError> {
+    let pattern: Vec<char> = query.chars().collect();
+    if pattern.is_empty() {
+        return Err(ApiError::ParameterError("search query may not be empty"));
+    }
+    let m = pattern.len();
+    let max_errors = max_errors.min(m);
+    //cost[i]: minimal edit distance between pattern[..i] and any text ending at the current position, start[i]: where that text begins
+    let mut cost: Vec<usize> = (0..=m).collect();
+    let mut start: Vec<usize> = vec![0; m + 1];
+    //last row with a cost within bounds, rows beyond it are not computed
+    let mut last_active = max_errors;
+    let mut matches: Vec<ApproximateMatch> = Vec::new();
+    //end of the last match, to detect runs of overlapping matches
+    let mut run_end: Option<usize> = None;
+    let mut charpos = 0;
+    let complete = read_chunks(textpath, deadline, |chunk| {
+        for c in chunk.chars() {
+            let mut diagonal = (cost[0], start[0]);
+            cost[0] = 0;
+            start[0] = charpos + 1;
+            let limit = (last_active + 1).min(m);
+            for i in 1..=limit {
+                let previous = if i <= last_active {
+                    (cost[i], start[i])
+                } else {
+                    (max_errors + 1, start[i])
+                };
+                let mut best = (diagonal.0 + (pattern[i - 1] != c) as usize, diagonal.1);
+                if previous.0 + 1 < best.0 {
+                    best = (previous.0 + 1, previous.1);
+                }
+                if cost[i - 1] + 1 < best.0 {
+                    best = (cost[i - 1] + 1, start[i - 1]);
+                }
+                diagonal = previous;
+                (cost[i], start[i]) = best;
+            }
+            last_active = limit;
+            while last_active > 0 && cost[last_active] > max_errors {
+                last_active -= 1;
+            }
+            charpos += 1;
+            if last_active == m {
+                let found = ApproximateMatch {
+                    begin: start[m],
+                    end: charpos,
+                    errors: cost[m],
+                };
+                if run_end == Some(charpos - 1) {
+                    //continues the previous match, keep the best one
+                    if let Some(previous) = matches.last_mut() {
+                        if found.errors < previous.errors {
+                            *previous = found;
+                        }
+                    }
+                } else {
+                    matches.push(found);
+                }
+                run_end = Some(charpos);
+            }
+        }
+        true
+    })?;
+    Ok((matches, complete))
+}
+
+/// Computes the edit distance (Levenshtein distance) between two strings, in unicode points
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let value = (diagonal + (ca != *cb) as usize)
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            diagonal = row[j + 1];
+            row[j + 1] = value;
+        }
+    }
+    row[b.len()]
+}
+
+/// Reads a text file in chunks of at most `CHUNK_SIZE` bytes (never splitting a character) and calls the closure for each chunk, the closure returns whether to continue.
+/// Returns `false` if reading was stopped before the end of the file, either by the closure or because the deadline passed.
+fn read_chunks<F>(textpath: &Path, deadline: Instant, mut f: F) -> Result<bool, ApiError>
+where
+    F: FnMut(&str) -> bool,
+{
+    let mut file = File::open(textpath)?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    //number of bytes in the buffer, the first ones may be carried over from the previous chunk (an incomplete character)
+    let mut filled = 0;
+    loop {
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        let n = file.read(&mut buffer[filled..])?;
+        if n == 0 {
+            if filled > 0 {
+                return Err(ApiError::InternalError("Text is not valid UTF-8"));
+            }
+            return Ok(true);
+        }
+        filled += n;
+        let valid = match std::str::from_utf8(&buffer[..filled]) {
+            Ok(chunk) => chunk.len(),
+            //an incomplete character at the end is completed by the next read
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return Err(ApiError::InternalError("Text is not valid UTF-8")),
+        };
+        let chunk = std::str::from_utf8(&buffer[..valid]).expect("chunk was validated");
+        if !f(chunk) {
+            return Ok(false);
+        }
+        buffer.copy_within(valid..filled, 0);
+        filled -= valid;
+    }
+}
+
+/// Folds a character for comparison, lowercases it if case-insensitive matching is requested
+/// (but only if that yields a single character, so offsets are not affected)
+fn fold(c: char, case_insensitive: bool) -> char {
+    if case_insensitive {
+        let mut lower = c.to_lowercase();
+        match (lower.next(), lower.next()) {
+            (Some(l), None) => l,
+            _ => c,
+        }
+    } else {
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Writes a text to a temporary file for a test
+    fn textfile(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "textsurf-search-{}-{}.txt",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, text).expect("writing test file");
+        path
+    }
+
+    fn later() -> Instant {
+        Instant::now() + Duration::from_secs(60)
+    }
+
+    fn search(
+        name: &str,
+        text: &str,
+        query: &str,
+        case_insensitive: bool,
+    ) -> Vec<(usize, usize, usize, String)> {
+        let path = textfile(name, text);
+        let pattern = Pattern::new(query, case_insensitive).expect("valid pattern");
+        let (matches, complete) = pattern
+            .search_file(&path, None, later(), None)
+            .expect("search");
+        std::fs::remove_file(path).ok();
+        assert!(complete);
+        matches
+            .into_iter()
+            .map(|m| (m.begin, m.end, m.line, m.text))
+            .collect()
+    }
+
+    #[test]
+    fn failure_function() {
+        assert_eq!(
+            Pattern::new("abab", false).unwrap().failure,
+            vec![0, 0, 1, 2]
+        );
+        assert_eq!(
+            Pattern::new("aabaaa", false).unwrap().failure,
+            vec![0, 1, 0, 1, 2, 2]
+        );
+    }
+
+    #[test]
+    fn empty_pattern() {
+        assert!(Pattern::new("", false).is_err());
+    }
+
+    #[test]
+    fn literal_with_partial_matches() {
+        //the partial match "abab" at 0 has to fall back via the failure function to find the one at 2
+        assert_eq!(
+            search("partial", "abababc ababc", "ababc", false),
+            vec![(2, 7, 0, "ababc".into()), (8, 13, 0, "ababc".into())]
+        );
+    }
+
+    #[test]
+    fn literal_non_overlapping() {
+        assert_eq!(
+            search("overlap", "aaaaa", "aa", false),
+            vec![(0, 2, 0, "aa".into()), (2, 4, 0, "aa".into())]
+        );
+    }
+
+    #[test]
+    fn literal_offsets_in_chars() {
+        assert_eq!(
+            search("unicode", "Дружба и дружба", "дружба", false),
+            vec![(9, 15, 0, "дружба".into())]
+        );
+    }
+
+    #[test]
+    fn literal_case_insensitive() {
+        assert_eq!(
+            search("case", "Hello hello HELLO", "hELLo", true),
+            vec![
+                (0, 5, 0, "Hello".into()),
+                (6, 11, 0, "hello".into()),
+                (12, 17, 0, "HELLO".into())
+            ]
+        );
+        assert!(search("nocase", "Hello", "hello", false).is_empty());
+    }
+
+    #[test]
+    fn literal_lines() {
+        assert_eq!(
+            search("lines", "one\ntwo\nthree two\n", "two", false),
+            vec![(4, 7, 1, "two".into()), (14, 17, 2, "two".into())]
+        );
+        //a match spanning lines reports the line it begins on
+        assert_eq!(
+            search("multiline", "one\ntwo\nthree", "two\nthr", false),
+            vec![(4, 11, 1, "two\nthr".into())]
+        );
+    }
+
+    #[test]
+    fn literal_across_chunks() {
+        //a multi-byte character and a match both straddle the boundary between the first two chunks
+        let mut text = "x".repeat(CHUNK_SIZE - 1);
+        text.push_str("ééé");
+        let begin = CHUNK_SIZE - 1;
+        assert_eq!(
+            search("chunks", &text, "xéé", false),
+            vec![(begin - 1, begin + 2, 0, "xéé".into())]
+        );
+    }
+
+    #[test]
+    fn literal_max_and_deadline() {
+        let path = textfile("limits", "a a a a");
+        let pattern = Pattern::new("a", false).unwrap();
+        let (matches, complete) = pattern.search_file(&path, Some(2), later(), None).unwrap();
+        assert_eq!(matches.len(), 2);
+        assert!(complete);
+        let (matches, complete) = pattern
+            .search_file(&path, None, Instant::now(), None)
+            .unwrap();
+        assert!(matches.is_empty());
+        assert!(!complete);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn regex_lines_and_chunks() {
+        let mut text = String::from("ab\ncab\n");
+        text.push_str(&"é".repeat(CHUNK_SIZE));
+        text.push_str("ab");
+        let path = textfile("regex", &text);
+        let regex = Regex::new("a?b").unwrap();
+        let mut matches = Vec::new();
+        let complete = regex_search_file(&path, &regex, later(), |m| {
+            matches.push((m.begin, m.end, m.line));
+            true
+        })
+        .unwrap();
+        std::fs::remove_file(path).ok();
+        assert!(complete);
+        assert_eq!(
+            matches,
+            vec![(0, 2, 0), (4, 6, 1), (7 + CHUNK_SIZE, 9 + CHUNK_SIZE, 2)]
+        );
+    }
+
+    fn approximate(
+        name: &str,
+        text: &str,
+        query: &str,
+        max_errors: usize,
+    ) -> Vec<(usize, usize, usize)> {
+        let path = textfile(name, text);
+        let (matches, complete) =
+            approximate_search_file(&path, query, max_errors, later()).expect("search");
+        std::fs::remove_file(path).ok();
+        assert!(complete);
+        matches
+            .into_iter()
+            .map(|m| (m.begin, m.end, m.errors))
+            .collect()
+    }
+
+    #[test]
+    fn approximate_exact() {
+        //the neighbouring matches with one error ("quic", "quick ") are part of the same run
+        assert_eq!(
+            approximate("approx-exact", "the quick brown fox", "quick", 1),
+            vec![(4, 9, 0)]
+        );
+    }
+
+    #[test]
+    fn approximate_edits() {
+        assert_eq!(
+            approximate("approx-substitution", "the quack brown fox", "quick", 1),
+            vec![(4, 9, 1)]
+        );
+        assert_eq!(
+            approximate("approx-insertion", "the quiick brown fox", "quick", 1),
+            vec![(4, 10, 1)]
+        );
+        assert_eq!(
+            approximate("approx-deletion", "the quck brown fox", "quick", 1),
+            vec![(4, 8, 1)]
+        );
+        assert_eq!(
+            approximate("approx-unicode", "été chxud", "chaud", 1),
+            vec![(4, 9, 1)]
+        );
+    }
+
+    #[test]
+    fn approximate_separate_runs() {
+        //runs of overlapping matches each yield their best match
+        assert_eq!(
+            approximate("approx-runs", "quick and quack", "quick", 1),
+            vec![(0, 5, 0), (10, 15, 1)]
+        );
+    }
+
+    #[test]
+    fn approximate_cut_off() {
+        //more edits than allowed
+        assert!(approximate("approx-cutoff", "the qaack brown fox", "quick", 1).is_empty());
+        assert_eq!(
+            approximate("approx-cutoff2", "the qaack brown fox", "quick", 2),
+            vec![(4, 9, 2)]
+        );
+        assert!(approximate("approx-none", "the quack brown fox", "quick", 0).is_empty());
+        assert!(approximate_search_file(Path::new("unused"), "", 1, later()).is_err());
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("abc", "abc"), 0);
+        assert_eq!(edit_distance("abc", "abd"), 1);
+        assert_eq!(edit_distance("abc", "abxc"), 1);
+        assert_eq!(edit_distance("abc", "ac"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("café", "cafe"), 1);
+    }
+
+    #[test]
+    fn fold_chars() {
+        assert_eq!(fold('A', true), 'a');
+        assert_eq!(fold('Д', true), 'д');
+        assert_eq!(fold('A', false), 'A');
+        assert_eq!(fold('a', true), 'a');
+        //lowercases to two characters, so it is left as is
+        assert_eq!(fold('İ', true), 'İ');
+    }
+}
@@ -0,0 +1,458 @@
+use crate::common::ApiError;
+use crate::textpool::remove_indices;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+use tracing::debug;
+use walkdir::WalkDir;
+
+/// Metadata of a stored file
+#[derive(Debug, Clone, Copy)]
+pub struct FileStat {
+    /// Size in bytes
+    pub size: u64,
+    /// Modification time
+    pub mtime: SystemTime,
+}
+
+/// A storage backend holds the text files. Files are addressed by paths relative to the root of the storage, with `/` as separator.
+/// The base directory of the [`TextPool`](crate::textpool::TextPool) is always on local disk and holds the indices and other internal data, for backends that don't keep files locally it also serves as a cache.
+pub trait Storage: Send + Sync {
+    /// Lists the paths of all files under a directory (either empty or ending in a slash), recursively.
+    /// Hidden files and directories below `dir` are not listed.
+    fn list(&self, dir: &str) -> Result<Vec<String>, ApiError>;
+
+    /// Lists the paths of the files directly in a directory (either empty or ending in a slash), not those in its subdirectories.
+    /// The default implementation filters the recursive listing, backends that can list a single directory more cheaply should override it.
+    fn list_dir(&self, dir: &str) -> Result<Vec<String>, ApiError> {
+        Ok(self
+            .list(dir)?
+            .into_iter()
+            .filter(|path| !path[dir.len()..].contains('/'))
+            .collect())
+    }
+
+    /// Returns the metadata of a file, or `None` if there is no such file
+    fn stat(&self, path: &str) -> Result<Option<FileStat>, ApiError>;
+
+    /// Reads a byte range (end is non-inclusive) of a file
+    fn read_range(&self, path: &str, begin: u64, end: u64) -> Result<Vec<u8>, ApiError>;
+
+    /// Writes a file, replacing it if it already exists
+    fn write(&self, path: &str, data: &[u8]) -> Result<(), ApiError>;
+
+    /// Deletes a file
+    fn delete(&self, path: &str) -> Result<(), ApiError>;
+
+    /// Moves a file to another path. The default implementation copies and deletes it.
+    fn rename(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let stat = self.stat(from)?.ok_or(ApiError::NotFound("No such file"))?;
+        self.write(to, &self.read_range(from, 0, stat.size)?)?;
+        self.delete(from)
+    }
+
+    /// Returns a file on local disk with the content of the file (needed for indexing and searching), or `None` if there is no such file.
+    /// Backends that don't keep files locally write a copy to a cache.
+    fn local_path(&self, path: &str) -> Result<Option<PathBuf>, ApiError>;
+
+    /// Whether a file is held remotely (or otherwise not as a plain local file), so reading it as a whole is expensive. Such texts are read with `read_range()` and locally (in the base directory) only their indices are kept,
+    /// along with an empty placeholder file of the same size and modification time. `local_path()` is only used when a whole text needs to be scanned, like for indexing or searching.
+    fn is_remote(&self, _path: &str) -> bool {
+        false
+    }
+
+    /// Picks up changes made outside of textsurf (if the backend can detect these). Returns the paths of all files that changed, were added or were removed since the last time.
+    fn refresh(&self) -> Result<Vec<String>, ApiError> {
+        Ok(Vec::new())
+    }
+
+    /// Removes cached copies of files (see `local_path()`) that are no longer needed, if the backend bounds the size of its cache.
+    /// Cached copies for which `in_use` returns true (texts that are currently loaded) are kept.
+    fn prune(&self, _in_use: &dyn Fn(&Path) -> bool) -> Result<(), ApiError> {
+        Ok(())
+    }
+}
+
+/// The default storage backend: plain files in a directory on local disk
+pub struct FilesystemStorage {
+    root: PathBuf,
+}
+
+impl FilesystemStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn filename(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+
+    /// Lists the files under a directory, recursively or only those directly in it
+    fn list_files(&self, dir: &str, recursive: bool) -> Result<Vec<String>, ApiError> {
+        let mut paths = Vec::new();
+        for entry in WalkDir::new(self.filename(dir))
+            .follow_links(true)
+            .max_depth(if recursive { usize::MAX } else { 1 })
+            .into_iter()
+            //hidden directories hold internal data (like prior revisions), these are never listed
+            .filter_entry(|e| {
+                e.depth() == 0 || e.file_name().as_encoded_bytes().first() != Some(&b'.')
+            })
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+        {
+            if let Some(path) = entry
+                .path()
+                .strip_prefix(&self.root)
+                .ok()
+                .and_then(|path| path.to_str())
+            {
+                paths.push(path.to_string());
+            }
+        }
+        Ok(paths)
+    }
+}
+
+impl Storage for FilesystemStorage {
+    fn list(&self, dir: &str) -> Result<Vec<String>, ApiError> {
+        self.list_files(dir, true)
+    }
+
+    fn list_dir(&self, dir: &str) -> Result<Vec<String>, ApiError> {
+        self.list_files(dir, false)
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<FileStat>, ApiError> {
+        match std::fs::metadata(self.filename(path)) {
+            Ok(metadata) if metadata.is_file() => Ok(Some(FileStat {
+                size: metadata.len(),
+                mtime: metadata.modified()?,
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn read_range(&self, path: &str, begin: u64, end: u64) -> Result<Vec<u8>, ApiError> {
+        read_file_range(&self.filename(path), begin, end)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> Result<(), ApiError> {
+        let filename = self.filename(path);
+        if let Some(parentdir) = filename.parent() {
+            std::fs::create_dir_all(parentdir)?;
+        }
+        let mut file = File::create(&filename)?;
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> Result<(), ApiError> {
+        std::fs::remove_file(self.filename(path))?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let filename = self.filename(to);
+        if let Some(parentdir) = filename.parent() {
+            std::fs::create_dir_all(parentdir)?;
+        }
+        //renaming retains the modification time, so indices remain valid
+        std::fs::rename(self.filename(from), filename)?;
+        Ok(())
+    }
+
+    fn local_path(&self, path: &str) -> Result<Option<PathBuf>, ApiError> {
+        let filename = self.filename(path);
+        Ok(filename.is_file().then_some(filename))
+    }
+}
+
+/// Reads a byte range from a file on local disk
+pub(crate) fn read_file_range(filename: &Path, begin: u64, end: u64) -> Result<Vec<u8>, ApiError> {
+    let mut buffer = vec![0; end.saturating_sub(begin) as usize];
+    let mut file = File::open(filename)?;
+    file.seek(SeekFrom::Start(begin))?;
+    file.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Is this the filename of a copy in a [`FileCache`], rather than of an index or a temporary file?
+pub(crate) fn is_cached_copy(filename: &Path) -> bool {
+    filename
+        .extension()
+        .is_none_or(|extension| extension != "index" && extension != "tmp")
+}
+
+/// A directory holding local copies of files (along with their indices) for backends that can not serve them from disk directly.
+/// The total size of the copies is bounded: [`Self::prune()`] removes the least recently used ones once it exceeds the limit.
+pub(crate) struct FileCache {
+    dir: PathBuf,
+    limit: u64,
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    /// Maps cached files to their size and their last use (in ticks of `clock`)
+    entries: HashMap<PathBuf, (u64, u64)>,
+    /// Total size of all cached files
+    total: u64,
+    clock: u64,
+}
+
+impl FileCache {
+    /// Opens the cache directory (it need not exist yet) and picks up the copies already in there (recursively), ordered by modification time.
+    /// Only files for which `is_cached` returns true are considered, so indices and temporary files are left out.
+    pub fn open(dir: &Path, limit: u64, is_cached: fn(&Path) -> bool) -> Result<Self, ApiError> {
+        let mut files: Vec<(SystemTime, PathBuf, u64)> = Vec::new();
+        if dir.is_dir() {
+            for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if metadata.is_file() && is_cached(entry.path()) {
+                    files.push((metadata.modified()?, entry.into_path(), metadata.len()));
+                }
+            }
+        }
+        files.sort();
+        let mut state = CacheState {
+            entries: HashMap::new(),
+            total: 0,
+            clock: 0,
+        };
+        for (_, filename, size) in files {
+            state.clock += 1;
+            state.total += size;
+            state.entries.insert(filename, (size, state.clock));
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            limit,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// The cache directory
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn state(&self) -> Result<MutexGuard<'_, CacheState>, ApiError> {
+        self.state
+            .lock()
+            .map_err(|_| ApiError::InternalError("Lock poisoned: file cache"))
+    }
+
+    /// Marks a cached file as used just now. Returns false if there is no such file.
+    pub fn touch(&self, filename: &Path) -> Result<bool, ApiError> {
+        let Ok(metadata) = std::fs::metadata(filename) else {
+            return Ok(false);
+        };
+        self.record(filename, metadata.len())?;
+        Ok(true)
+    }
+
+    /// Writes a file into the cache with the content read from `reader`, replacing any earlier copy
+    pub fn write(&self, filename: &Path, reader: &mut dyn Read) -> Result<(), ApiError> {
+        if let Some(parentdir) = filename.parent() {
+            std::fs::create_dir_all(parentdir)?;
+        }
+        //write to a temporary file first so a partially written file is never served,
+        //it has a unique name as other threads may be writing the same file at the same time
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut tmpfile = filename.as_os_str().to_owned();
+        tmpfile.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = File::create(&tmpfile)?;
+        let size = match std::io::copy(reader, &mut file) {
+            Ok(size) => size,
+            Err(e) => {
+                drop(file);
+                std::fs::remove_file(&tmpfile).ok();
+                return Err(e.into());
+            }
+        };
+        drop(file);
+        std::fs::rename(tmpfile, filename)?;
+        self.record(filename, size)
+    }
+
+    fn record(&self, filename: &Path, size: u64) -> Result<(), ApiError> {
+        let mut state = self.state()?;
+        state.clock += 1;
+        let entry = (size, state.clock);
+        if let Some((size, _)) = state.entries.insert(filename.to_path_buf(), entry) {
+            state.total -= size;
+        }
+        state.total += size;
+        Ok(())
+    }
+
+    /// Removes the least recently used files (and their indices) until the total size is within the limit again. Files for which `in_use` returns true are kept.
+    pub fn prune(&self, in_use: &dyn Fn(&Path) -> bool) -> Result<(), ApiError> {
+        let mut state = self.state()?;
+        if state.total <= self.limit {
+            return Ok(());
+        }
+        let mut entries: Vec<(u64, PathBuf, u64)> = state
+            .entries
+            .iter()
+            .map(|(filename, (size, last_use))| (*last_use, filename.clone(), *size))
+            .collect();
+        entries.sort();
+        for (_, filename, size) in entries {
+            if state.total <= self.limit {
+                break;
+            }
+            if in_use(&filename) {
+                continue;
+            }
+            debug!("Removing {} from the cache", filename.display());
+            if filename.exists() {
+                std::fs::remove_file(&filename)?;
+            }
+            remove_indices(&filename)?;
+            state.entries.remove(&filename);
+            state.total -= size;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory for a test
+    fn testdir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("textsurf-storage-{}-{}", std::process::id(), name));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).expect("creating test directory");
+        dir
+    }
+
+    /// Lists the names of all files in a directory (recursively), sorted
+    fn files(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| {
+                e.path()
+                    .strip_prefix(dir)
+                    .expect("file must be in directory")
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn filesystem_list_skips_hidden() {
+        let dir = testdir("list");
+        let storage = FilesystemStorage::new(&dir);
+        storage.write("a.txt", b"a").expect("writing");
+        storage.write("sub/b.txt", b"b").expect("writing");
+        storage.write(".versions/a@1.txt", b"a").expect("writing");
+        let mut paths = storage.list("").expect("listing");
+        paths.sort();
+        assert_eq!(paths, ["a.txt", "sub/b.txt"]);
+        assert_eq!(storage.list_dir("").expect("listing"), ["a.txt"]);
+        assert_eq!(storage.list_dir("sub").expect("listing"), ["sub/b.txt"]);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn filesystem_read_write() {
+        let dir = testdir("readwrite");
+        let storage = FilesystemStorage::new(&dir);
+        storage
+            .write("sub/a.txt", "één twee".as_bytes())
+            .expect("writing");
+        let stat = storage
+            .stat("sub/a.txt")
+            .expect("stat")
+            .expect("file must exist");
+        assert_eq!(stat.size, 10);
+        assert_eq!(
+            storage.read_range("sub/a.txt", 6, 10).expect("reading"),
+            b"twee"[..]
+        );
+        assert!(storage.read_range("sub/a.txt", 6, 11).is_err());
+        storage
+            .rename("sub/a.txt", "other/b.txt")
+            .expect("renaming");
+        assert!(storage.stat("sub/a.txt").expect("stat").is_none());
+        assert_eq!(
+            storage.local_path("other/b.txt").expect("local path"),
+            Some(dir.join("other/b.txt"))
+        );
+        storage.delete("other/b.txt").expect("deleting");
+        assert!(storage
+            .local_path("other/b.txt")
+            .expect("local path")
+            .is_none());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn cache_write_leaves_no_temporary_files() {
+        let dir = testdir("cachewrite");
+        let cache = FileCache::open(&dir, 1 << 20, is_cached_copy).expect("opening cache");
+        let filename = dir.join("sub/a.txt");
+        assert!(!cache.touch(&filename).expect("touching"));
+        cache
+            .write(&filename, &mut "content".as_bytes())
+            .expect("writing");
+        assert!(cache.touch(&filename).expect("touching"));
+        //a failed write leaves neither the file nor a temporary file behind
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("failing"))
+            }
+        }
+        assert!(cache.write(&dir.join("b.txt"), &mut Failing).is_err());
+        assert_eq!(files(&dir), ["sub/a.txt"]);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn cache_prune_least_recently_used() {
+        let dir = testdir("cacheprune");
+        let cache = FileCache::open(&dir, 20, is_cached_copy).expect("opening cache");
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            cache
+                .write(&dir.join(name), &mut [b'x'; 10].as_slice())
+                .expect("writing");
+        }
+        std::fs::write(dir.join("b.index"), "index").expect("writing index");
+        cache.touch(&dir.join("a.txt")).expect("touching");
+        cache.prune(&|_| false).expect("pruning");
+        //b was used least recently, its index goes along with it
+        assert_eq!(files(&dir), ["a.txt", "c.txt"]);
+        std::fs::write(dir.join("c.index"), "index").expect("writing index");
+        std::fs::write(dir.join("stale.1.2.tmp"), "tmp").expect("writing");
+
+        //the cache is picked up again when reopened, indices and temporary files are no cached copies
+        let cache = FileCache::open(&dir, 0, is_cached_copy).expect("opening cache");
+        cache
+            .prune(&|filename| filename.ends_with("c.txt"))
+            .expect("pruning");
+        assert_eq!(files(&dir), ["c.index", "c.txt", "stale.1.2.tmp"]);
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
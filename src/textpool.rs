@@ -1,30 +1,387 @@
+use crate::archive::{ArchiveStorage, ARCHIVE_CACHE_DIR};
+use crate::auxindex::{self, Alignment, AuxIndex, Checksum, Spans, AUX_INDEX_KINDS, CHECKSUM_KIND};
 use crate::common::{ApiError, ApiResponse};
-use std::collections::HashMap;
+use crate::gitstore::{parse_identity, BareRepository, GitStore, GIT_CACHE_DIR};
+use crate::invindex::InvertedIndex;
+use crate::search::{self, ApproximateMatch, Pattern, SearchLimits, SearchMatch};
+use crate::storage::{FileStat, FilesystemStorage, Storage};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
-use std::io::prelude::*;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::path::{Component, Path, PathBuf};
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use textframe::{TextFile, TextFileMode};
-use tracing::info;
+use tracing::{error, info};
 
 const WAIT_INTERVAL: Duration = Duration::from_millis(100);
 
+/// Name of the (hidden) directory under the base directory where prior revisions of texts are stored
+pub const VERSIONS_DIR: &str = ".versions";
+
+/// Name of the (hidden) directory under the base directory that holds the placeholders and indices of remote texts, see [`Storage::is_remote()`]
+pub const REMOTE_DIR: &str = ".remote";
+
+/// Number of locks that serialise modifications of texts, texts share a lock based on a hash of their path (see `TextPool::write_lock()`)
+const WRITE_LOCKS: usize = 64;
+
+/// Maximum total size (in bytes) of the byte ranges of a remote text that are kept in memory, beyond this the least recently used ranges are evicted
+const MAX_REMOTE_FRAMES_SIZE: usize = 1 << 24;
+
+/// Maximum number of byte ranges of a remote text that are kept in memory, beyond this the least recently used ranges are evicted
+const MAX_REMOTE_FRAMES: usize = 256;
+
 #[derive(Clone)]
 pub struct State {
     last_access: Duration,
     loading: bool,
 }
 
+/// A range in a text. Begin and end may be negative to count from the end of the text, and an end of 0 denotes the end of the text.
+#[derive(Debug, Clone, Copy)]
+pub enum Range {
+    /// Range in unicode points
+    Chars(isize, isize),
+    /// Range in lines (requires a line index)
+    Lines(isize, isize),
+    /// Range in UTF-16 code units (as used by JavaScript and therefore by many web clients)
+    Utf16(isize, isize),
+    /// Range in extended grapheme clusters (user-perceived characters)
+    Graphemes(isize, isize),
+    /// Range in tokens (words and punctuation)
+    Tokens(isize, isize),
+    /// Range in paragraphs (separated by blank lines)
+    Paragraphs(isize, isize),
+    /// Range in pages (separated by form feeds)
+    Pages(isize, isize),
+}
+
+impl Range {
+    /// Returns a range given the name of its unit (as used in query parameters and region prefixes)
+    pub fn from_unit(unit: &str, begin: isize, end: isize) -> Option<Self> {
+        match unit {
+            "char" => Some(Self::Chars(begin, end)),
+            "line" => Some(Self::Lines(begin, end)),
+            "utf16" => Some(Self::Utf16(begin, end)),
+            "grapheme" => Some(Self::Graphemes(begin, end)),
+            "token" | "word" => Some(Self::Tokens(begin, end)),
+            "para" => Some(Self::Paragraphs(begin, end)),
+            "page" => Some(Self::Pages(begin, end)),
+            _ => None,
+        }
+    }
+}
+
+/// Units in which the size of a context window around a selection can be expressed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContextUnit {
+    Chars,
+    Tokens,
+    /// Lines of context; the lines holding the begin and end of the selection are always completed
+    Lines,
+}
+
+impl ContextUnit {
+    /// Parses the name of a context unit: `char`, `word` (or its alias `token`, as in token ranges) or `line`
+    pub fn from_unit(unit: &str) -> Option<Self> {
+        match unit {
+            "char" => Some(Self::Chars),
+            "token" | "word" => Some(Self::Tokens),
+            "line" => Some(Self::Lines),
+            _ => None,
+        }
+    }
+}
+
+/// A loaded text along with its auxiliary indices, which are only loaded (or computed) when first needed.
+pub struct Text {
+    textfile: TextFile,
+    paragraphs: Option<Spans>,
+    pages: Option<Spans>,
+    utf16: Option<Alignment>,
+    graphemes: Option<Alignment>,
+    tokens: Option<Spans>,
+    remote: Option<Remote>,
+}
+
+/// The content of a text held by a remote storage backend (see [`Storage::is_remote()`]).
+/// The textframe index is used to translate offsets to byte ranges, which are then fetched and kept in memory.
+struct Remote {
+    storage: Arc<dyn Storage>,
+    path: String,
+    /// Fetched byte ranges (begin offset and text), the most recently used last
+    frames: VecDeque<(usize, String)>,
+    /// Total size of the fetched byte ranges
+    frames_size: usize,
+}
+
+impl Remote {
+    fn new(storage: Arc<dyn Storage>, path: &str) -> Self {
+        Self {
+            storage,
+            path: path.to_string(),
+            frames: VecDeque::new(),
+            frames_size: 0,
+        }
+    }
+
+    /// Returns a byte range of the text, it is fetched from storage unless it is covered by a range that was fetched earlier.
+    /// Fetched ranges are kept in memory up to [`MAX_REMOTE_FRAMES_SIZE`] bytes and [`MAX_REMOTE_FRAMES`] ranges, beyond that the least recently used ones are evicted.
+    fn get_or_fetch(&mut self, beginbyte: usize, endbyte: usize) -> Result<&str, ApiError> {
+        match self
+            .frames
+            .iter()
+            .position(|(begin, text)| *begin <= beginbyte && begin + text.len() >= endbyte)
+        {
+            Some(index) => {
+                let frame = self.frames.remove(index).expect("frame must exist");
+                self.frames.push_back(frame);
+            }
+            None => {
+                let data = self
+                    .storage
+                    .read_range(&self.path, beginbyte as u64, endbyte as u64)?;
+                let text = String::from_utf8(data)
+                    .map_err(|e| ApiError::TextError(textframe::Error::Utf8Error(e)))?;
+                while self.frames.len() >= MAX_REMOTE_FRAMES
+                    || (!self.frames.is_empty()
+                        && self.frames_size + text.len() > MAX_REMOTE_FRAMES_SIZE)
+                {
+                    if let Some((_, evicted)) = self.frames.pop_front() {
+                        self.frames_size -= evicted.len();
+                    }
+                }
+                self.frames_size += text.len();
+                self.frames.push_back((beginbyte, text));
+            }
+        }
+        let (begin, text) = self.frames.back().expect("frame must exist");
+        Ok(&text[beginbyte - begin..endbyte - begin])
+    }
+}
+
+impl Text {
+    fn new(textfile: TextFile, remote: Option<Remote>) -> Self {
+        Self {
+            textfile,
+            paragraphs: None,
+            pages: None,
+            utf16: None,
+            graphemes: None,
+            tokens: None,
+            remote,
+        }
+    }
+
+    pub fn textfile(&self) -> &TextFile {
+        &self.textfile
+    }
+
+    /// Returns a text fragment by character offsets (as [`TextFile::get_or_load()`]), it is loaded from storage if it is not in memory yet
+    pub fn get_or_load(&mut self, begin: isize, end: isize) -> Result<&str, ApiError> {
+        if let Some(remote) = self.remote.as_mut() {
+            let (begin, end) = self.textfile.absolute_pos(begin, end)?;
+            remote.get_or_fetch(
+                self.textfile.chars_to_bytes(begin)?,
+                self.textfile.chars_to_bytes(end)?,
+            )
+        } else {
+            Ok(self.textfile.get_or_load(begin, end)?)
+        }
+    }
+
+    /// Returns a text fragment by lines (as [`TextFile::get_or_load_lines()`]), it is loaded from storage if it is not in memory yet
+    pub fn get_or_load_lines(&mut self, begin: isize, end: isize) -> Result<&str, ApiError> {
+        if let Some(remote) = self.remote.as_mut() {
+            let beginbyte = self.textfile.line_to_bytes(begin)?;
+            let endbyte = if end == 0 {
+                self.textfile.len_utf8()
+            } else {
+                self.textfile.line_to_bytes(end)?
+            };
+            remote.get_or_fetch(beginbyte, endbyte)
+        } else {
+            Ok(self.textfile.get_or_load_lines(begin, end)?)
+        }
+    }
+
+    /// Returns the alignment between unicode points and UTF-16 code units
+    pub fn utf16(&mut self) -> Result<&Alignment, ApiError> {
+        if self.utf16.is_none() {
+            self.utf16 = Some(auxindex::load_or_build(
+                self.textfile.path(),
+                "utf16",
+                |textpath| Alignment::new_utf16(&source(self.remote.as_ref(), textpath)?),
+            )?);
+        }
+        Ok(self.utf16.as_ref().expect("alignment must exist"))
+    }
+
+    /// Returns the alignment between unicode points and grapheme clusters
+    pub fn graphemes(&mut self) -> Result<&Alignment, ApiError> {
+        if self.graphemes.is_none() {
+            self.graphemes = Some(auxindex::load_or_build(
+                self.textfile.path(),
+                "grapheme",
+                |textpath| Alignment::new_graphemes(&source(self.remote.as_ref(), textpath)?),
+            )?);
+        }
+        Ok(self.graphemes.as_ref().expect("alignment must exist"))
+    }
+
+    /// Returns the index of tokens
+    pub fn tokens(&mut self) -> Result<&Spans, ApiError> {
+        if self.tokens.is_none() {
+            self.tokens = Some(auxindex::load_or_build(
+                self.textfile.path(),
+                "token",
+                |textpath| Spans::new_tokens(&source(self.remote.as_ref(), textpath)?),
+            )?);
+        }
+        Ok(self.tokens.as_ref().expect("spans must exist"))
+    }
+
+    /// Loads (or builds) the paragraph and page indices, these are built together in a single pass
+    fn load_paragraphs_and_pages(&mut self) -> Result<(), ApiError> {
+        if self.paragraphs.is_none() || self.pages.is_none() {
+            let (paragraphs, pages) =
+                auxindex::load_or_build_paragraphs_and_pages(self.textfile.path(), |textpath| {
+                    Spans::new_paragraphs_and_pages(&source(self.remote.as_ref(), textpath)?)
+                })?;
+            self.paragraphs = Some(paragraphs);
+            self.pages = Some(pages);
+        }
+        Ok(())
+    }
+
+    /// Returns the index of paragraphs
+    pub fn paragraphs(&mut self) -> Result<&Spans, ApiError> {
+        self.load_paragraphs_and_pages()?;
+        Ok(self.paragraphs.as_ref().expect("spans must exist"))
+    }
+
+    /// Returns the index of pages
+    pub fn pages(&mut self) -> Result<&Spans, ApiError> {
+        self.load_paragraphs_and_pages()?;
+        Ok(self.pages.as_ref().expect("spans must exist"))
+    }
+
+    /// Converts a (relative) range to absolute character offsets
+    pub(crate) fn absolute_range(&mut self, range: &Range) -> Result<(usize, usize), ApiError> {
+        match *range {
+            Range::Chars(begin, end) => Ok(self.textfile.absolute_pos(begin, end)?),
+            Range::Lines(begin, end) => Ok(self.textfile.absolute_line_pos(begin, end)?),
+            Range::Utf16(begin, end) => self.utf16()?.absolute_pos(begin, end),
+            Range::Graphemes(begin, end) => self.graphemes()?.absolute_pos(begin, end),
+            Range::Tokens(begin, end) => self.tokens()?.absolute_pos(begin, end),
+            Range::Paragraphs(begin, end) => self.paragraphs()?.absolute_pos(begin, end),
+            Range::Pages(begin, end) => self.pages()?.absolute_pos(begin, end),
+        }
+    }
+}
+
+/// A position in a text, expressed in all supported coordinate systems
+#[derive(Debug, Clone, Serialize)]
+pub struct Coordinates {
+    /// Offset in unicode points (0-indexed)
+    pub char: usize,
+    /// Offset in UTF-8 bytes (0-indexed)
+    pub byte: usize,
+    /// Offset in UTF-16 code units (0-indexed)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utf16: Option<usize>,
+    /// Offset in grapheme clusters (0-indexed), only available if the position is at the boundary of a grapheme cluster
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grapheme: Option<usize>,
+    /// Index of the token that holds this position (0-indexed), if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<usize>,
+    /// Index of the paragraph that holds this position (0-indexed), if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub para: Option<usize>,
+    /// Index of the page that holds this position (0-indexed), if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<usize>,
+    /// Line number (0-indexed), only available if there is a line index
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    /// Offset in unicode points relative to the start of the line (0-indexed), only available if there is a line index
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+/// The coordinate systems a position is expressed in by [`TextPool::coordinates()`], besides characters and bytes (which are always included).
+/// Some of these need an index that is built when first needed, so only the ones that are requested are computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoordinateUnits {
+    pub utf16: bool,
+    pub grapheme: bool,
+    pub token: bool,
+    pub para: bool,
+    pub page: bool,
+    /// Line and column
+    pub line: bool,
+}
+
+impl CoordinateUnits {
+    pub fn all() -> Self {
+        Self {
+            utf16: true,
+            grapheme: true,
+            token: true,
+            para: true,
+            page: true,
+            line: true,
+        }
+    }
+
+    /// Parses a comma-separated list of units (`char` and `byte` are accepted but implied)
+    pub fn parse(s: &str) -> Result<Self, ApiError> {
+        let mut units = Self {
+            utf16: false,
+            grapheme: false,
+            token: false,
+            para: false,
+            page: false,
+            line: false,
+        };
+        for unit in s.split(',') {
+            match unit.trim() {
+                "char" | "byte" => {}
+                "utf16" => units.utf16 = true,
+                "grapheme" => units.grapheme = true,
+                "token" => units.token = true,
+                "para" => units.para = true,
+                "page" => units.page = true,
+                "line" | "column" => units.line = true,
+                _ => {
+                    return Err(ApiError::ParameterError(
+                        "units must be a comma-separated list of char, byte, utf16, grapheme, token, para, page, line or column",
+                    ))
+                }
+            }
+        }
+        Ok(units)
+    }
+}
+
 pub struct TextPool {
     basedir: PathBuf,
     extension: String,
     readonly: bool,
-    apikey: Option<String>,
+    /// API keys that grant write access, along with the identity (`Name <email>`) of their holder, if known
+    apikeys: Vec<(String, Option<String>)>,
     lines: bool,
     unload_time: u64,
-    texts: RwLock<HashMap<String, Arc<RwLock<TextFile>>>>, //the extra Arc allows us to drop the lock earlier
+    texts: RwLock<HashMap<String, Arc<RwLock<Text>>>>, //the extra Arc allows us to drop the lock earlier
     states: RwLock<HashMap<String, State>>,
+    search_limits: SearchLimits,
+    invindex: Option<InvertedIndex>,
+    git: Option<GitStore>,
+    storage: Arc<dyn Storage>,
+    write_locks: [Mutex<()>; WRITE_LOCKS],
 }
 
 impl TextPool {
@@ -41,6 +398,7 @@ impl TextPool {
             Err("Base directory must exist")
         } else {
             Ok(Self {
+                storage: Arc::new(FilesystemStorage::new(&basedir)),
                 basedir,
                 extension: extension.into(),
                 texts: HashMap::new().into(),
@@ -48,7 +406,14 @@ impl TextPool {
                 lines,
                 unload_time,
                 readonly,
-                apikey,
+                apikeys: apikey
+                    .map(|apikey| (apikey.trim().to_string(), None))
+                    .into_iter()
+                    .collect(),
+                search_limits: SearchLimits::default(),
+                invindex: None,
+                git: None,
+                write_locks: std::array::from_fn(|_| Mutex::new(())),
             })
         }
     }
@@ -57,49 +422,218 @@ impl TextPool {
         self.basedir.as_path()
     }
 
+    /// The file extension of the texts (without the dot), it is not part of their identifiers. If empty, identifiers are plain filenames.
     pub fn extension(&self) -> &str {
         self.extension.as_str()
     }
 
+    /// Returns the first API key, if any
     pub fn apikey(&self) -> Option<&str> {
-        self.apikey.as_deref()
+        self.apikeys.first().map(|(apikey, _)| apikey.as_str())
+    }
+
+    /// Adds an API key that grants write access. If the identity of its holder (`Name <email>`) is given, it is used as the author of git commits for writes with this key.
+    pub fn with_apikey(
+        mut self,
+        apikey: impl Into<String>,
+        author: Option<&str>,
+    ) -> Result<Self, ApiError> {
+        if let Some(author) = author {
+            if parse_identity(author).is_none() {
+                return Err(ApiError::ParameterError(
+                    "git author must be of the form 'Name <email>'",
+                ));
+            }
+        }
+        self.apikeys.push((
+            apikey.into().trim().to_string(),
+            author.map(|author| author.to_string()),
+        ));
+        Ok(self)
+    }
+
+    /// Checks the token a writer presented against the API keys (if any are configured, otherwise anyone may write).
+    /// Returns the identity of the holder of the key, if known.
+    pub fn authorize(&self, token: Option<&str>) -> Result<Option<&str>, ApiError> {
+        if self.apikeys.is_empty() {
+            return Ok(None);
+        }
+        let token = token.ok_or(ApiError::PermissionDenied("Authorization required"))?;
+        self.apikeys
+            .iter()
+            .find(|(apikey, _)| apikey == token)
+            .map(|(_, author)| author.as_deref())
+            .ok_or(ApiError::PermissionDenied("Invalid authorization"))
+    }
+
+    pub fn search_limits(&self) -> SearchLimits {
+        self.search_limits
+    }
+
+    /// Sets the limits for search requests
+    pub fn with_search_limits(mut self, search_limits: SearchLimits) -> Self {
+        self.search_limits = search_limits;
+        self
+    }
+
+    /// Enables the inverted index, and loads (or builds) it for the given texts.
+    /// Texts that fail to index are logged and skipped.
+    pub fn with_inverted_index(mut self, text_ids: impl IntoIterator<Item = String>) -> Self {
+        let invindex = InvertedIndex::new(&self.basedir);
+        let mut count = 0;
+        for id in text_ids {
+            match self
+                .filename_from_id(&id)
+                .and_then(|filename| invindex.load(&id, &filename))
+            {
+                Ok(()) => count += 1,
+                Err(e) => error!("Failed to add {} to the inverted index: {:?}", id, e),
+            }
+        }
+        info!("Inverted index holds {} text(s)", count);
+        self.invindex = Some(invindex);
+        self
+    }
+
+    pub(crate) fn inverted_index(&self) -> Option<&InvertedIndex> {
+        self.invindex.as_ref()
+    }
+
+    /// Sets the storage backend that holds the texts (by default the texts are files in the base directory).
+    /// The base directory itself still holds the indices and other internal data.
+    pub fn with_storage(mut self, storage: impl Storage + 'static) -> Self {
+        self.storage = Arc::new(storage);
+        self
+    }
+
+    /// Serves the members of tar and zip archives in the storage as read-only texts (e.g. `archive.zip/path/file.txt`), without extracting them.
+    /// Members that need to be extracted (e.g. for search) are cached, up to `cache_limit` bytes (the least recently used are removed on [`Self::flush()`]).
+    /// This wraps the current storage backend, so it must be called after [`Self::with_storage()`].
+    pub fn with_archives(mut self, cache_limit: u64) -> Result<Self, ApiError> {
+        self.storage = Arc::new(ArchiveStorage::new(
+            self.storage.clone(),
+            &self.basedir.join(ARCHIVE_CACHE_DIR),
+            cache_limit,
+        )?);
+        Ok(self)
+    }
+
+    /// Enables git-backed storage: the base directory is a git repository (initialised if needed) and every write is committed.
+    /// The given author identity (`Name <email>`) is used for writes by anonymous writers or with an API key that has no identity of its own (see [`Self::with_apikey()`]).
+    /// Texts retrieved from git at a ref are cached, up to `cache_limit` bytes (the least recently used are removed on [`Self::flush()`]).
+    /// This requires the default (filesystem) storage.
+    pub fn with_git(mut self, author: &str, cache_limit: u64) -> Result<Self, ApiError> {
+        self.git = Some(GitStore::open(&self.basedir, author, cache_limit)?);
+        Ok(self)
+    }
+
+    /// Serves texts from a (bare) git repository at the given ref rather than from the base directory, which then only holds a cache of the texts and their indices (in [`GIT_CACHE_DIR`]).
+    /// At most `cache_limit` bytes of texts are cached (the least recently used are removed on [`Self::flush()`]). This makes the service read-only.
+    pub fn with_bare_repository(
+        mut self,
+        path: &Path,
+        reference: &str,
+        cache_limit: u64,
+    ) -> Result<Self, ApiError> {
+        let bare = BareRepository::open(
+            path,
+            reference,
+            &self.basedir.join(GIT_CACHE_DIR),
+            cache_limit,
+        )?;
+        self.readonly = true;
+        Ok(self.with_storage(bare))
+    }
+
+    /// Picks up changes made to the storage outside of textsurf, like a new commit for the ref of a bare repository. Texts that changed are unloaded, so their new content is served from now on.
+    pub fn refresh(&self) -> Result<(), ApiError> {
+        for path in self.storage.refresh()? {
+            let id = self.id_from_path(&path);
+            self.unload(&id)?;
+            if let Some(invindex) = self.invindex.as_ref() {
+                match self.storage.local_path(&path)? {
+                    Some(filename) => invindex.update(&id, &filename)?,
+                    None => invindex.remove(&id)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the identifiers of all texts under a directory (either empty or ending in a slash), relative to that directory
+    pub fn list(&self, dir: &str) -> Result<Vec<String>, ApiError> {
+        let extension = format!(".{}", self.extension);
+        Ok(self
+            .storage
+            .list(dir)?
+            .into_iter()
+            .filter_map(|path| {
+                let path = path.strip_prefix(dir)?;
+                if path.split('/').any(|component| component.starts_with('.')) {
+                    //hidden files hold internal data and are never listed
+                    None
+                } else if self.extension.is_empty() {
+                    (!path.ends_with(".index")).then(|| path.to_string())
+                } else {
+                    path.strip_suffix(extension.as_str())
+                        .map(|id| id.to_string())
+                }
+            })
+            .collect())
+    }
+
+    /// Returns the identifier under which a text can be loaded as it was at a given git ref (a branch, tag, commit or any other git revision expression).
+    /// This is an internal identifier of a read-only copy of the text (in the git cache), it is meant for loading only and is never served as such.
+    pub(crate) fn ref_id(&self, id: &str, reference: &str) -> Result<String, ApiError> {
+        let git = self
+            .git
+            .as_ref()
+            .ok_or(ApiError::ParameterError("git storage is not enabled"))?;
+        let path = self.path_from_id(id)?;
+        Ok(self.id_from_filename(&git.checkout(&path, reference)?))
     }
 
     pub fn map<F, T>(&self, id: &str, begin: isize, end: isize, f: F) -> Result<T, ApiError>
     where
         F: FnOnce(&str) -> Result<T, ApiError>,
     {
-        let _state = self.load(id)?;
-        if let Ok(texts) = self.texts.read() {
-            if let Some(textlock) = texts.get(id).cloned() {
-                drop(texts); //compiler should be able to infer this but better safe than sorry
-                if let Ok(mut textfile) = textlock.write() {
-                    //we need a write lock because we may load a new part of the text from disk here
-                    let text = textfile.get_or_load(begin, end)?; //this triggers a load from disk of a part of the text unless it's already covered by a part that was loaded earlier
-                    f(text)
-                } else {
-                    Err(ApiError::InternalError("Textfiles lock got poisoned")) //only happens if a thread holding a write lock panics
-                }
-            } else {
-                unreachable!("text file should have been  loaded in first line")
-            }
-        } else {
-            Err(ApiError::InternalError("Lock poisoned: textfiles"))
-        }
+        self.with_text(id, |text| {
+            let text = text.get_or_load(begin, end)?; //this triggers a load from disk of a part of the text unless it's already covered by a part that was loaded earlier
+            f(text)
+        })
     }
 
     pub fn map_lines<F, T>(&self, id: &str, begin: isize, end: isize, f: F) -> Result<T, ApiError>
     where
         F: FnOnce(&str) -> Result<T, ApiError>,
+    {
+        self.with_text(id, |text| {
+            let text = text.get_or_load_lines(begin, end)?; //this triggers a load from disk of a part of the text unless it's already covered by a part that was loaded earlier
+            f(text)
+        })
+    }
+
+    /// Checks whether a text exists (without loading it)
+    pub fn exists(&self, id: &str) -> bool {
+        self.path_from_id(id)
+            .and_then(|path| self.storage.stat(&path))
+            .map(|stat| stat.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Loads a text (once) and gives the closure mutable access to it.
+    /// This allows multiple operations on the same text without repeatedly going through the loading logic.
+    pub(crate) fn with_text<F, T>(&self, id: &str, f: F) -> Result<T, ApiError>
+    where
+        F: FnOnce(&mut Text) -> Result<T, ApiError>,
     {
         let _state = self.load(id)?;
         if let Ok(texts) = self.texts.read() {
             if let Some(textlock) = texts.get(id).cloned() {
                 drop(texts); //compiler should be able to infer this but better safe than sorry
-                if let Ok(mut textfile) = textlock.write() {
-                    //we need a write lock because we may load a new part of the text from disk here
-                    let text = textfile.get_or_load_lines(begin, end)?; //this triggers a load from disk of a part of the text unless it's already covered by a part that was loaded earlier
-                    f(text)
+                if let Ok(mut text) = textlock.write() {
+                    //we need a write lock because we may load a new part of the text (or an index) from disk here
+                    f(&mut text)
                 } else {
                     Err(ApiError::InternalError("Textfiles lock got poisoned")) //only happens if a thread holding a write lock panics
                 }
@@ -111,17 +645,45 @@ impl TextPool {
         }
     }
 
+    /// Builds (or refreshes, if outdated) the indices of a text ahead of time, so the first request for it need not wait for them.
+    /// This covers the indices that are needed to load a text at all; `aux` also builds those that are otherwise only computed when first needed (UTF-16 code units, grapheme clusters, tokens, paragraphs and pages).
+    /// The text is unloaded again afterwards.
+    pub fn build_indices(&self, id: &str, aux: bool) -> Result<(), ApiError> {
+        let result = self.with_text(id, |text| {
+            if aux {
+                text.utf16()?;
+                text.graphemes()?;
+                text.tokens()?;
+                text.paragraphs()?;
+            }
+            Ok(())
+        });
+        self.unload(id)?;
+        result
+    }
+
     pub fn stat(&self, id: &str) -> Result<ApiResponse, ApiError> {
         let _state = self.load(id)?;
         if let Ok(texts) = self.texts.read() {
             if let Some(textlock) = texts.get(id).cloned() {
                 drop(texts); //compiler should be able to infer this but better safe than sorry
-                if let Ok(textfile) = textlock.read() {
+                if let Ok(text) = textlock.read() {
+                    let textfile = text.textfile();
+                    let (chars, bytes, mtime, checksum) = (
+                        textfile.len() as u64,
+                        textfile.len_utf8() as u64,
+                        textfile.mtime(),
+                        textfile.checksum_digest(),
+                    );
+                    drop(text); //listing revisions loads those, so don't hold this lock
+                    let (version, revisions) = self.revision_list(id)?;
                     Ok(ApiResponse::Stat {
-                        chars: textfile.len() as u64,
-                        bytes: textfile.len_utf8() as u64,
-                        mtime: textfile.mtime(),
-                        checksum: textfile.checksum_digest(),
+                        chars,
+                        bytes,
+                        mtime,
+                        checksum,
+                        version,
+                        revisions,
                     })
                 } else {
                     Err(ApiError::InternalError("Textfiles lock got poisoned")) //only happens if a thread holding a write lock panics
@@ -139,12 +701,23 @@ impl TextPool {
         if let Ok(texts) = self.texts.read() {
             if let Some(textlock) = texts.get(id).cloned() {
                 drop(texts); //compiler should be able to infer this but better safe than sorry
-                if let Ok(textfile) = textlock.read() {
+                if let Ok(text) = textlock.read() {
+                    let textfile = text.textfile();
+                    let (chars, bytes, mtime, checksum) = (
+                        textfile.len() as u64,
+                        textfile.len_utf8() as u64,
+                        textfile.mtime(),
+                        textfile.checksum_digest(),
+                    );
+                    drop(text); //listing revisions loads those, so don't hold this lock
+                    let (version, revisions) = self.revision_list(id)?;
                     Ok(ApiResponse::StatLD {
-                        chars: textfile.len() as u64,
-                        bytes: textfile.len_utf8() as u64,
-                        mtime: textfile.mtime(),
-                        checksum: textfile.checksum_digest(),
+                        chars,
+                        bytes,
+                        mtime,
+                        checksum,
+                        version,
+                        revisions,
                     })
                 } else {
                     Err(ApiError::InternalError("Textfiles lock got poisoned")) //only happens if a thread holding a write lock panics
@@ -157,22 +730,50 @@ impl TextPool {
         }
     }
 
-    /// Create a new text. Returns true if it was newly created
+    /// Create a new text. Returns true if it was newly created.
+    /// If an existing text is overwritten, its previous content is kept as a revision.
     pub fn new_text(&self, id: &str, text: String, overwrite: bool) -> Result<bool, ApiError> {
+        self.new_text_as(id, text, overwrite, None)
+    }
+
+    /// Create a new text (as [`Self::new_text()`]) on behalf of a writer, whose identity (`Name <email>`) is the author of the git commit (if git-backed storage is enabled)
+    pub fn new_text_as(
+        &self,
+        id: &str,
+        text: String,
+        overwrite: bool,
+        author: Option<&str>,
+    ) -> Result<bool, ApiError> {
         if self.readonly {
             return Err(ApiError::PermissionDenied("Service is readonly"));
         }
-        let filename = self.filename_from_id(id)?; //this also does validation and security checks
-        let exists = filename.exists();
+        let path = self.path_from_id(id)?; //this also does validation and security checks
+        if is_read_only_copy(&path) {
+            return Err(ApiError::PermissionDenied(
+                "Revisions and texts retrieved from git can not be modified",
+            ));
+        }
+        let _lock = self.write_lock(&path)?;
+        let exists = self.storage.stat(&path)?.is_some();
         if exists && !overwrite {
             Err(ApiError::PermissionDenied("Text already exists"))
         } else {
+            if exists {
+                //keep the previous revision so references into it remain resolvable
+                self.unload(id)?;
+                self.archive_revision(&path)?;
+            }
             info!("Creating {}", id);
-            if let Some(parentdir) = filename.parent() {
-                std::fs::create_dir_all(parentdir)?;
+            self.storage.write(&path, text.as_bytes())?;
+            if let Some(invindex) = self.invindex.as_ref() {
+                if let Some(filename) = self.storage.local_path(&path)? {
+                    invindex.update(&self.id_from_path(&path), &filename)?;
+                }
+            }
+            if let Some(git) = self.git.as_ref() {
+                let action = if exists { "Update" } else { "Add" };
+                git.commit(&path, &format!("{} {}", action, id), author)?;
             }
-            let mut file = File::create(filename)?;
-            let _ = file.write(text.as_bytes())?;
             Ok(!exists)
         }
     }
@@ -215,10 +816,24 @@ impl TextPool {
                 None => break, //not loaded yet
             }
         }
-        let filename = self.filename_from_id(id)?;
-        if !filename.exists() {
-            return Err(ApiError::NotFound("No such text exists"));
-        }
+        let path = self.path_from_id(id)?;
+        //for remote texts the local file is only a placeholder, see Storage::is_remote()
+        let remote_stat = if self.storage.is_remote(&path) {
+            Some(
+                self.storage
+                    .stat(&path)?
+                    .ok_or(ApiError::NotFound("No such text exists"))?,
+            )
+        } else {
+            None
+        };
+        let filename = match remote_stat {
+            Some(_) => self.placeholder(&path),
+            None => self
+                .storage
+                .local_path(&path)?
+                .ok_or(ApiError::NotFound("No such text exists"))?,
+        };
 
         let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
         if let Ok(mut states) = self.states.write() {
@@ -243,10 +858,24 @@ impl TextPool {
         } else {
             TextFileMode::NoLineIndex
         };
-        match TextFile::new(filename, Some(&indexname), mode) {
-            Ok(textfile) => {
+        let result = match remote_stat {
+            Some(stat) => self.load_remote(&path, &filename, stat, mode),
+            None => TextFile::new(filename, Some(&indexname), mode)
+                .map_err(ApiError::TextError)
+                .map(|textfile| Text::new(textfile, None)),
+        };
+        //the checksum is cached alongside the indices, so it can be reported without loading the text (see revision_list())
+        let result = result.and_then(|text| {
+            let textfile = text.textfile();
+            auxindex::load_or_build(textfile.path(), CHECKSUM_KIND, |_| {
+                Ok(Checksum(*textfile.checksum()))
+            })?;
+            Ok(text)
+        });
+        match result {
+            Ok(text) => {
                 if let Ok(mut texts) = self.texts.write() {
-                    texts.insert(id.to_string(), Arc::new(RwLock::new(textfile)));
+                    texts.insert(id.to_string(), Arc::new(RwLock::new(text)));
                 } else {
                     if let Ok(mut states) = self.states.write() {
                         states.remove(id);
@@ -258,7 +887,7 @@ impl TextPool {
                 if let Ok(mut states) = self.states.write() {
                     states.remove(id);
                 }
-                return Err(ApiError::TextError(e));
+                return Err(e);
             }
         }
 
@@ -275,11 +904,49 @@ impl TextPool {
         }
     }
 
-    /// Gets the filename from the ID, validating the ID in the process
-    fn filename_from_id(&self, id: &str) -> Result<PathBuf, ApiError> {
+    /// Loads a text from a remote storage backend: the local placeholder file is (re)created and the indices are loaded.
+    /// Only if there are no up-to-date indices yet is the whole text downloaded, to build them.
+    fn load_remote(
+        &self,
+        path: &str,
+        placeholder: &Path,
+        stat: FileStat,
+        mode: TextFileMode,
+    ) -> Result<Text, ApiError> {
+        write_placeholder(placeholder, stat)?;
+        let indexname = placeholder.with_extension("index");
+        let mut download = None;
+        let aux_up_to_date = AUX_INDEX_KINDS
+            .iter()
+            .all(|kind| is_up_to_date(&auxindex::aux_index_path(placeholder, kind), stat));
+        if !is_up_to_date(&indexname, stat) || !aux_up_to_date {
+            let filename = self
+                .storage
+                .local_path(path)?
+                .ok_or(ApiError::NotFound("No such text exists"))?;
+            if !is_up_to_date(&indexname, stat) {
+                TextFile::new(&filename, Some(&indexname), mode)?;
+            }
+            //the auxiliary indices are built right away as well, so the text need not be downloaded again when one is first needed
+            build_aux_indices(placeholder, &filename)?;
+            download = Some(filename);
+        }
+        let remote = Remote::new(self.storage.clone(), path);
+        let text = TextFile::new(placeholder, Some(&indexname), mode)
+            .map_err(ApiError::TextError)
+            .map(|textfile| Text::new(textfile, Some(remote)));
+        if let Some(download) = download {
+            //the full copy was only needed for indexing
+            std::fs::remove_file(download)?;
+        }
+        text
+    }
+
+    /// Gets the path in the storage from the ID, validating the ID in the process
+    fn path_from_id(&self, id: &str) -> Result<String, ApiError> {
         //some security checks so the user can't break out of the configured base directory
         let basename: PathBuf = self.check_basename(id)?;
-        let mut filename = self.basedir.clone().join(basename.clone());
+        let mut filename = basename;
         if !self.extension.is_empty() {
             if filename.extension().is_none() {
                 filename = filename.with_extension(&self.extension);
@@ -302,10 +969,48 @@ impl TextPool {
             //hidden files are never served
             Err(ApiError::NotFound("No such file"))
         } else {
-            Ok(filename)
+            filename
+                .to_str()
+                .map(|path| path.to_string())
+                .ok_or(ApiError::NotFound("No such file"))
+        }
+    }
+
+    /// Gets the filename on local disk from the ID, validating the ID in the process.
+    /// Depending on the storage backend, this may be a cached copy.
+    fn filename_from_id(&self, id: &str) -> Result<PathBuf, ApiError> {
+        let path = self.path_from_id(id)?;
+        self.storage
+            .local_path(&path)?
+            .ok_or(ApiError::NotFound("No such text exists"))
+    }
+
+    /// Returns the filename of the local placeholder for a remote text, its indices are stored alongside it
+    fn placeholder(&self, path: &str) -> PathBuf {
+        self.basedir.join(REMOTE_DIR).join(path)
+    }
+
+    /// Returns the canonical text identifier for a path in the storage (the inverse of `path_from_id()`)
+    fn id_from_path(&self, path: &str) -> String {
+        if self.extension.is_empty() {
+            path.to_string()
+        } else {
+            path.strip_suffix(&format!(".{}", self.extension))
+                .unwrap_or(path)
+                .to_string()
         }
     }
 
+    /// Returns the canonical text identifier for a filename in the base directory
+    fn id_from_filename(&self, filename: &Path) -> String {
+        self.id_from_path(
+            &filename
+                .strip_prefix(&self.basedir)
+                .unwrap_or(filename)
+                .to_string_lossy(),
+        )
+    }
+
     fn wait_until_ready(&self, id: &str) -> Result<State, ApiError> {
         //loop in case we have to wait for another thread to do loading or saving
         let mut wait = false;
@@ -375,9 +1080,164 @@ impl TextPool {
             self.unload(id)?;
         }
 
+        //cached copies of texts that are still loaded must be kept
+        let loaded: Vec<Arc<RwLock<Text>>> = if let Ok(texts) = self.texts.read() {
+            texts.values().cloned().collect()
+        } else {
+            return Err(ApiError::InternalError("Lock poisoned"));
+        };
+        let mut in_use: HashSet<PathBuf> = HashSet::new();
+        for text in loaded {
+            if let Ok(text) = text.read() {
+                in_use.insert(text.textfile().path().to_path_buf());
+            } else {
+                return Err(ApiError::InternalError("Textfiles lock got poisoned"));
+            }
+        }
+        let in_use = |filename: &Path| in_use.contains(filename);
+        self.storage.prune(&in_use)?;
+        if let Some(git) = self.git.as_ref() {
+            git.prune(&in_use)?;
+        }
+
         Ok(remove_ids)
     }
 
+    /// Returns all prior revisions of a text (version number and path), ordered by version
+    fn revisions(&self, path: &str) -> Result<Vec<(usize, String)>, ApiError> {
+        //the revision numbers are derived from the placeholder path of revision 0
+        let template = revision_path(path, 0);
+        let (dir, name) = template
+            .rsplit_once('/')
+            .expect("revision path must be in a directory");
+        let (prefix, suffix) = name
+            .rsplit_once("@0")
+            .expect("revision path must contain @0");
+        let prefix = format!("{}/{}@", dir, prefix);
+        let mut revisions: Vec<(usize, String)> = self
+            .storage
+            .list_dir(&format!("{}/", dir))?
+            .into_iter()
+            .filter_map(|revision| {
+                let version = revision
+                    .strip_prefix(prefix.as_str())?
+                    .strip_suffix(suffix)?
+                    .parse::<usize>()
+                    .ok()?;
+                Some((version, revision))
+            })
+            .collect();
+        revisions.sort_unstable();
+        Ok(revisions)
+    }
+
+    /// Moves the current content of a text (and its indices) to the revision store.
+    /// The caller must hold the write lock of the text, as the version number is derived from the existing revisions.
+    fn archive_revision(&self, path: &str) -> Result<(), ApiError> {
+        let mut version = self
+            .revisions(path)?
+            .last()
+            .map(|(version, _)| version + 1)
+            .unwrap_or(1);
+        //never overwrite a revision, in case another process archived one meanwhile
+        while self.storage.stat(&revision_path(path, version))?.is_some() {
+            version += 1;
+        }
+        let revision = revision_path(path, version);
+        info!("Archiving {} as {}", path, revision);
+        if self.storage.is_remote(path) {
+            //the copied revision is a new object, it gets indexed anew
+            self.storage.rename(path, &revision)?;
+            return self.remove_local_data(path);
+        }
+        let filename = self.storage.local_path(path)?;
+        self.storage.rename(path, &revision)?;
+        //the indices are moved along so the revision need not be reindexed
+        if let (Some(filename), Some(revision)) = (filename, self.storage.local_path(&revision)?) {
+            for kind in AUX_INDEX_KINDS.iter().chain([&CHECKSUM_KIND]) {
+                let cachefilename = auxindex::aux_index_path(&filename, kind);
+                if cachefilename.exists() {
+                    std::fs::rename(cachefilename, auxindex::aux_index_path(&revision, kind))?;
+                }
+            }
+            let cachefilename = filename.with_extension("index");
+            if cachefilename.exists() {
+                std::fs::rename(cachefilename, revision.with_extension("index"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the lock that serialises modifications (writes, deletions, archiving revisions) of the text at the given path
+    fn write_lock(&self, path: &str) -> Result<MutexGuard<'_, ()>, ApiError> {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        self.write_locks[hasher.finish() as usize % WRITE_LOCKS]
+            .lock()
+            .map_err(|_| ApiError::InternalError("Lock poisoned: write lock"))
+    }
+
+    /// Returns the current version number of a text (versions are numbered from 1), along with all prior revisions (version number and path)
+    fn versions(&self, id: &str) -> Result<(usize, Vec<(usize, String)>), ApiError> {
+        let path = self.path_from_id(id)?;
+        if is_revision(&path) {
+            //a revision has no revisions of its own, its version is in the filename
+            let version = Path::new(&path)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.rsplit_once('@'))
+                .and_then(|(_, version)| version.parse().ok())
+                .unwrap_or(0);
+            return Ok((version, Vec::new()));
+        }
+        let revisions = self.revisions(&path)?;
+        let current = revisions
+            .last()
+            .map(|(version, _)| version + 1)
+            .unwrap_or(1);
+        Ok((current, revisions))
+    }
+
+    /// Returns the identifier under which a specific version of a text can be loaded. For the current version this is the text identifier itself.
+    /// For prior revisions this is an internal identifier of a read-only copy, which should not be reported as such.
+    pub fn revision_id(&self, id: &str, version: usize) -> Result<String, ApiError> {
+        let (current, revisions) = self.versions(id)?;
+        if version == current {
+            return Ok(id.to_string());
+        }
+        revisions
+            .into_iter()
+            .find(|(v, _)| *v == version)
+            .map(|(_, revision)| self.id_from_path(&revision))
+            .ok_or(ApiError::NotFound("No such revision"))
+    }
+
+    /// Lists all prior revisions of a text, with their checksum and modification time.
+    /// These are read from the cached checksum index of each revision, only revisions without a usable one are loaded.
+    fn revision_list(&self, id: &str) -> Result<(usize, Vec<serde_json::Value>), ApiError> {
+        let (current, revisions) = self.versions(id)?;
+        let mut list = Vec::with_capacity(revisions.len());
+        for (version, revision) in revisions {
+            let filename = if self.storage.is_remote(&revision) {
+                Some(self.placeholder(&revision))
+            } else {
+                self.storage.local_path(&revision)?
+            };
+            let (mtime, checksum) = match filename.as_deref().and_then(indexed_checksum) {
+                Some(result) => result,
+                None => self.with_text(&self.id_from_path(&revision), |text| {
+                    Ok((text.textfile().mtime(), text.textfile().checksum_digest()))
+                })?,
+            };
+            list.push(serde_json::json!({
+                "version": version,
+                "mtime": mtime,
+                "checksum": checksum,
+            }));
+        }
+        Ok((current, list))
+    }
+
     fn check_basename(&self, id: &str) -> Result<PathBuf, ApiError> {
         let filename: PathBuf = id.into();
 
@@ -387,28 +1247,58 @@ impl TextPool {
                 "No such text exists (no absolute paths allowed)",
             ));
         }
-        for component in filename.components() {
+        for (i, component) in filename.components().enumerate() {
             if component == Component::ParentDir {
                 return Err(ApiError::NotFound(
                     "No such text exists (no parent directories allowed)",
                 ));
             }
+            if let Component::Normal(component) = component {
+                if component.as_encoded_bytes().first() == Some(&b'.')
+                    && !(i == 0 && (component == VERSIONS_DIR || component == GIT_CACHE_DIR))
+                {
+                    //hidden directories hold internal data (like the inverted index) and are never served, except for the (read-only) revisions and texts retrieved from git
+                    return Err(ApiError::NotFound(
+                        "No such text exists (no hidden paths allowed)",
+                    ));
+                }
+            }
         }
         Ok(filename)
     }
 
     pub fn delete_text(&self, text_id: &str) -> Result<(), ApiError> {
+        self.delete_text_as(text_id, None)
+    }
+
+    /// Delete a text (as [`Self::delete_text()`]) on behalf of a writer, whose identity (`Name <email>`) is the author of the git commit (if git-backed storage is enabled)
+    pub fn delete_text_as(&self, text_id: &str, author: Option<&str>) -> Result<(), ApiError> {
         if self.readonly {
             return Err(ApiError::PermissionDenied("Service is readonly"));
         }
-        let filename = self.filename_from_id(text_id)?;
-        if filename.exists() {
+        let path = self.path_from_id(text_id)?;
+        if is_read_only_copy(&path) {
+            return Err(ApiError::PermissionDenied(
+                "Revisions and texts retrieved from git can not be modified",
+            ));
+        }
+        let _lock = self.write_lock(&path)?;
+        if self.storage.stat(&path)?.is_some() {
             self.unload(text_id)?;
-            let cachefilename = filename.with_extension("index");
-            std::fs::remove_file(filename)?;
-            //also remove index file:
-            if cachefilename.exists() {
-                std::fs::remove_file(cachefilename)?;
+            //also remove index files:
+            self.remove_local_data(&path)?;
+            if let Some(invindex) = self.invindex.as_ref() {
+                invindex.remove(&self.id_from_path(&path))?;
+            }
+            //and all prior revisions
+            for (_, revision) in self.revisions(&path)? {
+                self.unload(&self.id_from_path(&revision))?;
+                self.remove_local_data(&revision)?;
+                self.storage.delete(&revision)?;
+            }
+            self.storage.delete(&path)?;
+            if let Some(git) = self.git.as_ref() {
+                git.commit(&path, &format!("Delete {}", text_id), author)?;
             }
             Ok(())
         } else {
@@ -416,6 +1306,20 @@ impl TextPool {
         }
     }
 
+    /// Removes the cached indices of a file (and for remote storage also the placeholder)
+    fn remove_local_data(&self, path: &str) -> Result<(), ApiError> {
+        if self.storage.is_remote(path) {
+            let placeholder = self.placeholder(path);
+            remove_indices(&placeholder)?;
+            if placeholder.exists() {
+                std::fs::remove_file(placeholder)?;
+            }
+        } else if let Some(filename) = self.storage.local_path(path)? {
+            remove_indices(&filename)?;
+        }
+        Ok(())
+    }
+
     pub fn absolute_pos(
         &self,
         id: &str,
@@ -426,8 +1330,8 @@ impl TextPool {
         if let Ok(texts) = self.texts.read() {
             if let Some(textlock) = texts.get(id).cloned() {
                 drop(texts); //compiler should be able to infer this but better safe than sorry
-                if let Ok(textfile) = textlock.read() {
-                    textfile
+                if let Ok(text) = textlock.read() {
+                    text.textfile()
                         .absolute_pos(begin, end)
                         .map_err(ApiError::TextError)
                 } else {
@@ -452,8 +1356,8 @@ impl TextPool {
         if let Ok(texts) = self.texts.read() {
             if let Some(textlock) = texts.get(id).cloned() {
                 drop(texts); //compiler should be able to infer this but better safe than sorry
-                if let Ok(textfile) = textlock.read() {
-                    textfile
+                if let Ok(text) = textlock.read() {
+                    text.textfile()
                         .absolute_line_pos(begin, end)
                         .map_err(ApiError::TextError)
                 } else {
@@ -466,6 +1370,260 @@ impl TextPool {
             Err(ApiError::InternalError("Lock poisoned: textfiles"))
         }
     }
+
+    /// Convert a (relative) range in any supported unit to an absolute character range
+    pub(crate) fn absolute_range(
+        &self,
+        id: &str,
+        range: &Range,
+    ) -> Result<(usize, usize), ApiError> {
+        match *range {
+            //these only need a read lock
+            Range::Chars(begin, end) => self.absolute_pos(id, begin, end),
+            Range::Lines(begin, end) => self.absolute_line_pos(id, begin, end),
+            //these may need to load an auxiliary index
+            _ => self.with_text(id, |text| text.absolute_range(range)),
+        }
+    }
+
+    /// Convert an absolute UTF-8 byte offset to an absolute character offset
+    pub fn byte_to_char_pos(&self, id: &str, bytepos: usize) -> Result<usize, ApiError> {
+        self.with_text(id, |text| Ok(text.textfile().bytes_to_chars(bytepos)?))
+    }
+
+    /// Express an absolute character position in all supported coordinate systems
+    pub(crate) fn coordinates(
+        &self,
+        id: &str,
+        charpos: usize,
+        units: CoordinateUnits,
+    ) -> Result<Coordinates, ApiError> {
+        let lines = self.lines && units.line;
+        self.with_text(id, |text| {
+            if charpos > text.textfile().len() {
+                return Err(ApiError::TextError(textframe::Error::OutOfBoundsError {
+                    begin: charpos as isize,
+                    end: 0,
+                }));
+            }
+            let utf16 = if units.utf16 {
+                Some(text.utf16()?.to_unit(charpos)?)
+            } else {
+                None
+            };
+            let grapheme = if units.grapheme {
+                text.graphemes()?.to_unit(charpos).ok()
+            } else {
+                None
+            };
+            let token = if units.token {
+                text.tokens()?.find(charpos)
+            } else {
+                None
+            };
+            let para = if units.para {
+                text.paragraphs()?.find(charpos)
+            } else {
+                None
+            };
+            let page = if units.page {
+                text.pages()?.find(charpos)
+            } else {
+                None
+            };
+            let textfile = text.textfile();
+            let byte = textfile.chars_to_bytes(charpos)?;
+            let (line, column) = if lines {
+                let line = line_from_bytes(textfile, byte)?;
+                let linebegin = textfile.bytes_to_chars(textfile.line_to_bytes(line as isize)?)?;
+                (Some(line), Some(charpos - linebegin))
+            } else {
+                (None, None)
+            };
+            Ok(Coordinates {
+                char: charpos,
+                byte,
+                utf16,
+                grapheme,
+                token,
+                para,
+                page,
+                line,
+                column,
+            })
+        })
+    }
+
+    /// Extends an absolute character range with the given amount of context on either side, clamped to the text boundaries.
+    /// Returns the absolute character range including context.
+    pub(crate) fn context(
+        &self,
+        id: &str,
+        begin: usize,
+        end: usize,
+        size: usize,
+        unit: ContextUnit,
+    ) -> Result<(usize, usize), ApiError> {
+        if unit == ContextUnit::Lines && !self.lines {
+            return Err(ApiError::ParameterError(
+                "line context is not available because the service has no line index",
+            ));
+        }
+        self.with_text(id, |text| match unit {
+            ContextUnit::Chars => Ok((
+                begin.saturating_sub(size),
+                end.saturating_add(size).min(text.textfile().len()),
+            )),
+            ContextUnit::Tokens => {
+                let tokens = text.tokens()?;
+                Ok((tokens.preceding(begin, size), tokens.following(end, size)))
+            }
+            ContextUnit::Lines => {
+                let textfile = text.textfile();
+                let firstline = line_from_bytes(textfile, textfile.chars_to_bytes(begin)?)?;
+                let lastline = if end > begin {
+                    line_from_bytes(textfile, textfile.chars_to_bytes(end - 1)?)?
+                } else {
+                    firstline
+                };
+                let before = textfile.bytes_to_chars(
+                    textfile.line_to_bytes(firstline.saturating_sub(size) as isize)?,
+                )?;
+                //the line index also holds the end of the text, beyond that we clamp
+                let after = match textfile.line_to_bytes((lastline + size + 1) as isize) {
+                    Ok(bytepos) => textfile.bytes_to_chars(bytepos)?,
+                    Err(_) => textfile.len(),
+                };
+                Ok((before, after))
+            }
+        })
+    }
+
+    /// Returns the text in an absolute character range, as opposed to `map()` an empty range yields an empty string.
+    pub fn excerpt(&self, id: &str, begin: usize, end: usize) -> Result<String, ApiError> {
+        if begin >= end {
+            Ok(String::new())
+        } else {
+            self.map(
+                id,
+                begin as isize,
+                end as isize,
+                |text| Ok(text.to_string()),
+            )
+        }
+    }
+
+    /// Search a text for a literal pattern. The text is scanned from disk, so this does not require it to be held in memory.
+    /// With `tokens`, only matches consisting of whole tokens are returned.
+    /// Returns the matches and whether the search was complete (see [`Pattern::search_file()`]).
+    pub(crate) fn search(
+        &self,
+        id: &str,
+        pattern: &Pattern,
+        max: Option<usize>,
+        deadline: Instant,
+        tokens: bool,
+    ) -> Result<(Vec<SearchMatch>, bool), ApiError> {
+        let tokens = if tokens {
+            Some(self.token_spans(id)?)
+        } else {
+            None
+        };
+        //the lock is only held to obtain the path, not during the search itself
+        let path = self.filename_from_id(id)?;
+        pattern.search_file(&path, max, deadline, tokens.as_ref())
+    }
+
+    /// Search a text for a literal pattern, calling the closure for each match (see [`Pattern::search_file_with()`]).
+    /// No lock is held while the closure is called, so it may access the text pool.
+    /// Returns `false` if the search was stopped prematurely.
+    pub(crate) fn search_with<F>(
+        &self,
+        id: &str,
+        pattern: &Pattern,
+        deadline: Instant,
+        tokens: bool,
+        f: F,
+    ) -> Result<bool, ApiError>
+    where
+        F: FnMut(SearchMatch) -> bool,
+    {
+        let tokens = if tokens {
+            Some(self.token_spans(id)?)
+        } else {
+            None
+        };
+        let path = self.filename_from_id(id)?;
+        pattern.search_file_with(&path, deadline, tokens.as_ref(), f)
+    }
+
+    /// Search a text for approximate occurrences of a query (see [`search::approximate_search_file()`]).
+    pub(crate) fn search_approximate(
+        &self,
+        id: &str,
+        query: &str,
+        max_errors: usize,
+        deadline: Instant,
+    ) -> Result<(Vec<ApproximateMatch>, bool), ApiError> {
+        let path = self.filename_from_id(id)?;
+        search::approximate_search_file(&path, query, max_errors, deadline)
+    }
+
+    /// Search a text for a regular expression, calling the closure for each match (see [`search::regex_search_file()`]).
+    /// With `tokens`, only matches consisting of whole tokens are passed.
+    /// Returns `false` if the search was stopped prematurely.
+    pub(crate) fn search_regex<F>(
+        &self,
+        id: &str,
+        regex: &Regex,
+        deadline: Instant,
+        tokens: bool,
+        mut f: F,
+    ) -> Result<bool, ApiError>
+    where
+        F: FnMut(SearchMatch) -> bool,
+    {
+        let tokens = if tokens {
+            Some(self.token_spans(id)?)
+        } else {
+            None
+        };
+        let path = self.filename_from_id(id)?;
+        search::regex_search_file(&path, regex, deadline, |m| match tokens.as_ref() {
+            Some(tokens) if !tokens.aligned(m.begin, m.end) => true,
+            _ => f(m),
+        })
+    }
+
+    /// Returns the token index of a text, independent of the loaded text so no lock needs to be held while using it (it is memory-mapped, so this is cheap)
+    fn token_spans(&self, id: &str) -> Result<Spans, ApiError> {
+        let textpath = self.with_text(id, |text| {
+            text.tokens()?;
+            Ok(text.textfile().path().to_path_buf())
+        })?;
+        Spans::from_file(&auxindex::aux_index_path(&textpath, "token"), &textpath)
+            .ok_or(ApiError::InternalError("Unable to load token index"))
+    }
+}
+
+/// Find the line (0-indexed) that holds the given byte offset, using the line index
+/// The line index is not directly exposed by textframe, so we do a binary search on it via `line_to_bytes()`
+fn line_from_bytes(textfile: &TextFile, bytepos: usize) -> Result<usize, ApiError> {
+    //find the last line starting at or before the byte position, a line never starts before its own index so that bounds the search.
+    //line_to_bytes() also accepts one past the last entry (yielding the end of the text), such a line does not exist so it is excluded
+    let mut low = 0;
+    let mut high = bytepos + 1; //exclusive
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if textfile.line_to_bytes(mid as isize + 1).is_ok()
+            && textfile.line_to_bytes(mid as isize)? <= bytepos
+        {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(low)
 }
 
 impl Drop for TextPool {
@@ -475,3 +1633,99 @@ impl Drop for TextPool {
         }
     }
 }
+
+/// Reads the modification time and checksum of a text from its cached checksum index, without loading the text.
+/// Returns `None` if there is no such index or it is outdated.
+fn indexed_checksum(textpath: &Path) -> Option<(u64, String)> {
+    let checksum =
+        Checksum::from_file(&auxindex::aux_index_path(textpath, CHECKSUM_KIND), textpath)?;
+    let modified = std::fs::metadata(textpath).ok()?.modified().ok()?;
+    let mtime = modified.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some((mtime, checksum.digest()))
+}
+
+/// Builds all auxiliary indices of a text that are not up to date yet, reading its full content from `source` (for remote texts a downloaded copy)
+fn build_aux_indices(textpath: &Path, source: &Path) -> Result<(), ApiError> {
+    auxindex::load_or_build(textpath, "utf16", |_| Alignment::new_utf16(source))?;
+    auxindex::load_or_build(textpath, "grapheme", |_| Alignment::new_graphemes(source))?;
+    auxindex::load_or_build(textpath, "token", |_| Spans::new_tokens(source))?;
+    auxindex::load_or_build_paragraphs_and_pages(textpath, |_| {
+        Spans::new_paragraphs_and_pages(source)
+    })?;
+    Ok(())
+}
+
+/// Returns the filename of a local file with the full content of a text, as needed to build indices.
+/// For remote texts this downloads a copy, which is normally not needed as all indices are built when the text is loaded (see `TextPool::load_remote()`).
+fn source(remote: Option<&Remote>, textpath: &Path) -> Result<PathBuf, ApiError> {
+    match remote {
+        Some(remote) => remote
+            .storage
+            .local_path(&remote.path)?
+            .ok_or(ApiError::NotFound("No such text exists")),
+        None => Ok(textpath.to_path_buf()),
+    }
+}
+
+/// Writes an empty placeholder file with the given size and modification time (unless it already exists), so cached indices can be used for a remote text.
+/// On most filesystems this takes no disk space.
+fn write_placeholder(filename: &Path, stat: FileStat) -> Result<(), ApiError> {
+    if let Ok(metadata) = std::fs::metadata(filename) {
+        if metadata.len() == stat.size && metadata.modified().ok() == Some(stat.mtime) {
+            return Ok(());
+        }
+    }
+    if let Some(parentdir) = filename.parent() {
+        std::fs::create_dir_all(parentdir)?;
+    }
+    let file = File::create(filename)?;
+    file.set_len(stat.size)?;
+    file.set_modified(stat.mtime)?;
+    Ok(())
+}
+
+/// Checks whether an index file exists and is at least as recent as the text it was computed for
+fn is_up_to_date(indexname: &Path, stat: FileStat) -> bool {
+    std::fs::metadata(indexname)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|mtime| mtime >= stat.mtime)
+}
+
+/// Removes all cached index files of a text
+pub(crate) fn remove_indices(filename: &Path) -> Result<(), ApiError> {
+    let cachefilename = filename.with_extension("index");
+    if cachefilename.exists() {
+        std::fs::remove_file(cachefilename)?;
+    }
+    for kind in AUX_INDEX_KINDS.iter().chain([&CHECKSUM_KIND]) {
+        let cachefilename = auxindex::aux_index_path(filename, kind);
+        if cachefilename.exists() {
+            std::fs::remove_file(cachefilename)?;
+        }
+    }
+    Ok(())
+}
+
+/// Is this the path of a prior revision of a text, or of a text retrieved from git?
+fn is_read_only_copy(path: &str) -> bool {
+    is_revision(path) || Path::new(path).starts_with(GIT_CACHE_DIR)
+}
+
+/// Is this the path of a prior revision of a text?
+fn is_revision(path: &str) -> bool {
+    Path::new(path).starts_with(VERSIONS_DIR)
+}
+
+/// Returns the path of a revision of a text: the revision number is appended to the file stem (separated by `@`) and it is stored in [`VERSIONS_DIR`].
+fn revision_path(path: &str, version: usize) -> String {
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/{}", VERSIONS_DIR, dir), name),
+        None => (VERSIONS_DIR.to_string(), path),
+    };
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}/{}@{}.{}", dir, stem, version, extension)
+        }
+        _ => format!("{}/{}@{}", dir, name, version),
+    }
+}